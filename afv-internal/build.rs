@@ -0,0 +1,12 @@
+fn main() {
+    // The bootloader brings its own vectors and startup, lives in the 1 KiB boot section and ends with its id,
+    // see src/bin/afv-bootloader.rs and update::BOOTLOADER_ADDRESS. The id overlapping the code is a link error
+    for arg in [
+        "-nostartfiles",
+        "-Wl,--section-start=.text=0x7c00",
+        "-Wl,--section-start=.bootloader_id=0x7ffc",
+        "-Wl,--undefined=__afv_bootloader_id",
+    ] {
+        println!("cargo:rustc-link-arg-bin=afv-bootloader={}", arg);
+    }
+}
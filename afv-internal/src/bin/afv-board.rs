@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(abi_avr_interrupt)]
+
+use afv_internal::{w5500::W5500, board::{self, Board, PinBank}, clock::SystemClock, config::ConfigStore, eeprom::Eeprom, fault::{self, FaultReport}, garmin_lidar_v3, output::{LIGHTS_CHANNEL, SIREN_CHANNEL}, scheduler::{Io, Scheduler}, sensors::Adc, FLIR_TURRET_PORT, NOZZLE_TURRET_PORT};
+use arduino_hal::{Spi, I2c};
+use embedded_hal::spi::{Polarity, Phase};
+
+/// The firmware every AFV board runs.
+/// 
+/// Which services are started and how they are wired comes from the configuration stored in the EEPROM,
+/// or when nothing has been committed yet, from the preset selected by the jumpers on D6 and D9 (see [board::preset])
+#[arduino_hal::entry]
+fn main() -> ! {
+    let peripherals = arduino_hal::Peripherals::take().unwrap();
+    // A watchdog reset leaves the watchdog running, so this must happen before anything slow
+    let reset_cause = fault::take_reset_cause(&peripherals.CPU, &peripherals.WDT);
+    let pins = arduino_hal::pins!(peripherals);
+    let mut serial = arduino_hal::default_serial!(peripherals, pins, 57600);    
+    let _clock = SystemClock::new(peripherals.TC0);
+    let mut cs = pins.d10.into_output();
+    cs.set_high();
+    let mut mosi = pins.d11.into_output();
+    mosi.set_high();
+    let miso = pins.d12.into_pull_up_input();
+    let sck = pins.d13.into_output();
+    let mut settings = arduino_hal::spi::Settings::default();
+    settings.clock = arduino_hal::spi::SerialClockRate::OscfOver128;
+    settings.mode.polarity = Polarity::IdleLow;
+    settings.mode.phase = Phase::CaptureOnFirstTransition; 
+    let (mut spi, mut cs) = Spi::new(peripherals.SPI, sck, mosi, miso, cs, settings);
+    let mut i2c = I2c::new(peripherals.TWI, pins.a4.into_pull_up_input(), pins.a5.into_pull_up_input(), garmin_lidar_v3::I2C_SPEED_HZ);
+    let mut adc = Adc::new(peripherals.ADC);
+
+    // A fitted jumper pulls its pin low
+    let d6 = pins.d6.into_pull_up_input();
+    let d9 = pins.d9.into_pull_up_input();
+    arduino_hal::delay_ms(1);
+    let jumpers = (d6.is_low() as u8) | ((d9.is_low() as u8) << 1);
+
+    let eeprom = Eeprom::new(peripherals.EEPROM);
+    let report = FaultReport{
+        reset_cause,
+        fault: fault::load(&eeprom),
+    };
+    if let Some(fault) = report.fault{
+        let _ = ufmt::uwriteln!(&mut serial, "Recovered from panic at {}:{}", fault.file(), fault.line);
+    }
+    let store = ConfigStore::new(eeprom);
+    let config = match store.load(){
+        Some(config) => config,
+        None => {
+            let _ = ufmt::uwriteln!(&mut serial, "No stored config, using preset {}", jumpers);
+            board::preset(jumpers)
+        }
+    };
+    let network = config.network;
+    let (_, _) = W5500::new(Default::default(), network.gateway, network.subnet, network.mac, network.ip, &mut spi, &mut cs, &mut serial);
+
+    let mut board = Board::new(PinBank::new([
+        (board::D2, pins.d2.into_output().downgrade()),
+        (board::D3, pins.d3.into_output().downgrade()),
+        (board::D4, pins.d4.into_output().downgrade()),
+        (board::D5, pins.d5.into_output().downgrade()),
+        (board::D7, pins.d7.into_output().downgrade()),
+        (board::D8, pins.d8.into_output().downgrade()),
+        (board::A0, pins.a0.into_output().downgrade()),
+        (board::A1, pins.a1.into_output().downgrade()),
+        (board::A2, pins.a2.into_output().downgrade()),
+        (board::A3, pins.a3.into_output().downgrade()),
+    ]));
+
+    let mut flir_turret = board.turret(config.flir_turret, FLIR_TURRET_PORT, &mut spi, &mut cs, &mut serial);
+    let mut nozzle_turret = board.turret(config.nozzle_turret, NOZZLE_TURRET_PORT, &mut spi, &mut cs, &mut serial);
+    let mut lidar = board.lidar(config.lidar, &mut i2c, &mut spi, &mut cs, &mut serial);
+    let mut pump = board.pump(config.pump, &mut spi, &mut cs, &mut serial);
+    let mut lights = board.output(LIGHTS_CHANNEL, config.lights, &mut spi, &mut cs, &mut serial);
+    let mut siren = board.output(SIREN_CHANNEL, config.siren, &mut spi, &mut cs, &mut serial);
+    let mut system = board.system(store, config, &mut spi, &mut cs, &mut serial);
+    let mut logger = board.logger(report, &mut spi, &mut cs, &mut serial);
+
+    let _ = ufmt::uwriteln!(&mut serial, "Starting board loop");
+    afv_internal::log_info!("Board {}.{}.{}.{} started", network.ip[0], network.ip[1], network.ip[2], network.ip[3]);
+    let mut scheduler = Scheduler::new();
+    let mut io = Io{
+        spi: &mut spi,
+        cs: &mut cs,
+        serial: &mut serial,
+        i2c: &mut i2c,
+        adc: &mut adc,
+        stats: Default::default(),
+    };
+    loop{
+        scheduler.pass(&mut [&mut flir_turret, &mut nozzle_turret, &mut lidar, &mut pump, &mut lights, &mut siren, &mut system, &mut logger], &mut io);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    fault::on_panic(info)
+}
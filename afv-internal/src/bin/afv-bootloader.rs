@@ -0,0 +1,177 @@
+#![no_std]
+#![no_main]
+#![feature(asm_experimental_arch)]
+
+//! The bootloader that installs the images [afv_internal::update] stages, and rolls them back.
+//!
+//! It replaces optiboot in the 1 KiB boot section at [BOOTLOADER_ADDRESS](afv_internal::update::BOOTLOADER_ADDRESS),
+//! so the board's fuses need BOOTSZ = 10 and BOOTRST programmed (high fuse 0xDC on an Uno). It has no serial upload,
+//! the firmware is flashed over ISP or updated over the network with `afv-update`. See the README for the build and
+//! flash steps.
+//!
+//! The bootloader brings its own two word vector table: a jump to its startup code, then the `do_spm` entry point
+//! the firmware calls at [DO_SPM_ADDRESS](afv_internal::update::DO_SPM_ADDRESS). Startup follows avr-libc's `.init`
+//! sections so libgcc's `.data` copy and `.bss` clear run if the bootloader ever needs them. The linker arguments
+//! that place it are in `build.rs`.
+
+use afv_internal::{
+    config::crc16_update,
+    eeprom::Eeprom,
+    fault::TRIAL_WATCHDOG,
+    update::{
+        read_flash, UpdateRecord, UpdateState, PAGE_SIZE, SPM_ERASE, SPM_FILL, SPM_WRITE,
+        STAGING_ADDRESS,
+    },
+};
+use arduino_hal::pac::Peripherals;
+use core::arch::{asm, global_asm};
+
+// WDTCSR bits
+const WDCE: u8 = 1 << 4;
+const WDE: u8 = 1 << 3;
+
+global_asm!(
+    ".section .vectors,\"ax\",@progbits",
+    "rjmp __afv_boot_init",
+    // DO_SPM_ADDRESS
+    "rjmp afv_do_spm",
+    "",
+    ".section .init2,\"ax\",@progbits",
+    "__afv_boot_init:",
+    "clr r1",
+    "out 0x3f, r1", // SREG, interrupts stay off
+    "ldi r28, 0xff",
+    "ldi r29, 0x08",
+    "out 0x3e, r29", // SPH = hi8(RAMEND)
+    "out 0x3d, r28", // SPL = lo8(RAMEND)
+    "",
+    ".section .init9,\"ax\",@progbits",
+    "rjmp afv_boot_main",
+    "",
+    // Waits for the command to finish and re-enables the RWW section after erases and writes,
+    // the same contract as optiboot's do_spm. Address in r25:r24, command in r22, data in r21:r20
+    ".section .text.afv_do_spm,\"ax\",@progbits",
+    ".global afv_do_spm",
+    "afv_do_spm:",
+    "movw r30, r24",
+    "movw r0, r20",
+    "out 0x37, r22", // SPMCSR
+    "spm",
+    "clr r1",
+    "1:",
+    "in r18, 0x37",
+    "sbrc r18, 0", // SPMEN
+    "rjmp 1b",
+    "andi r22, 0x06", // PGWRT | PGERS
+    "breq 3f",
+    "ldi r18, 0x11", // RWWSRE | SPMEN
+    "out 0x37, r18",
+    "spm",
+    "2:",
+    "in r18, 0x37",
+    "sbrc r18, 0",
+    "rjmp 2b",
+    "3:",
+    "ret",
+    "",
+    // Must match update::BOOTLOADER_ID, placed at BOOTLOADER_ID_ADDRESS by build.rs
+    ".section .bootloader_id,\"a\",@progbits",
+    ".global __afv_bootloader_id",
+    "__afv_bootloader_id:",
+    ".byte 0x41, 0x46, 0x42, 1",
+);
+
+extern "C" {
+    fn afv_do_spm(address: u16, command: u8, data: u16);
+}
+
+#[no_mangle]
+extern "C" fn afv_boot_main() -> ! {
+    let dp = unsafe { Peripherals::steal() };
+
+    // The firmware reads the reset cause from GPIOR0 once MCUSR is cleared, see fault::take_reset_cause
+    let mcusr = dp.CPU.mcusr.read().bits();
+    dp.CPU.gpior0.write(|w| unsafe { w.bits(mcusr) });
+    dp.CPU.mcusr.write(|w| unsafe { w.bits(0) });
+    // A watchdog reset leaves the watchdog running with a 16ms timeout, far shorter than a swap
+    dp.WDT.wdtcsr.write(|w| unsafe { w.bits(WDCE | WDE) });
+    dp.WDT.wdtcsr.write(|w| unsafe { w.bits(0) });
+
+    let mut eeprom = Eeprom::new(dp.EEPROM);
+    let mut record = UpdateRecord::load(&eeprom);
+    match record.state {
+        UpdateState::Staged => {
+            if staged_crc(record.len) == record.crc {
+                swap(record.len);
+                record.state = UpdateState::Trial;
+                record.store(&mut eeprom);
+                // The image must kick the watchdog to keep running, so one that hangs resets into the rollback below
+                dp.WDT.wdtcsr.write(|w| unsafe { w.bits(WDCE | WDE) });
+                dp.WDT.wdtcsr.write(|w| unsafe { w.bits(TRIAL_WATCHDOG) });
+            } else {
+                record.state = UpdateState::Idle;
+                record.store(&mut eeprom);
+            }
+        }
+        UpdateState::Trial => {
+            swap(record.len);
+            record.state = UpdateState::RolledBack;
+            record.store(&mut eeprom);
+        }
+        _ => {}
+    }
+
+    unsafe { asm!("jmp 0", options(noreturn)) }
+}
+
+fn staged_crc(len: u16) -> u16 {
+    let mut crc = 0xffff;
+    let mut address = STAGING_ADDRESS;
+    while address < STAGING_ADDRESS + len {
+        crc = crc16_update(crc, &[read_flash(address)]);
+        address += 1;
+    }
+    crc
+}
+
+fn read_word(address: u16) -> u16 {
+    u16::from_le_bytes([read_flash(address), read_flash(address + 1)])
+}
+
+/// Erases and programs the page at `address` with the words `word` returns for each byte offset
+fn program_page(address: u16, word: impl Fn(u16) -> u16) {
+    unsafe {
+        afv_do_spm(address, SPM_ERASE, 0);
+        let mut offset = 0;
+        while offset < PAGE_SIZE as u16 {
+            afv_do_spm(address + offset, SPM_FILL, word(offset));
+            offset += 2;
+        }
+        afv_do_spm(address, SPM_WRITE, 0);
+    }
+}
+
+/// Swaps the pages of the application and staging areas that hold an image of `len` bytes.
+///
+/// The application's pages past the image are left alone, so swapping the same length back restores the previous
+/// image whole. A reset part way through leaves a mixed image that has to be reflashed over ISP
+fn swap(len: u16) {
+    let mut page = [0u8; PAGE_SIZE];
+    let mut application = 0;
+    while application < len {
+        let staging = STAGING_ADDRESS + application;
+        for (i, byte) in page.iter_mut().enumerate() {
+            *byte = read_flash(application + i as u16);
+        }
+        program_page(application, |offset| read_word(staging + offset));
+        program_page(staging, |offset| {
+            u16::from_le_bytes([page[offset as usize], page[offset as usize + 1]])
+        });
+        application += PAGE_SIZE as u16;
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,377 @@
+use arduino_hal::{
+    clock::MHz16,
+    hal::{port::PB2, usart::Usart0},
+    port::{mode::Output, Pin},
+    spi::ChipSelectPin,
+    I2c, Spi,
+};
+
+use crate::{
+    config::{
+        BoardConfig, ConfigStore, LidarConfig, LidarSensorConfig, NetworkConfig, OutputConfig,
+        PumpConfig, StepperConfig, TurretConfig,
+    },
+    fault::FaultReport,
+    garmin_lidar_v3::{GarminLidarV3, LIDAR_ADDRESS},
+    lidar::{Lidar, MAX_LIDARS},
+    logging::Logger,
+    output::{channel_port, OutputChannel, PUMP_CHANNEL},
+    pump::Pump,
+    sensors::{AnalogCalibration, AnalogSensor, FlowMeter},
+    stepper::{StepperMotor, StepperOps},
+    system::System,
+    turret::Turret,
+    w5500::socket_register::SocketBlock,
+};
+
+// Pin ids follow the Arduino numbering. D0/D1 are the serial port, D10-D13 the Ethernet shield's SPI bus,
+// A4/A5 the I2C bus and D6/D9 are the preset jumpers, so none of them can be assigned to a service.
+pub const D2: u8 = 2;
+pub const D3: u8 = 3;
+pub const D4: u8 = 4;
+pub const D5: u8 = 5;
+pub const D7: u8 = 7;
+pub const D8: u8 = 8;
+pub const A0: u8 = 14;
+pub const A1: u8 = 15;
+pub const A2: u8 = 16;
+pub const A3: u8 = 17;
+
+/// The socket block reserved for the [System] service
+pub const SYSTEM_SOCKET: u8 = 7;
+/// The socket block reserved for the [Logger]
+pub const LOG_SOCKET: u8 = 6;
+
+pub type BoardStepper = StepperMotor<Pin<Output>, Pin<Output>>;
+
+/// Owns the output pins that can be assigned to services
+pub struct PinBank {
+    pins: [(u8, Option<Pin<Output>>); 10],
+}
+
+impl PinBank {
+    pub fn new(pins: [(u8, Pin<Output>); 10]) -> PinBank {
+        Self {
+            pins: pins.map(|(id, pin)| (id, Some(pin))),
+        }
+    }
+    /// Takes the pin out of the bank, returns None if it does not exist or is already in use
+    pub fn take(&mut self, id: u8) -> Option<Pin<Output>> {
+        for (pin_id, pin) in self.pins.iter_mut() {
+            if *pin_id == id {
+                return pin.take();
+            }
+        }
+        None
+    }
+}
+
+/// Builds the services a [BoardConfig] asks for, handing out pins and socket blocks so that no two services share one
+pub struct Board {
+    pins: PinBank,
+    used_sockets: u8,
+}
+
+impl Board {
+    pub fn new(pins: PinBank) -> Board {
+        Self {
+            pins,
+            used_sockets: 1 << SYSTEM_SOCKET | 1 << LOG_SOCKET,
+        }
+    }
+    fn socket(&mut self, index: u8, serial: &mut Usart0<MHz16>) -> Option<SocketBlock> {
+        let block = SocketBlock::from_index(index);
+        if block.is_none() || self.used_sockets & (1 << index) != 0 {
+            let _ = ufmt::uwriteln!(serial, "Socket block {} is unavailable", index);
+            crate::log_error!("Socket block {} is unavailable", index);
+            return None;
+        }
+        self.used_sockets |= 1 << index;
+        block
+    }
+    fn pin(&mut self, id: u8, serial: &mut Usart0<MHz16>) -> Option<Pin<Output>> {
+        let pin = self.pins.take(id);
+        if pin.is_none() {
+            let _ = ufmt::uwriteln!(serial, "Pin {} is unavailable", id);
+            crate::log_error!("Pin {} is unavailable", id);
+        }
+        pin
+    }
+    fn stepper(
+        &mut self,
+        config: &StepperConfig,
+        serial: &mut Usart0<MHz16>,
+    ) -> Option<BoardStepper> {
+        let step = self.pin(config.step_pin, serial)?;
+        let dir = self.pin(config.dir_pin, serial)?;
+        let mut stepper = StepperMotor::from_config(step, dir, config);
+        stepper.home(config.home_step, serial);
+        Some(stepper)
+    }
+    pub fn turret(
+        &mut self,
+        config: Option<TurretConfig>,
+        port: u16,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) -> Option<Turret<BoardStepper, BoardStepper>> {
+        let config = config?;
+        let socket = self.socket(config.socket, serial)?;
+        let pan = self.stepper(&config.pan, serial)?;
+        let tilt = self.stepper(&config.tilt, serial)?;
+        Some(Turret::new(pan, tilt, port, socket, spi, cs, serial))
+    }
+    pub fn lidar(
+        &mut self,
+        config: Option<LidarConfig>,
+        i2c: &mut I2c,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) -> Option<Lidar<GarminLidarV3>> {
+        let config = config?;
+        let socket = self.socket(config.socket, serial)?;
+        // Every sensor boots on the default address, so they are all held off until it is their turn to be moved
+        let mut enables: [Option<Pin<Output>>; MAX_LIDARS] = Default::default();
+        for (sensor, enable) in config.sensors.iter().zip(enables.iter_mut()) {
+            if let Some(id) = sensor.and_then(|s| s.enable_pin) {
+                let mut pin = self.pin(id, serial)?;
+                pin.set_low();
+                *enable = Some(pin);
+            }
+        }
+        arduino_hal::delay_ms(1);
+
+        let mut sensors: [Option<GarminLidarV3>; MAX_LIDARS] = Default::default();
+        for (i, sensor) in config.sensors.iter().enumerate() {
+            if let Some(sensor) = sensor {
+                let mut lidar =
+                    GarminLidarV3::new(Some(sensor.address), enables[i].take(), i2c, serial);
+                lidar.start_auto_measurement(i2c, serial);
+                sensors[i] = Some(lidar);
+            }
+        }
+        Some(Lidar::new(socket, sensors, spi, cs, serial))
+    }
+    /// Runs one of the output channels, such as [crate::output::PUMP_CHANNEL], on its usual port
+    pub fn output(
+        &mut self,
+        channel: u8,
+        config: Option<OutputConfig>,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) -> Option<OutputChannel<Pin<Output>>> {
+        let config = config?;
+        let port = channel_port(channel)?;
+        let socket = self.socket(config.socket, serial)?;
+        let pin = self.pin(config.pin, serial)?;
+        Some(OutputChannel::new(
+            channel, port, socket, pin, spi, cs, serial,
+        ))
+    }
+    pub fn pump(
+        &mut self,
+        config: Option<PumpConfig>,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) -> Option<Pump<Pin<Output>>> {
+        let config = config?;
+        let output = self.output(PUMP_CHANNEL, Some(config.output), spi, cs, serial)?;
+        let mut pump = Pump::new(output, config.flow_ml_per_min);
+        if let Some(pressure) = config.pressure {
+            let sensor = self.analog(
+                pressure.pin,
+                AnalogCalibration {
+                    zero_raw: pressure.zero_raw,
+                    full_raw: pressure.full_raw,
+                    full_scale: pressure.full_scale_kpa,
+                },
+                serial,
+            )?;
+            pump.set_pressure_sensor(sensor, pressure.min_kpa);
+        }
+        if let Some(meter) = config.flow_meter {
+            let interrupt = match meter.pin {
+                D2 => 0,
+                D3 => 1,
+                _ => {
+                    let _ = ufmt::uwriteln!(serial, "Flow meters must be on D2 or D3");
+                    return None;
+                }
+            };
+            let pin = self.pin(meter.pin, serial)?.into_pull_up_input();
+            let flow_meter = FlowMeter::new(interrupt, pin, meter.pulses_per_liter);
+            pump.set_flow_meter(flow_meter, meter.min_ml_per_min);
+        }
+        Some(pump)
+    }
+    /// Takes one of the analog pins, A0-A3, as an ADC input
+    pub fn analog(
+        &mut self,
+        id: u8,
+        calibration: AnalogCalibration,
+        serial: &mut Usart0<MHz16>,
+    ) -> Option<AnalogSensor> {
+        if !(A0..=A3).contains(&id) {
+            let _ = ufmt::uwriteln!(serial, "Pin {} is not an analog input", id);
+            return None;
+        }
+        let pin = self.pin(id, serial)?.into_floating_input();
+        Some(AnalogSensor::new(id - A0, pin, calibration))
+    }
+    pub fn system(
+        &mut self,
+        store: ConfigStore,
+        config: BoardConfig,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) -> System {
+        System::new(SocketBlock::SOCKET7, store, config, spi, cs, serial)
+    }
+    pub fn logger(
+        &mut self,
+        report: FaultReport,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) -> Logger {
+        Logger::new(SocketBlock::SOCKET6, report, spi, cs, serial)
+    }
+}
+
+/// Selects the built in board description from the preset jumpers on D6 (bit 0) and D9 (bit 1).
+/// This is only used when no configuration has been committed to the EEPROM.
+pub fn preset(jumpers: u8) -> BoardConfig {
+    match jumpers {
+        1 => NOZZLE_BOARD,
+        2 => PERIPHERAL_BOARD,
+        _ => FLIR_BOARD,
+    }
+}
+
+const FLIR_PAN: StepperConfig = StepperConfig {
+    step_pin: D3,
+    dir_pin: D2,
+    max_clockwise: 92,
+    min_clockwise: -800,
+    microsteps: Some(16),
+    step_time_us: 1000,
+    microstep_time: 500,
+    inverted: true,
+    home_step: 250,
+};
+const FLIR_TILT: StepperConfig = StepperConfig {
+    step_pin: D5,
+    dir_pin: D4,
+    max_clockwise: 266,
+    min_clockwise: -60,
+    microsteps: Some(16),
+    step_time_us: 2000,
+    microstep_time: 1000,
+    inverted: false,
+    home_step: 266,
+};
+
+/// A starting point for the pump's flow rate, measure the real pump and set it with board-config
+pub const DEFAULT_FLOW_ML_PER_MIN: u16 = 2000;
+
+/// The main board: both turrets, the lidar and the lights
+pub const FLIR_BOARD: BoardConfig = BoardConfig {
+    network: NetworkConfig {
+        mac: [0x00, 0x08, 0xdc, 0x01, 0x02, 0x03],
+        ip: [192, 168, 4, 20],
+        gateway: [192, 168, 4, 1],
+        subnet: [255, 255, 255, 0],
+    },
+    flir_turret: Some(TurretConfig {
+        socket: 0,
+        pan: FLIR_PAN,
+        tilt: FLIR_TILT,
+    }),
+    nozzle_turret: Some(TurretConfig {
+        socket: 1,
+        pan: StepperConfig {
+            step_pin: A0,
+            dir_pin: A1,
+            max_clockwise: 330,
+            min_clockwise: -1000,
+            microsteps: Some(16),
+            step_time_us: 1000,
+            microstep_time: 500,
+            inverted: false,
+            home_step: 300,
+        },
+        tilt: StepperConfig {
+            step_pin: A2,
+            dir_pin: A3,
+            max_clockwise: 50,
+            min_clockwise: -60,
+            microsteps: Some(16),
+            step_time_us: 2000,
+            microstep_time: 1000,
+            inverted: true,
+            home_step: -30,
+        },
+    }),
+    lidar: Some(LidarConfig {
+        socket: 2,
+        sensors: [
+            Some(LidarSensorConfig {
+                address: LIDAR_ADDRESS,
+                enable_pin: None,
+            }),
+            None,
+        ],
+    }),
+    pump: None,
+    lights: Some(OutputConfig { socket: 4, pin: D8 }),
+    siren: None,
+};
+
+/// A board that only runs the nozzle turret
+pub const NOZZLE_BOARD: BoardConfig = BoardConfig {
+    network: NetworkConfig {
+        mac: [0x00, 0x08, 0xdc, 0x01, 0x02, 0x04],
+        ip: [192, 168, 4, 21],
+        gateway: [192, 168, 4, 1],
+        subnet: [255, 255, 255, 0],
+    },
+    flir_turret: None,
+    nozzle_turret: Some(TurretConfig {
+        socket: 0,
+        pan: StepperConfig {
+            min_clockwise: -1000,
+            ..FLIR_PAN
+        },
+        tilt: FLIR_TILT,
+    }),
+    lidar: None,
+    pump: None,
+    lights: None,
+    siren: None,
+};
+
+/// A board that runs the pump, lights and siren
+pub const PERIPHERAL_BOARD: BoardConfig = BoardConfig {
+    network: NetworkConfig {
+        mac: [0x00, 0x08, 0xdc, 0x01, 0x02, 0x05],
+        ip: [192, 168, 4, 22],
+        gateway: [192, 168, 4, 1],
+        subnet: [255, 255, 255, 0],
+    },
+    flir_turret: None,
+    nozzle_turret: None,
+    lidar: None,
+    pump: Some(PumpConfig {
+        output: OutputConfig { socket: 3, pin: A0 },
+        flow_ml_per_min: DEFAULT_FLOW_ML_PER_MIN,
+        pressure: None,
+        flow_meter: None,
+    }),
+    lights: Some(OutputConfig { socket: 4, pin: A1 }),
+    siren: Some(OutputConfig { socket: 5, pin: A2 }),
+};
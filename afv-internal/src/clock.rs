@@ -0,0 +1,71 @@
+use core::cell::Cell;
+
+use arduino_hal::pac::TC0;
+use avr_device::interrupt::Mutex;
+
+// 16MHz / 64 / 250 = 1kHz
+const PRESCALER: u32 = 64;
+const TIMER_COUNTS: u32 = 250;
+const US_PER_COUNT: u32 = PRESCALER / 16;
+
+static MILLIS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Drives the board's monotonic millisecond tick from the 8 bit TC0 timer.
+///
+/// The arduino_hal delay functions are busy loops so TC0 is free to use. The tick wraps after roughly 49 days,
+/// so durations should always be computed with [elapsed_ms] or `wrapping_sub`.
+pub struct SystemClock {
+    timer: TC0,
+}
+
+impl SystemClock {
+    /// Starts the tick and enables interrupts globally
+    pub fn new(timer: TC0) -> SystemClock {
+        timer.tccr0a.write(|w| w.wgm0().ctc());
+        timer.ocr0a.write(|w| w.bits((TIMER_COUNTS - 1) as u8));
+        timer.tccr0b.write(|w| w.cs0().prescale_64());
+        timer.timsk0.write(|w| w.ocie0a().set_bit());
+        avr_device::interrupt::free(|cs| MILLIS.borrow(cs).set(0));
+        unsafe { avr_device::interrupt::enable() };
+        SystemClock { timer }
+    }
+    pub fn dissolve(self) -> TC0 {
+        self.timer.timsk0.write(|w| w.ocie0a().clear_bit());
+        self.timer.tccr0b.write(|w| w.cs0().no_clock());
+        self.timer
+    }
+}
+
+/// Milliseconds since the [SystemClock] was started
+pub fn millis() -> u32 {
+    avr_device::interrupt::free(|cs| MILLIS.borrow(cs).get())
+}
+
+/// Microseconds since the [SystemClock] was started with a resolution of 4us. Wraps after roughly 71 minutes
+pub fn micros() -> u32 {
+    avr_device::interrupt::free(|cs| {
+        let timer = unsafe { &*TC0::ptr() };
+        let mut ms = MILLIS.borrow(cs).get();
+        let mut counts = timer.tcnt0.read().bits() as u32;
+        // A compare match that happened while interrupts were off has not been counted yet
+        if timer.tifr0.read().ocf0a().bit_is_set() {
+            ms = ms.wrapping_add(1);
+            counts = timer.tcnt0.read().bits() as u32;
+        }
+        ms.wrapping_mul(1000).wrapping_add(counts * US_PER_COUNT)
+    })
+}
+
+/// Milliseconds elapsed since the tick value `since`
+pub fn elapsed_ms(since: u32) -> u32 {
+    millis().wrapping_sub(since)
+}
+
+#[cfg(target_arch = "avr")]
+#[avr_device::interrupt(atmega328p)]
+fn TIMER0_COMPA() {
+    avr_device::interrupt::free(|cs| {
+        let counter = MILLIS.borrow(cs);
+        counter.set(counter.get().wrapping_add(1));
+    })
+}
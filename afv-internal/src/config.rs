@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+use ufmt::derive::uDebug;
+
+use crate::{eeprom::Eeprom, lidar::MAX_LIDARS};
+
+/// Bump this whenever the layout of [BoardConfig] changes so stale EEPROM contents are rejected
+pub const CONFIG_VERSION: u8 = 5;
+/// The EEPROM address the configuration record starts at
+pub const CONFIG_ADDRESS: u16 = 0;
+/// The maximum encoded size of a [BoardConfig]
+pub const CONFIG_CAPACITY: usize = 192;
+const CONFIG_MAGIC: [u8; 2] = [0xaf, 0x5c];
+const HEADER_SIZE: usize = 6;
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ConfigMsg {
+    /// Requests the configuration the board is currently running
+    Read,
+    /// Replaces the staged configuration, which takes effect once committed and the board is restarted
+    Write(BoardConfig),
+    /// Persists the staged configuration to EEPROM
+    Commit,
+    /// Erases the stored configuration so the board falls back to its built in defaults
+    Erase,
+    /// Response to [ConfigMsg::Read], [ConfigMsg::ReadStaged] and [ConfigMsg::Write]
+    Current(BoardConfig),
+    /// Response to [ConfigMsg::Commit] and [ConfigMsg::Erase]
+    Committed(bool),
+    /// Requests the staged configuration, which is the running one until another is written
+    ReadStaged,
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct NetworkConfig {
+    pub mac: [u8; 6],
+    pub ip: [u8; 4],
+    pub gateway: [u8; 4],
+    pub subnet: [u8; 4],
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct StepperConfig {
+    /// See [crate::board] for pin ids
+    pub step_pin: u8,
+    pub dir_pin: u8,
+    pub max_clockwise: i32,
+    pub min_clockwise: i32,
+    pub microsteps: Option<u32>,
+    pub step_time_us: u32,
+    pub microstep_time: u32,
+    pub inverted: bool,
+    /// The step the stepper is driven to before being zeroed on startup
+    pub home_step: i32,
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct TurretConfig {
+    /// The index of the W5500 socket block the turret's server uses
+    pub socket: u8,
+    pub pan: StepperConfig,
+    pub tilt: StepperConfig,
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct LidarSensorConfig {
+    /// The 7 bit I2C address the sensor is moved to
+    pub address: u8,
+    /// The pin driving the sensor's power enable line. Sensors without one are powered from boot on the default
+    /// address, so at most one sensor may leave it out
+    pub enable_pin: Option<u8>,
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct LidarConfig {
+    pub socket: u8,
+    /// Indexed by the sensor id used in [crate::lidar::LidarMsg]
+    pub sensors: [Option<LidarSensorConfig>; MAX_LIDARS],
+}
+
+/// A service that drives a single output pin, such as the pump, lights or siren
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct OutputConfig {
+    pub socket: u8,
+    pub pin: u8,
+}
+
+/// A pressure transducer on one of the analog pins, read as a straight line between two ADC readings
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct PressureSensorConfig {
+    pub pin: u8,
+    pub zero_raw: u16,
+    pub full_raw: u16,
+    pub full_scale_kpa: u16,
+    /// Running below this pressure counts as running dry
+    pub min_kpa: u16,
+}
+
+/// A pulse output flow meter on D2 or D3
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct FlowMeterConfig {
+    pub pin: u8,
+    pub pulses_per_liter: u16,
+    /// Running below this flow counts as running dry
+    pub min_ml_per_min: u16,
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct PumpConfig {
+    pub output: OutputConfig,
+    /// The pump's flow rate, used to time volume runs and estimate the water dispensed
+    pub flow_ml_per_min: u16,
+    pub pressure: Option<PressureSensorConfig>,
+    pub flow_meter: Option<FlowMeterConfig>,
+}
+
+/// The description of a single board: its network settings, which services it runs and how they are wired.
+/// Services that are None are not started.
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct BoardConfig {
+    pub network: NetworkConfig,
+    pub flir_turret: Option<TurretConfig>,
+    pub nozzle_turret: Option<TurretConfig>,
+    pub lidar: Option<LidarConfig>,
+    pub pump: Option<PumpConfig>,
+    pub lights: Option<OutputConfig>,
+    pub siren: Option<OutputConfig>,
+}
+
+impl BoardConfig {
+    /// Encodes the configuration into a versioned, crc protected record
+    pub fn encode(&self) -> Option<[u8; HEADER_SIZE + CONFIG_CAPACITY]> {
+        let mut record = [0u8; HEADER_SIZE + CONFIG_CAPACITY];
+        let len = match postcard::to_slice(self, &mut record[HEADER_SIZE..]) {
+            Ok(payload) => payload.len(),
+            Err(_) => return None,
+        };
+        let crc = crc16(&record[HEADER_SIZE..HEADER_SIZE + len]).to_be_bytes();
+        record[..HEADER_SIZE].copy_from_slice(&[
+            CONFIG_MAGIC[0],
+            CONFIG_MAGIC[1],
+            CONFIG_VERSION,
+            len as u8,
+            crc[0],
+            crc[1],
+        ]);
+        Some(record)
+    }
+    /// Decodes a record produced by [BoardConfig::encode], rejecting it if the magic, version or crc do not match
+    pub fn decode(record: &[u8]) -> Option<BoardConfig> {
+        if record.len() < HEADER_SIZE {
+            return None;
+        }
+        if record[0..2] != CONFIG_MAGIC || record[2] != CONFIG_VERSION {
+            return None;
+        }
+        let len = record[3] as usize;
+        if len > CONFIG_CAPACITY || HEADER_SIZE + len > record.len() {
+            return None;
+        }
+        let payload = &record[HEADER_SIZE..HEADER_SIZE + len];
+        if crc16(payload) != u16::from_be_bytes([record[4], record[5]]) {
+            return None;
+        }
+        postcard::from_bytes(payload).ok()
+    }
+}
+
+/// Persists a [BoardConfig] in the EEPROM
+pub struct ConfigStore {
+    eeprom: Eeprom,
+}
+
+impl ConfigStore {
+    pub fn new(eeprom: Eeprom) -> ConfigStore {
+        ConfigStore { eeprom }
+    }
+    /// The EEPROM is shared with records outside the configuration such as the [crate::fault] record
+    pub fn eeprom_mut(&mut self) -> &mut Eeprom {
+        &mut self.eeprom
+    }
+    /// Returns the stored configuration, or None if nothing valid has been committed
+    pub fn load(&self) -> Option<BoardConfig> {
+        let mut record = [0u8; HEADER_SIZE + CONFIG_CAPACITY];
+        if self.eeprom.read(CONFIG_ADDRESS, &mut record).is_err() {
+            return None;
+        }
+        BoardConfig::decode(&record)
+    }
+    /// Writes the configuration and reads it back to verify it
+    pub fn store(&mut self, config: &BoardConfig) -> bool {
+        let record = match config.encode() {
+            Some(r) => r,
+            None => return false,
+        };
+        let len = HEADER_SIZE + record[3] as usize;
+        if self.eeprom.write(CONFIG_ADDRESS, &record[..len]).is_err() {
+            return false;
+        }
+        self.load() == Some(*config)
+    }
+    /// Invalidates the stored configuration
+    pub fn erase(&mut self) -> bool {
+        if self
+            .eeprom
+            .write(CONFIG_ADDRESS, &[0xff; HEADER_SIZE])
+            .is_err()
+        {
+            return false;
+        }
+        self.load().is_none()
+    }
+    pub fn dissolve(self) -> Eeprom {
+        self.eeprom
+    }
+}
+
+/// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(0xffff, data)
+}
+
+/// Continues a [crc16] over more data, for data that arrives in pieces
+pub fn crc16_update(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
@@ -0,0 +1,66 @@
+use arduino_hal::pac::EEPROM;
+
+/// The size of the [Atmega328p's](https://www.microchip.com/en-us/product/ATmega328P) EEPROM in bytes
+pub const EEPROM_SIZE: u16 = 1024;
+
+pub enum EepromError {
+    OutOfBounds,
+}
+
+/// A small wrapper around the EEPROM peripheral that provides byte and slice access
+pub struct Eeprom {
+    eeprom: EEPROM,
+}
+
+impl Eeprom {
+    pub fn new(eeprom: EEPROM) -> Eeprom {
+        Eeprom { eeprom }
+    }
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        self.wait_ready();
+        self.eeprom.eear.write(|w| unsafe { w.bits(addr) });
+        self.eeprom.eecr.write(|w| w.eere().set_bit());
+        self.eeprom.eedr.read().bits()
+    }
+    /// Writes a single byte, skipping the write entirely if the stored value already matches
+    /// to save EEPROM wear
+    pub fn write_byte(&mut self, addr: u16, data: u8) {
+        if self.read_byte(addr) == data {
+            return;
+        }
+        avr_device::interrupt::free(|_| {
+            self.wait_ready();
+            self.eeprom.eear.write(|w| unsafe { w.bits(addr) });
+            self.eeprom.eedr.write(|w| unsafe { w.bits(data) });
+            // EEPE must be set within four clock cycles of EEMPE
+            self.eeprom.eecr.write(|w| w.eempe().set_bit());
+            self.eeprom
+                .eecr
+                .write(|w| w.eempe().set_bit().eepe().set_bit());
+        });
+    }
+    pub fn read(&self, addr: u16, data: &mut [u8]) -> Result<(), EepromError> {
+        if addr as usize + data.len() > EEPROM_SIZE as usize {
+            return Err(EepromError::OutOfBounds);
+        }
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.read_byte(addr + i as u16);
+        }
+        Ok(())
+    }
+    pub fn write(&mut self, addr: u16, data: &[u8]) -> Result<(), EepromError> {
+        if addr as usize + data.len() > EEPROM_SIZE as usize {
+            return Err(EepromError::OutOfBounds);
+        }
+        for (i, byte) in data.iter().enumerate() {
+            self.write_byte(addr + i as u16, *byte);
+        }
+        Ok(())
+    }
+    pub fn dissolve(self) -> EEPROM {
+        self.eeprom
+    }
+    fn wait_ready(&self) {
+        while self.eeprom.eecr.read().eepe().bit_is_set() {}
+    }
+}
@@ -0,0 +1,47 @@
+use core::cell::Cell;
+
+use avr_device::interrupt::Mutex;
+use serde::{Deserialize, Serialize};
+use ufmt::derive::uDebug;
+
+use crate::network::InternalMessage;
+
+/// Shared by every service on the board, so a stop sent to any one of them stops them all
+static LATCHED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+#[derive(uDebug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum EStopMsg {
+    /// Latches the board's E-stop, turning every output off and refusing turret moves until [EStopMsg::Reset]
+    Stop,
+    Reset,
+    PollState,
+    /// Answers every other [EStopMsg] with whether the E-stop is latched
+    State(bool),
+}
+
+pub fn latched() -> bool {
+    avr_device::interrupt::free(|cs| LATCHED.borrow(cs).get())
+}
+
+pub fn latch() {
+    if !avr_device::interrupt::free(|cs| LATCHED.borrow(cs).replace(true)) {
+        crate::log_error!("Emergency stop latched");
+    }
+}
+
+pub fn reset() {
+    if avr_device::interrupt::free(|cs| LATCHED.borrow(cs).replace(false)) {
+        crate::log_warn!("Emergency stop reset");
+    }
+}
+
+/// Runs an E-stop message received on any service's socket, returning the reply for that socket
+pub fn process(msg: EStopMsg) -> Option<InternalMessage> {
+    match msg {
+        EStopMsg::Stop => latch(),
+        EStopMsg::Reset => reset(),
+        EStopMsg::PollState => {}
+        EStopMsg::State(_) => return None,
+    }
+    Some(InternalMessage::EmergencyStop(EStopMsg::State(latched())))
+}
@@ -0,0 +1,202 @@
+use core::{cell::Cell, panic::PanicInfo};
+
+use arduino_hal::pac::{CPU, WDT};
+use avr_device::interrupt::Mutex;
+use serde::{Deserialize, Serialize};
+use ufmt::derive::uDebug;
+
+use crate::eeprom::Eeprom;
+
+/// The EEPROM address the fault record starts at, well clear of the [crate::config] record
+pub const FAULT_ADDRESS: u16 = 512;
+/// The most trailing bytes of the panicking file's path that are kept, cut at a character boundary
+pub const FAULT_FILE_SIZE: usize = 24;
+const FAULT_MAGIC: [u8; 2] = [0xfa, 0x17];
+const FAULT_RECORD_SIZE: usize = 2 + 4 + 4 + 1 + FAULT_FILE_SIZE;
+
+// WDTCSR bits
+const WDCE: u8 = 1 << 4;
+const WDE: u8 = 1 << 3;
+const WDP3: u8 = 1 << 5;
+const WDP0: u8 = 1 << 0;
+const WDP_MASK: u8 = WDP3 | 0b111;
+
+/// The WDTCSR value the AFV bootloader starts a trial image with, resetting it unless the watchdog is kicked
+/// within 8s. A trial image that hangs is reset and so rolled back, see [crate::update]
+pub const TRIAL_WATCHDOG: u8 = WDE | WDP3 | WDP0;
+
+static CLEAR_REQUESTED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+/// The contents of the MCUSR register at boot
+///
+/// NOTE: Some optiboot builds clear MCUSR before starting the firmware, boards flashed with one always report an empty cause.
+/// The AFV bootloader hands MCUSR over in GPIOR0 instead
+pub struct ResetCause(pub u8);
+
+impl ResetCause {
+    pub fn power_on(&self) -> bool {
+        self.0 & 1 << 0 != 0
+    }
+    pub fn external(&self) -> bool {
+        self.0 & 1 << 1 != 0
+    }
+    pub fn brown_out(&self) -> bool {
+        self.0 & 1 << 2 != 0
+    }
+    pub fn watchdog(&self) -> bool {
+        self.0 & 1 << 3 != 0
+    }
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+/// Where the firmware panicked before its last reset
+pub struct FaultRecord {
+    pub line: u32,
+    pub column: u32,
+    len: u8,
+    file: [u8; FAULT_FILE_SIZE],
+}
+
+impl FaultRecord {
+    /// The end of the file's path, at most [FAULT_FILE_SIZE] bytes
+    pub fn file(&self) -> &str {
+        let len = (self.len as usize).min(FAULT_FILE_SIZE);
+        core::str::from_utf8(&self.file[..len]).unwrap_or("")
+    }
+    fn encode(&self) -> [u8; FAULT_RECORD_SIZE] {
+        let mut record = [0u8; FAULT_RECORD_SIZE];
+        record[..2].copy_from_slice(&FAULT_MAGIC);
+        record[2..6].copy_from_slice(&self.line.to_be_bytes());
+        record[6..10].copy_from_slice(&self.column.to_be_bytes());
+        record[10] = self.len;
+        record[11..].copy_from_slice(&self.file);
+        record
+    }
+    fn decode(record: &[u8; FAULT_RECORD_SIZE]) -> Option<FaultRecord> {
+        if record[..2] != FAULT_MAGIC || record[10] as usize > FAULT_FILE_SIZE {
+            return None;
+        }
+        let mut file = [0u8; FAULT_FILE_SIZE];
+        file.copy_from_slice(&record[11..]);
+        Some(FaultRecord {
+            line: u32::from_be_bytes([record[2], record[3], record[4], record[5]]),
+            column: u32::from_be_bytes([record[6], record[7], record[8], record[9]]),
+            len: record[10],
+            file,
+        })
+    }
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+/// Why the board last reset, reported to the host every time it connects until acknowledged
+pub struct FaultReport {
+    pub reset_cause: ResetCause,
+    pub fault: Option<FaultRecord>,
+}
+
+#[derive(uDebug, Serialize, Deserialize, Clone, Copy)]
+pub enum FaultMsg {
+    Report(FaultReport),
+    /// Sent by the host once the report has been handled, which clears the stored [FaultRecord]
+    Acknowledge,
+}
+
+/// Reads and clears MCUSR, along with the copy the AFV bootloader leaves in GPIOR0, then disables the watchdog.
+/// This must be called early in boot because a watchdog reset leaves the watchdog running.
+///
+/// The [TRIAL_WATCHDOG] the bootloader starts a trial image with is left running, the scheduler kicks it
+pub fn take_reset_cause(cpu: &CPU, wdt: &WDT) -> ResetCause {
+    let cause = ResetCause(cpu.mcusr.read().bits() | cpu.gpior0.read().bits());
+    cpu.mcusr.write(|w| unsafe { w.bits(0) });
+    cpu.gpior0.write(|w| unsafe { w.bits(0) });
+    if wdt.wdtcsr.read().bits() & (WDE | WDP_MASK) == TRIAL_WATCHDOG {
+        return cause;
+    }
+    avr_device::interrupt::free(|_| {
+        // WDE may only be cleared within four clock cycles of setting WDCE
+        wdt.wdtcsr.write(|w| unsafe { w.bits(WDCE | WDE) });
+        wdt.wdtcsr.write(|w| unsafe { w.bits(0) });
+    });
+    cause
+}
+
+/// Returns the fault recorded by [on_panic], if there is one
+pub fn load(eeprom: &Eeprom) -> Option<FaultRecord> {
+    let mut record = [0u8; FAULT_RECORD_SIZE];
+    if eeprom.read(FAULT_ADDRESS, &mut record).is_err() {
+        return None;
+    }
+    FaultRecord::decode(&record)
+}
+
+pub fn clear(eeprom: &mut Eeprom) {
+    let _ = eeprom.write(FAULT_ADDRESS, &[0xff; 2]);
+}
+
+/// Asks the owner of the EEPROM to [clear] the stored fault on its next pass
+pub fn request_clear() {
+    avr_device::interrupt::free(|cs| CLEAR_REQUESTED.borrow(cs).set(true));
+}
+
+pub fn take_clear_request() -> bool {
+    avr_device::interrupt::free(|cs| CLEAR_REQUESTED.borrow(cs).replace(false))
+}
+
+/// Called from the firmware's `#[panic_handler]`.
+///
+/// Drives every pin a service can be assigned to (see [crate::board]) low so steppers stop and outputs turn off,
+/// records where the panic happened and resets the board through the watchdog
+pub fn on_panic(info: &PanicInfo) -> ! {
+    avr_device::interrupt::disable();
+    let dp = unsafe { arduino_hal::Peripherals::steal() };
+
+    // D2-D5 and D7 on PORTD, D8 on PORTB and A0-A3 on PORTC
+    dp.PORTD
+        .portd
+        .modify(|r, w| unsafe { w.bits(r.bits() & !0b1011_1100) });
+    dp.PORTB
+        .portb
+        .modify(|r, w| unsafe { w.bits(r.bits() & !0b0000_0001) });
+    dp.PORTC
+        .portc
+        .modify(|r, w| unsafe { w.bits(r.bits() & !0b0000_1111) });
+
+    let mut record = FaultRecord {
+        line: 0,
+        column: 0,
+        len: 0,
+        file: [0; FAULT_FILE_SIZE],
+    };
+    if let Some(location) = info.location() {
+        let file = location.file();
+        // Kept from a character boundary, so a multi-byte character is never split and file() can decode it
+        let mut start = file.len().saturating_sub(FAULT_FILE_SIZE);
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+        let file = file[start..].as_bytes();
+        record.line = location.line();
+        record.column = location.column();
+        record.len = file.len() as u8;
+        record.file[..file.len()].copy_from_slice(file);
+    }
+    let mut eeprom = Eeprom::new(dp.EEPROM);
+    let _ = eeprom.write(FAULT_ADDRESS, &record.encode());
+    watchdog_reset()
+}
+
+/// Restarts the watchdog's timeout, called on every pass of the main loop and through anything that blocks for long
+pub fn kick_watchdog() {
+    avr_device::asm::wdr();
+}
+
+/// Resets the board by letting the watchdog expire
+pub fn watchdog_reset() -> ! {
+    avr_device::interrupt::disable();
+    let wdt = unsafe { &*WDT::ptr() };
+    // Shortest watchdog timeout, 16ms
+    wdt.wdtcsr.write(|w| unsafe { w.bits(WDCE | WDE) });
+    wdt.wdtcsr.write(|w| unsafe { w.bits(WDE) });
+    loop {}
+}
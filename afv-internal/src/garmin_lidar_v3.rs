@@ -1,11 +1,25 @@
 use arduino_hal::{
     clock::MHz16,
     hal::usart::Usart0,
+    i2c,
+    port::{mode::Output, Pin},
     prelude::{_embedded_hal_blocking_i2c_Write, _embedded_hal_blocking_i2c_WriteRead},
     I2c,
 };
 
-use crate::lidar::I2cLidarOps;
+use crate::lidar::{AcquisitionSettings, I2cLidarOps, LidarMeasurement, MIN_SIGNAL_STRENGTH};
+
+/// How long the lidar takes to boot after its power enable pin is raised
+pub const BOOT_TIME_MS: u16 = 22;
+/// The lidar supports fast mode I2C, which reads a measurement in well under a millisecond
+pub const I2C_SPEED_HZ: u32 = 400_000;
+/// Bit 3 of the I2C_CONFIG register stops the lidar answering on [LIDAR_ADDRESS]
+const DISABLE_DEFAULT_ADDRESS: u8 = 0b00001000;
+/// Set on a register address to read on through the registers after it
+const AUTO_INCREMENT: u8 = 0b10000000;
+/// The registers from [ControlRegister::STATUS] to [ControlRegister::LAST_DELAY_LOW], which hold everything in a
+/// [LidarMeasurement]
+const MEASUREMENT_REGISTERS: usize = 0x15;
 
 pub const LIDAR_ADDRESS: u8 = 0x62;
 pub struct ControlRegister(u8);
@@ -67,6 +81,8 @@ impl From<Status> for [u8; 1] {
     }
 }
 impl Status {
+    /// Flags that mean the last measurement should not be trusted
+    pub const INVALID_MASK: u8 = Self::PROCESS_ERROR_FLAG.0 | Self::INVALID_SIGANTURE_FLAG.0;
     pub const PROCESS_ERROR_FLAG: Self = Self(0b01000000);
     pub const HEALTH_FLAG: Self = Self(0b00100000);
     pub const SECONDARY_RETURN_FLAG: Self = Self(0b00010000);
@@ -102,22 +118,105 @@ impl PowerCtl {
 
 pub struct GarminLidarV3 {
     address: u8,
+    enable: Option<Pin<Output>>,
 }
 
 impl GarminLidarV3 {
-    /// Give address to configure a new i2c addresss
-    pub fn new(address: Option<u8>, serial: &mut Usart0<MHz16>) -> GarminLidarV3 {
-        let lidar_address = LIDAR_ADDRESS;
-        if let Some(_) = address {
-            let _ = ufmt::uwriteln!(
-                serial,
-                "Configure new address for garmin lidar not implemented"
-            );
+    /// Give address to configure a new i2c addresss.
+    ///
+    /// Every lidar boots on [LIDAR_ADDRESS] and forgets its new address when it loses power, so when several share
+    /// the bus all but one must be held off through their power `enable` pin until they have been moved.
+    pub fn new(
+        address: Option<u8>,
+        enable: Option<Pin<Output>>,
+        i2c: &mut I2c,
+        serial: &mut Usart0<MHz16>,
+    ) -> GarminLidarV3 {
+        let mut lidar = Self {
+            address: LIDAR_ADDRESS,
+            enable,
+        };
+        if let Some(enable) = lidar.enable.as_mut() {
+            enable.set_high();
+            arduino_hal::delay_ms(BOOT_TIME_MS);
         }
-
-        Self {
-            address: lidar_address,
+        if let Some(address) = address.filter(|a| *a != LIDAR_ADDRESS) {
+            if lidar.set_address(address, i2c).is_err() {
+                let _ = ufmt::uwriteln!(serial, "Failed to move garmin lidar to {}", address);
+                crate::log_error!("Failed to move garmin lidar to {}", address);
+            }
         }
+        lidar
+    }
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+    /// Moves the lidar to a new 7 bit address and stops it answering on its current one.
+    /// The lidar's serial number is written back to it to unlock the change
+    pub fn set_address(&mut self, address: u8, i2c: &mut I2c) -> Result<(), i2c::Error> {
+        let mut serial_number = [0u8; 2];
+        let cmd: [u8; 1] = [Into::<u8>::into(ControlRegister::UNIT_ID_HIGH) | AUTO_INCREMENT];
+        i2c.write_read(self.address, &cmd, &mut serial_number)?;
+        i2c.write(
+            self.address,
+            &[ControlRegister::I2C_ID_HIGH.into(), serial_number[0]],
+        )?;
+        i2c.write(
+            self.address,
+            &[ControlRegister::I2C_ID_LOW.into(), serial_number[1]],
+        )?;
+        i2c.write(
+            self.address,
+            &[ControlRegister::I2C_SEC_ADDR.into(), address],
+        )?;
+        // Enables the new address alongside the old one
+        i2c.write(self.address, &[ControlRegister::I2C_CONIFG.into(), 0])?;
+        i2c.write(
+            address,
+            &[ControlRegister::I2C_CONIFG.into(), DISABLE_DEFAULT_ADDRESS],
+        )?;
+        self.address = address;
+        Ok(())
+    }
+    /// Applies the settings and restarts free running measurement so they take effect
+    pub fn set_acquisition(
+        &mut self,
+        settings: AcquisitionSettings,
+        i2c: &mut I2c,
+        serial: &mut Usart0<MHz16>,
+    ) -> Result<(), i2c::Error> {
+        i2c.write(
+            self.address,
+            &[ControlRegister::SIG_COUNT_VAL.into(), settings.count],
+        )?;
+        let mut acq_config = self.read_register(ControlRegister::ACQ_CONFIG_REG, i2c)?;
+        acq_config &= !(AcqConfig::QUICK_TERMINATION.0 | AcqConfig::BURST_FREE_DELAY.0);
+        // The quick termination bit disables it when set
+        if !settings.quick_termination {
+            acq_config |= AcqConfig::QUICK_TERMINATION.0;
+        }
+        if settings.rate_hz > 0 {
+            // The delay register counts in units of 0.5ms
+            let delay = (2000 / settings.rate_hz as u16).clamp(1, 0xff) as u8;
+            i2c.write(
+                self.address,
+                &[ControlRegister::MEASURE_DELAY.into(), delay],
+            )?;
+            acq_config |= AcqConfig::BURST_FREE_DELAY.0;
+        }
+        i2c.write(
+            self.address,
+            &[ControlRegister::ACQ_CONFIG_REG.into(), acq_config],
+        )?;
+        i2c.write(
+            self.address,
+            &[
+                ControlRegister::THRESHOLD_BYPASS.into(),
+                settings.threshold_bypass,
+            ],
+        )?;
+        self.start_auto_measurement(i2c, serial);
+        Ok(())
     }
     pub fn start_auto_measurement(&mut self, i2c: &mut I2c, _serial: &mut Usart0<MHz16>) {
         // First we write to outer loop count
@@ -131,18 +230,78 @@ impl GarminLidarV3 {
         let _ = i2c.write(self.address, &cmd);
         // Now the auto command system is running on the lidar
     }
-    pub fn read_last_measurement(&mut self, i2c: &mut I2c, _serial: &mut Usart0<MHz16>) -> u16 {
-        let cmd: [u8; 1] = [Into::<u8>::into(ControlRegister::LAST_DELAY_HIGH) | 0b10000000];
+    pub fn read_last_measurement(
+        &mut self,
+        i2c: &mut I2c,
+        _serial: &mut Usart0<MHz16>,
+    ) -> Result<u16, i2c::Error> {
+        let cmd: [u8; 1] = [Into::<u8>::into(ControlRegister::LAST_DELAY_HIGH) | AUTO_INCREMENT];
         let mut data = [0u8; 2];
-        let _ = ufmt::uwriteln!(_serial, "Reading i2c");
-        let _ = i2c.write_read(self.address, &cmd, &mut data);
+        i2c.write_read(self.address, &cmd, &mut data)?;
 
-        u16::from_be_bytes(data)
+        Ok(u16::from_be_bytes(data))
+    }
+    pub fn read_register(
+        &mut self,
+        register: ControlRegister,
+        i2c: &mut I2c,
+    ) -> Result<u8, i2c::Error> {
+        let mut data = [0u8; 1];
+        i2c.write_read(self.address, &[register.into()], &mut data)?;
+        Ok(data[0])
+    }
+    /// Reads the last distance along with the status, signal strength and velocity it was taken with,
+    /// in a single transaction so they all come from the same measurement
+    pub fn read_full_measurement(
+        &mut self,
+        i2c: &mut I2c,
+        _serial: &mut Usart0<MHz16>,
+    ) -> Result<LidarMeasurement, i2c::Error> {
+        let mut registers = [0u8; MEASUREMENT_REGISTERS];
+        let cmd: [u8; 1] = [u8::from(ControlRegister::STATUS) | AUTO_INCREMENT];
+        i2c.write_read(self.address, &cmd, &mut registers)?;
+        let register = |register: ControlRegister| {
+            registers[(register.0 - ControlRegister::STATUS.0) as usize]
+        };
+
+        let status = register(ControlRegister::STATUS);
+        let signal_strength = register(ControlRegister::SIGNAL_STRENGTH);
+        let velocity = register(ControlRegister::VELOCITY) as i8;
+        let distance_cm = u16::from_be_bytes([
+            register(ControlRegister::LAST_DELAY_HIGH),
+            register(ControlRegister::LAST_DELAY_LOW),
+        ]);
+        let healthy = status & u8::from(Status::HEALTH_FLAG) != 0;
+        let valid = healthy
+            && status & Status::INVALID_MASK == 0
+            && signal_strength >= MIN_SIGNAL_STRENGTH
+            // The lidar reports 1cm when it gets no return
+            && distance_cm > 1;
+        Ok(LidarMeasurement {
+            distance_cm,
+            status,
+            signal_strength,
+            velocity,
+            i2c_error: false,
+            valid,
+        })
     }
 }
 
 impl I2cLidarOps for GarminLidarV3 {
-    fn read_distance_cm(&mut self, i2c: &mut I2c, serial: &mut Usart0<MHz16>) -> u16 {
-        self.read_last_measurement(i2c, serial)
+    fn read_measurement(&mut self, i2c: &mut I2c, serial: &mut Usart0<MHz16>) -> LidarMeasurement {
+        self.read_full_measurement(i2c, serial)
+            .unwrap_or(LidarMeasurement {
+                i2c_error: true,
+                ..Default::default()
+            })
+    }
+    fn configure(
+        &mut self,
+        settings: AcquisitionSettings,
+        i2c: &mut I2c,
+        serial: &mut Usart0<MHz16>,
+    ) -> Result<(), i2c::Error> {
+        self.set_acquisition(settings, i2c, serial)
     }
 }
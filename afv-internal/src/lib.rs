@@ -17,6 +17,7 @@
 //! NOTE: This crate and its containd binary programs shoud ONLY EVER BE RUN IN RELEASE MODE.
 
 #![no_std]
+#![cfg_attr(target_arch = "avr", feature(abi_avr_interrupt, asm_experimental_arch))]
 
 /// The port of the FLIR turret TCP server
 pub const FLIR_TURRET_PORT: u16 = 3031;
@@ -30,6 +31,10 @@ pub const PUMP_PORT: u16 = 3034;
 pub const LIGHTS_PORT: u16 = 3035;
 /// The port of the Sirens TCP server
 pub const SIREN_PORT: u16 = 3036;
+/// The port of the board level System TCP server that every board runs
+pub const SYSTEM_PORT: u16 = 3037;
+/// The port of the log TCP server that every board runs
+pub const LOG_PORT: u16 = 3038;
 /// The packet size of communcation between the GCS-AFV drivers and the AFV-INTERNAL drivers
 pub const SOCKET_MSG_SIZE: usize = 256;
 pub const PAN_STEPPER_STEPS_REV: u32 = 200;
@@ -61,14 +66,43 @@ pub mod servo;
 /// a TCP server
 pub mod lidar;
 
-/// This module provides a convenience wrapper for controlling the pump and transeiving data on 
-/// a TCP server
+/// This module provides a single output pin service, used for the pump, lights and sirens, that supports PWM and
+/// patterns timed on the board and transeives data on a TCP server
+pub mod output;
+
+/// This module wraps the pump's [output] channel with timed runs and an estimate of the water it has dispensed
 pub mod pump;
 
-/// This module provides a convenience wrapper for controlling the lights and transeiving data on 
-/// a TCP server
-pub mod lights;
+/// This module holds the board wide emergency stop latch the turret and output services honour
+pub mod estop;
 
-/// This module provides a convenience wrapper for controlling the sirens and transeiving data on 
-/// a TCP server
-pub mod sirens;
+/// This module contains the ADC sampling and flow meter pulse counting the pump uses to tell whether water is moving
+pub mod sensors;
+
+/// This module provides a convenience wrapper around the [Atmega328p's](https://www.microchip.com/en-us/product/ATmega328P) EEPROM
+pub mod eeprom;
+
+/// This module contains the versioned board configuration and its persistent storage in the [eeprom]
+pub mod config;
+
+/// This module provides the board level service used to manage a board's [config] from the host
+pub mod system;
+
+/// This module turns a [config::BoardConfig] into running services so a single firmware image can serve every board
+pub mod board;
+
+/// This module provides the board's monotonic millisecond tick on the 8 bit TC0 timer
+pub mod clock;
+
+/// This module provides the cooperative [scheduler::Task] runner that drives the firmware's main loop
+pub mod scheduler;
+
+/// This module provides leveled logging that is buffered in RAM and shipped to the host over the network.
+/// Use the [log_error], [log_warn], [log_info] and [log_debug] macros to record
+pub mod logging;
+
+/// This module records firmware panics and the reset cause so they can be reported to the host after the board restarts
+pub mod fault;
+
+/// This module stages new firmware images in flash so the bootloader can install them, with rollback
+pub mod update;
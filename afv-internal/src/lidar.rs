@@ -1,6 +1,7 @@
 use arduino_hal::{
     clock::MHz16,
     hal::{port::PB2, usart::Usart0},
+    i2c,
     spi::ChipSelectPin,
     I2c, Spi,
 };
@@ -8,7 +9,9 @@ use serde::{Deserialize, Serialize};
 use ufmt::derive::uDebug;
 
 use crate::{
+    clock,
     network::InternalMessage,
+    scheduler::{Io, Task},
     w5500::{
         socket_register::{self, Socket, SocketBlock},
         W5500,
@@ -16,25 +19,96 @@ use crate::{
     LIDAR_PORT,
 };
 
+/// The most sensors a single [Lidar] service can own, enough for one per turret
+pub const MAX_LIDARS: usize = 2;
+/// Readings weaker than this are too noisy to range on
+pub const MIN_SIGNAL_STRENGTH: u8 = 10;
+/// The fastest a subscription may push readings, however quickly the sensors are read
+pub const MIN_STREAM_INTERVAL_MS: u16 = 20;
+/// A subscription's interval is kept to at least this many times the longest a reading of every sensor has taken,
+/// so streaming never starves the other tasks
+pub const STREAM_INTERVAL_READS: u32 = 2;
+
+/// How a sensor takes its measurements, trading range and accuracy against rate
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct AcquisitionSettings {
+    /// The most acquisitions summed into one measurement, more reach further but take longer
+    pub count: u8,
+    /// Ends a measurement as soon as a strong enough return is seen
+    pub quick_termination: bool,
+    /// A fixed detection threshold, 0 uses the sensor's noise based default
+    pub threshold_bypass: u8,
+    /// The free running measurement rate, 0 keeps the sensor's default delay
+    pub rate_hz: u8,
+}
+
+impl Default for AcquisitionSettings {
+    fn default() -> Self {
+        Self {
+            count: 0x80,
+            quick_termination: false,
+            threshold_bypass: 0,
+            rate_hz: 0,
+        }
+    }
+}
+
+/// A single reading and the state the sensor reported alongside it
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LidarMeasurement {
+    pub distance_cm: u16,
+    /// The raw status register, see [crate::garmin_lidar_v3::Status]
+    pub status: u8,
+    pub signal_strength: u8,
+    /// The change in distance since the previous reading, in cm
+    pub velocity: i8,
+    /// Set when the sensor could not be read at all, the other fields are then zeroed
+    pub i2c_error: bool,
+    /// Whether the distance can be trusted
+    pub valid: bool,
+}
+
 #[derive(uDebug, Serialize, Deserialize, Clone, Copy)]
 pub enum LidarMsg {
+    /// Polls every sensor, each answers with its own [LidarMsg::Measurement]
     PollLidar,
-    LidarDistanceCm(u32),
+    /// Polls a single sensor
+    PollSensor(u8),
+    /// The reading taken by the sensor with the given index
+    Measurement(u8, LidarMeasurement),
+    /// Applies new settings to a sensor, answered with [LidarMsg::Configured]
+    Configure(u8, AcquisitionSettings),
+    /// Whether the sensor accepted its new settings
+    Configured(u8, bool),
+    /// Pushes a reading from every sensor each interval in ms, until the host disconnects or unsubscribes.
+    /// Intervals shorter than [Lidar::min_stream_interval_ms] are stretched to it
+    Subscribe(u16),
+    Unsubscribe,
 }
 
 pub trait I2cLidarOps {
-    fn read_distance_cm(&mut self, i2c: &mut I2c, serial: &mut Usart0<MHz16>) -> u16;
+    fn read_measurement(&mut self, i2c: &mut I2c, serial: &mut Usart0<MHz16>) -> LidarMeasurement;
+    fn configure(
+        &mut self,
+        settings: AcquisitionSettings,
+        i2c: &mut I2c,
+        serial: &mut Usart0<MHz16>,
+    ) -> Result<(), i2c::Error>;
 }
 
 pub struct Lidar<L: I2cLidarOps> {
     socket: Socket,
-    lidar: L,
+    sensors: [Option<L>; MAX_LIDARS],
+    /// Set while the host is subscribed
+    stream_interval_ms: Option<u16>,
+    /// The longest reading and sending every sensor's measurement has taken, in µs
+    read_us: u32,
 }
 
 impl<L: I2cLidarOps> Lidar<L> {
     pub fn new(
         socket_block: SocketBlock,
-        lidar: L,
+        sensors: [Option<L>; MAX_LIDARS],
         spi: &mut Spi,
         cs: &mut ChipSelectPin<PB2>,
         serial: &mut Usart0<MHz16>,
@@ -42,7 +116,18 @@ impl<L: I2cLidarOps> Lidar<L> {
         let mode = socket_register::Mode::default().set_protocol_tcp();
         let socket = W5500::socket_n(socket_block, mode, LIDAR_PORT, spi, cs);
         let _ = ufmt::uwriteln!(serial, "Created lidar using port {}", LIDAR_PORT);
-        Self { socket, lidar }
+        Self {
+            socket,
+            sensors,
+            stream_interval_ms: None,
+            read_us: 0,
+        }
+    }
+
+    /// The fastest a subscription may stream at, from how long reading the sensors has taken so far
+    pub fn min_stream_interval_ms(&self) -> u16 {
+        let read_ms = (self.read_us * STREAM_INTERVAL_READS + 999) / 1000;
+        read_ms.clamp(MIN_STREAM_INTERVAL_MS as u32, u16::MAX as u32) as u16
     }
 
     pub fn process(
@@ -59,25 +144,85 @@ impl<L: I2cLidarOps> Lidar<L> {
                 }
                 InternalMessage::Lidar(LidarMsg::PollLidar) => {
                     // let _ = ufmt::uwriteln!(serial, "Lidar distance polled");
-                    self.poll_distance(i2c, spi, cs, serial);
+                    self.poll_all(i2c, spi, cs, serial);
+                }
+                InternalMessage::Lidar(LidarMsg::PollSensor(sensor)) => {
+                    self.poll_distance(sensor as usize, i2c, spi, cs, serial);
+                }
+                InternalMessage::Lidar(LidarMsg::Configure(sensor, settings)) => {
+                    let configured = match self.sensors.get_mut(sensor as usize) {
+                        Some(Some(lidar)) => lidar.configure(settings, i2c, serial).is_ok(),
+                        _ => false,
+                    };
+                    if !configured {
+                        crate::log_warn!("Lidar {} could not be configured", sensor);
+                    }
+                    let msg = InternalMessage::Lidar(LidarMsg::Configured(sensor, configured));
+                    self.socket.send(msg, spi, cs);
+                }
+                InternalMessage::Lidar(LidarMsg::Subscribe(interval_ms)) => {
+                    self.stream_interval_ms = Some(interval_ms);
+                }
+                InternalMessage::Lidar(LidarMsg::Unsubscribe) => {
+                    self.stream_interval_ms = None;
                 }
                 _ => {}
             }
         }
     }
+    /// Sends every sensor's reading, timing how long it takes
+    fn poll_all(
+        &mut self,
+        i2c: &mut I2c,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) {
+        let start = clock::micros();
+        for sensor in 0..MAX_LIDARS {
+            self.poll_distance(sensor, i2c, spi, cs, serial);
+        }
+        self.read_us = self.read_us.max(clock::micros().wrapping_sub(start));
+    }
+    /// Sends the reading taken by the sensor, if it exists
     pub fn poll_distance(
         &mut self,
+        sensor: usize,
         i2c: &mut I2c,
         spi: &mut Spi,
         cs: &mut ChipSelectPin<PB2>,
         serial: &mut Usart0<MHz16>,
     ) {
-        let distance = self.lidar.read_distance_cm(i2c, serial);
+        let lidar = match self.sensors.get_mut(sensor) {
+            Some(Some(lidar)) => lidar,
+            _ => return,
+        };
+        let measurement = lidar.read_measurement(i2c, serial);
+        if measurement.i2c_error {
+            crate::log_warn!("Lidar {} did not answer", sensor);
+        }
         // let _ = ufmt::uwriteln!(serial, "Lidar calculated distance {}", distance);
-        let msg = InternalMessage::Lidar(LidarMsg::LidarDistanceCm(
-            self.lidar.read_distance_cm(i2c, serial) as u32,
-        ));
+        let msg = InternalMessage::Lidar(LidarMsg::Measurement(sensor as u8, measurement));
         self.socket.send(msg, spi, cs);
         // let _ = ufmt::uwriteln!(serial, "Lidar sent distance");
     }
 }
+
+impl<L: I2cLidarOps> Task for Lidar<L> {
+    fn poll(&mut self, io: &mut Io) {
+        self.process(io.i2c, io.spi, io.cs, io.serial);
+    }
+    fn period_ms(&self) -> Option<u32> {
+        self.stream_interval_ms
+            .map(|interval| interval.max(self.min_stream_interval_ms()) as u32)
+    }
+    /// Pushes readings to a subscribed host
+    fn tick(&mut self, _now: u32, io: &mut Io) {
+        // A new connection has to subscribe again
+        if !self.socket.connected() {
+            self.stream_interval_ms = None;
+            return;
+        }
+        self.poll_all(io.i2c, io.spi, io.cs, io.serial);
+    }
+}
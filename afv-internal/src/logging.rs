@@ -0,0 +1,252 @@
+use core::cell::RefCell;
+
+use arduino_hal::{
+    clock::MHz16,
+    hal::{port::PB2, usart::Usart0},
+    spi::ChipSelectPin,
+    Spi,
+};
+use avr_device::interrupt::Mutex;
+use serde::{Deserialize, Serialize};
+use ufmt::{derive::uDebug, uWrite};
+
+use crate::{
+    clock,
+    fault::{self, FaultMsg, FaultReport},
+    network::InternalMessage,
+    scheduler::{Io, Task},
+    w5500::{
+        socket_register::{self, Socket, SocketBlock},
+        W5500,
+    },
+    LOG_PORT, SOCKET_MSG_SIZE,
+};
+
+/// The most text a single [LogRecord] carries, longer messages are truncated
+pub const LOG_TEXT_SIZE: usize = 32;
+/// The number of records buffered while no host is connected. The oldest records are dropped first
+pub const LOG_BUFFER_RECORDS: usize = 6;
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(uDebug, Serialize, Deserialize, Clone, Copy)]
+pub struct LogRecord {
+    pub uptime_ms: u32,
+    pub level: LogLevel,
+    /// The number of records that were dropped from a full buffer before this one
+    pub dropped: u8,
+    len: u8,
+    text: [u8; LOG_TEXT_SIZE],
+}
+
+impl LogRecord {
+    const EMPTY: LogRecord = LogRecord {
+        uptime_ms: 0,
+        level: LogLevel::Debug,
+        dropped: 0,
+        len: 0,
+        text: [0; LOG_TEXT_SIZE],
+    };
+    pub fn text(&self) -> &str {
+        let len = (self.len as usize).min(LOG_TEXT_SIZE);
+        core::str::from_utf8(&self.text[..len]).unwrap_or("")
+    }
+}
+
+#[derive(uDebug, Serialize, Deserialize, Clone, Copy)]
+pub enum LogMsg {
+    Record(LogRecord),
+    /// Sets the most verbose level that is recorded
+    SetLevel(LogLevel),
+}
+
+impl uWrite for LogRecord {
+    type Error = ();
+    fn write_str(&mut self, s: &str) -> Result<(), ()> {
+        for c in s.chars() {
+            let start = self.len as usize;
+            if start + c.len_utf8() > LOG_TEXT_SIZE {
+                return Ok(());
+            }
+            c.encode_utf8(&mut self.text[start..]);
+            self.len += c.len_utf8() as u8;
+        }
+        Ok(())
+    }
+}
+
+struct LogBuffer {
+    level: LogLevel,
+    records: [LogRecord; LOG_BUFFER_RECORDS],
+    head: usize,
+    count: usize,
+    dropped: u8,
+}
+
+static LOG_BUFFER: Mutex<RefCell<LogBuffer>> = Mutex::new(RefCell::new(LogBuffer {
+    level: LogLevel::Info,
+    records: [LogRecord::EMPTY; LOG_BUFFER_RECORDS],
+    head: 0,
+    count: 0,
+    dropped: 0,
+}));
+
+/// Returns true if records at `level` are currently being recorded
+pub fn enabled(level: LogLevel) -> bool {
+    avr_device::interrupt::free(|cs| level <= LOG_BUFFER.borrow(cs).borrow().level)
+}
+
+pub fn set_level(level: LogLevel) {
+    avr_device::interrupt::free(|cs| LOG_BUFFER.borrow(cs).borrow_mut().level = level);
+}
+
+/// Buffers a record. This is normally called through the [log_error], [log_warn], [log_info] and [log_debug] macros
+pub fn log(level: LogLevel, write: impl FnOnce(&mut LogRecord)) {
+    if !enabled(level) {
+        return;
+    }
+    let mut record = LogRecord {
+        uptime_ms: clock::millis(),
+        level,
+        ..LogRecord::EMPTY
+    };
+    write(&mut record);
+    avr_device::interrupt::free(|cs| {
+        let mut buffer = LOG_BUFFER.borrow(cs).borrow_mut();
+        if buffer.count == LOG_BUFFER_RECORDS {
+            buffer.head = (buffer.head + 1) % LOG_BUFFER_RECORDS;
+            buffer.count -= 1;
+            buffer.dropped = buffer.dropped.saturating_add(1);
+        }
+        let tail = (buffer.head + buffer.count) % LOG_BUFFER_RECORDS;
+        buffer.records[tail] = record;
+        buffer.count += 1;
+    });
+}
+
+/// Takes the oldest buffered record
+pub fn pop() -> Option<LogRecord> {
+    avr_device::interrupt::free(|cs| {
+        let mut buffer = LOG_BUFFER.borrow(cs).borrow_mut();
+        if buffer.count == 0 {
+            return None;
+        }
+        let mut record = buffer.records[buffer.head];
+        record.dropped = buffer.dropped;
+        buffer.dropped = 0;
+        buffer.head = (buffer.head + 1) % LOG_BUFFER_RECORDS;
+        buffer.count -= 1;
+        Some(record)
+    })
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::LogLevel::Error, |record| {
+            let _ = ufmt::uwrite!(record, $($arg)*);
+        })
+    };
+}
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::LogLevel::Warn, |record| {
+            let _ = ufmt::uwrite!(record, $($arg)*);
+        })
+    };
+}
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::LogLevel::Info, |record| {
+            let _ = ufmt::uwrite!(record, $($arg)*);
+        })
+    };
+}
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::LogLevel::Debug, |record| {
+            let _ = ufmt::uwrite!(record, $($arg)*);
+        })
+    };
+}
+
+/// Ships the buffered records to the host connected to [LOG_PORT], one record per pass of the main loop.
+/// The board's [FaultReport] is sent first on every new connection until the host acknowledges it
+pub struct Logger {
+    socket: Socket,
+    report: Option<FaultReport>,
+    reported: bool,
+}
+
+impl Logger {
+    pub fn new(
+        socket_block: SocketBlock,
+        report: FaultReport,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) -> Self {
+        let mode = socket_register::Mode::default().set_protocol_tcp();
+        let socket = W5500::socket_n(socket_block, mode, LOG_PORT, spi, cs);
+        let _ = ufmt::uwriteln!(serial, "Created logger using port {}", LOG_PORT);
+        Self {
+            socket,
+            report: Some(report),
+            reported: false,
+        }
+    }
+
+    pub fn process(
+        &mut self,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) {
+        match self.socket.receive_connected(spi, cs, serial) {
+            Some(InternalMessage::Ping(val)) => {
+                self.socket.send(InternalMessage::Ping(val), spi, cs);
+            }
+            Some(InternalMessage::Log(LogMsg::SetLevel(level))) => {
+                set_level(level);
+            }
+            Some(InternalMessage::Fault(FaultMsg::Acknowledge)) => {
+                if let Some(FaultReport { fault: Some(_), .. }) = self.report {
+                    fault::request_clear();
+                }
+                self.report = None;
+            }
+            _ => {}
+        }
+        // Records stay buffered until there is a host to receive them
+        if !self.socket.connected() {
+            self.reported = false;
+            return;
+        }
+        if self.socket.read_tx_free_size(spi, cs) < SOCKET_MSG_SIZE as u16 {
+            return;
+        }
+        if let (Some(report), false) = (self.report, self.reported) {
+            self.socket
+                .send(InternalMessage::Fault(FaultMsg::Report(report)), spi, cs);
+            self.reported = true;
+        } else if let Some(record) = pop() {
+            self.socket
+                .send(InternalMessage::Log(LogMsg::Record(record)), spi, cs);
+        }
+    }
+}
+
+impl Task for Logger {
+    fn poll(&mut self, io: &mut Io) {
+        self.process(io.spi, io.cs, io.serial);
+    }
+}
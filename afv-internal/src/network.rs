@@ -2,7 +2,16 @@ use serde::{Deserialize, Serialize};
 use ufmt::derive::uDebug;
 
 use crate::{
-    lidar::LidarMsg, lights::LightsMsg, pump::PumpMsg, sirens::SirenMsg, turret::TurretMsg,
+    config::ConfigMsg,
+    estop::EStopMsg,
+    fault::FaultMsg,
+    lidar::LidarMsg,
+    logging::LogMsg,
+    output::{OutputMsg, SwitchMsg},
+    pump::PumpMsg,
+    scheduler::LoopStats,
+    turret::TurretMsg,
+    update::UpdateMsg,
     SOCKET_MSG_SIZE,
 };
 
@@ -12,8 +21,16 @@ pub enum InternalMessage {
     Turret(TurretMsg),
     Lidar(LidarMsg),
     Pump(PumpMsg),
-    Lights(LightsMsg),
-    Siren(SirenMsg),
+    Lights(SwitchMsg),
+    Siren(SwitchMsg),
+    Config(ConfigMsg),
+    PollLoopStats,
+    LoopStats(LoopStats),
+    Log(LogMsg),
+    Fault(FaultMsg),
+    Update(UpdateMsg),
+    Output(OutputMsg),
+    EmergencyStop(EStopMsg),
 }
 
 impl InternalMessage {
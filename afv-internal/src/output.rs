@@ -0,0 +1,322 @@
+use arduino_hal::{
+    clock::MHz16,
+    hal::{port::PB2, usart::Usart0},
+    spi::ChipSelectPin,
+    Spi,
+};
+use embedded_hal::digital::v2::OutputPin;
+use serde::{Deserialize, Serialize};
+use ufmt::derive::uDebug;
+
+use crate::{
+    clock, estop,
+    network::InternalMessage,
+    scheduler::{Io, Task},
+    w5500::{
+        socket_register::{self, Socket, SocketBlock},
+        W5500,
+    },
+    LIGHTS_PORT, PUMP_PORT, SIREN_PORT,
+};
+
+pub const PUMP_CHANNEL: u8 = 0;
+pub const LIGHTS_CHANNEL: u8 = 1;
+pub const SIREN_CHANNEL: u8 = 2;
+/// The period of the software PWM, fine enough for motors and relays but not for audio
+pub const PWM_PERIOD_US: u32 = 10_000;
+
+/// Each channel keeps the port its service had before [OutputChannel] replaced it
+pub fn channel_port(channel: u8) -> Option<u16> {
+    match channel {
+        PUMP_CHANNEL => Some(PUMP_PORT),
+        LIGHTS_CHANNEL => Some(LIGHTS_PORT),
+        SIREN_CHANNEL => Some(SIREN_PORT),
+        _ => None,
+    }
+}
+
+/// The commands the lights and siren services took before [OutputChannel], still accepted on their ports
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchMsg {
+    TurnOn,
+    TurnOff,
+}
+
+/// Patterns are timed on the board, so they keep their rhythm however often the host talks to it
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// On for `on_ms` then off for `off_ms`
+    Blink { on_ms: u16, off_ms: u16 },
+    /// A burst of `flashes` flashes `flash_ms` long, then off for `pause_ms`
+    Strobe {
+        flashes: u8,
+        flash_ms: u16,
+        pause_ms: u16,
+    },
+    /// The duty ramps from off to full over `rise_ms` and back down over `fall_ms`
+    Wail { rise_ms: u16, fall_ms: u16 },
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OutputCommand {
+    On,
+    Off,
+    /// A PWM duty cycle out of 255
+    Duty(u8),
+    /// On for the given ms, then off
+    Pulse(u16),
+    Pattern(Pattern),
+}
+
+/// What a channel is doing, as read back from the board rather than what a host last asked for
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct OutputState {
+    pub command: OutputCommand,
+    /// The pin's level when the state was read, PWM and patterns toggle it
+    pub level: bool,
+}
+
+impl OutputState {
+    /// Whether the command drives the pin at all
+    pub fn active(&self) -> bool {
+        !matches!(self.command, OutputCommand::Off | OutputCommand::Duty(0))
+    }
+}
+
+#[derive(uDebug, Serialize, Deserialize, Clone, Copy)]
+pub enum OutputMsg {
+    /// Runs the command on the channel with the given id. Repeating the running command leaves its timing alone,
+    /// so hosts can resend it as a keep alive
+    Command(u8, OutputCommand),
+    /// Asks the channel with the given id for its [OutputState]
+    PollState(u8),
+    /// Answers [OutputMsg::PollState], and is sent unprompted when a host connects and whenever the command changes
+    State(u8, OutputState),
+}
+
+/// A single output pin, such as the pump, lights or siren, driven by an [OutputCommand]
+pub struct OutputChannel<Pin: OutputPin> {
+    channel: u8,
+    socket: Socket,
+    ctl: Pin,
+    command: OutputCommand,
+    /// When the command was started, in [clock::millis]
+    started_ms: u32,
+    level: bool,
+    /// The command last reported to the connected host, None until the host has been sent one
+    reported: Option<OutputCommand>,
+    /// Set by the owning service, such as the pump after it runs dry, to refuse every command but off
+    locked: bool,
+}
+
+impl<Pin: OutputPin> OutputChannel<Pin> {
+    pub fn new(
+        channel: u8,
+        port: u16,
+        socket_block: SocketBlock,
+        mut ctl_pin: Pin,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) -> Self {
+        let mode = socket_register::Mode::default().set_protocol_tcp();
+        let socket = W5500::socket_n(socket_block, mode, port, spi, cs);
+        let _ = ufmt::uwriteln!(serial, "Created output {} using port {}", channel, port);
+        let _ = ctl_pin.set_low();
+
+        Self {
+            channel,
+            socket,
+            ctl: ctl_pin,
+            command: OutputCommand::Off,
+            started_ms: clock::millis(),
+            level: false,
+            reported: None,
+            locked: false,
+        }
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn command(&self) -> OutputCommand {
+        self.command
+    }
+
+    pub fn state(&self) -> OutputState {
+        OutputState {
+            command: self.command,
+            level: self.level,
+        }
+    }
+
+    pub fn set_command(&mut self, command: OutputCommand) {
+        if command == self.command {
+            return;
+        }
+        crate::log_debug!("Output {} command {:?}", self.channel, command);
+        self.command = command;
+        self.started_ms = clock::millis();
+        self.update();
+    }
+
+    /// While locked the channel is off and refuses every command but [OutputCommand::Off], whichever host sends it
+    pub fn set_locked(&mut self, locked: bool) {
+        self.locked = locked;
+        if locked {
+            self.set_command(OutputCommand::Off);
+        }
+    }
+
+    pub fn send(&mut self, msg: InternalMessage, spi: &mut Spi, cs: &mut ChipSelectPin<PB2>) {
+        self.socket.send(msg, spi, cs);
+    }
+
+    /// Runs the commands sent to the channel, passing back any other message for the owning service to handle
+    pub fn process(
+        &mut self,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) -> Option<InternalMessage> {
+        let command = match self.socket.receive_connected(spi, cs, serial)? {
+            InternalMessage::Ping(val) => {
+                self.socket.send(InternalMessage::Ping(val), spi, cs);
+                return None;
+            }
+            InternalMessage::Output(OutputMsg::Command(channel, command))
+                if channel == self.channel =>
+            {
+                command
+            }
+            InternalMessage::EmergencyStop(msg) => {
+                if let Some(reply) = estop::process(msg) {
+                    self.socket.send(reply, spi, cs);
+                }
+                return None;
+            }
+            InternalMessage::Output(OutputMsg::PollState(channel)) if channel == self.channel => {
+                let state = self.state();
+                self.reported = Some(state.command);
+                self.socket.send(
+                    InternalMessage::Output(OutputMsg::State(self.channel, state)),
+                    spi,
+                    cs,
+                );
+                return None;
+            }
+            InternalMessage::Lights(msg) if self.channel == LIGHTS_CHANNEL => msg.into(),
+            InternalMessage::Siren(msg) if self.channel == SIREN_CHANNEL => msg.into(),
+            msg => return Some(msg),
+        };
+        if estop::latched() && command != OutputCommand::Off {
+            crate::log_warn!("Output {} command refused, E-stop latched", self.channel);
+            return None;
+        }
+        if self.locked && command != OutputCommand::Off {
+            crate::log_warn!("Output {} command refused, locked out", self.channel);
+            return None;
+        }
+        self.set_command(command);
+        None
+    }
+
+    /// Sends the [OutputState] to the host when the command has changed since it was last told, including
+    /// commands that end on their own such as [OutputCommand::Pulse]
+    pub fn report(&mut self, spi: &mut Spi, cs: &mut ChipSelectPin<PB2>) {
+        if !self.socket.connected() {
+            // A host that connects later is sent the state straight away
+            self.reported = None;
+            return;
+        }
+        if self.reported == Some(self.command) {
+            return;
+        }
+        self.reported = Some(self.command);
+        let state = self.state();
+        self.socket.send(
+            InternalMessage::Output(OutputMsg::State(self.channel, state)),
+            spi,
+            cs,
+        );
+    }
+
+    /// Drives the pin to where the command has it at this moment
+    pub fn update(&mut self) {
+        // A stop latched through another service's socket turns this output off too
+        if estop::latched() && self.command != OutputCommand::Off {
+            self.command = OutputCommand::Off;
+            self.started_ms = clock::millis();
+        }
+        let elapsed = clock::elapsed_ms(self.started_ms);
+        let level = match self.command {
+            OutputCommand::On => true,
+            OutputCommand::Off => false,
+            OutputCommand::Duty(duty) => pwm(duty),
+            OutputCommand::Pulse(ms) => {
+                if elapsed >= ms as u32 {
+                    self.command = OutputCommand::Off;
+                }
+                elapsed < ms as u32
+            }
+            OutputCommand::Pattern(Pattern::Blink { on_ms, off_ms }) => {
+                let period = (on_ms as u32 + off_ms as u32).max(1);
+                elapsed % period < on_ms as u32
+            }
+            OutputCommand::Pattern(Pattern::Strobe {
+                flashes,
+                flash_ms,
+                pause_ms,
+            }) => {
+                let flash_ms = (flash_ms as u32).max(1);
+                let burst = flashes as u32 * 2 * flash_ms;
+                let phase = elapsed % (burst + pause_ms as u32).max(1);
+                phase < burst && (phase / flash_ms) % 2 == 0
+            }
+            OutputCommand::Pattern(Pattern::Wail { rise_ms, fall_ms }) => {
+                let (rise, fall) = (rise_ms as u32, fall_ms as u32);
+                let phase = elapsed % (rise + fall).max(1);
+                let duty = match phase < rise {
+                    true => phase * 255 / rise.max(1),
+                    false => (rise + fall - phase) * 255 / fall.max(1),
+                };
+                pwm(duty.min(255) as u8)
+            }
+        };
+        if level != self.level {
+            self.level = level;
+            let _ = match level {
+                true => self.ctl.set_high(),
+                false => self.ctl.set_low(),
+            };
+        }
+    }
+}
+
+/// Whether a PWM output with this duty is high at the current [clock::micros]
+fn pwm(duty: u8) -> bool {
+    match duty {
+        0 => false,
+        255 => true,
+        duty => clock::micros() % PWM_PERIOD_US < PWM_PERIOD_US * duty as u32 / 255,
+    }
+}
+
+impl From<SwitchMsg> for OutputCommand {
+    fn from(msg: SwitchMsg) -> Self {
+        match msg {
+            SwitchMsg::TurnOn => OutputCommand::On,
+            SwitchMsg::TurnOff => OutputCommand::Off,
+        }
+    }
+}
+
+impl<Pin: OutputPin> Task for OutputChannel<Pin> {
+    fn poll(&mut self, io: &mut Io) {
+        self.process(io.spi, io.cs, io.serial);
+        // PWM and patterns are timed off the main loop, so the pin is updated on every pass
+        self.update();
+        self.report(io.spi, io.cs);
+    }
+}
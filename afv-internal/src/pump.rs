@@ -9,40 +9,141 @@ use serde::{Deserialize, Serialize};
 use ufmt::derive::uDebug;
 
 use crate::{
+    clock, estop,
     network::InternalMessage,
-    w5500::{
-        socket_register::{self, Socket, SocketBlock},
-        W5500,
-    },
-    PUMP_PORT,
+    output::{OutputChannel, OutputCommand},
+    scheduler::{Io, Task},
+    sensors::{Adc, AnalogSensor, FlowMeter},
 };
 
+/// Flow and pressure take a moment to build after the pump starts, so dry running is not checked before this
+pub const DRY_RUN_GRACE_MS: u32 = 3000;
+/// How long flow or pressure must stay low before the pump is shut off
+pub const DRY_RUN_TIMEOUT_MS: u32 = 2000;
+/// How often the pressure sensor is sampled
+pub const PRESSURE_SAMPLE_MS: u32 = 20;
+
 #[derive(uDebug, Serialize, Deserialize, Clone, Copy)]
 pub enum PumpMsg {
     TurnOn,
     TurnOff,
+    /// Runs the pump for the given ms, then turns it off
+    RunFor(u32),
+    /// Runs the pump until it has dispensed this many ml, measured by the flow meter if there is one,
+    /// otherwise going by the configured flow rate
+    RunVolume(u32),
+    PollStatus,
+    /// Answers [PumpMsg::PollStatus], and is sent unprompted whenever a run ends
+    Status(PumpStatus),
+}
+
+/// Run time and the volume estimated from it, counted since the board started, along with any sensor readings
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PumpStatus {
+    pub running: bool,
+    /// How long the current or last run went on for
+    pub run_ms: u32,
+    /// The length the current or last run was timed to, 0 if it was not timed
+    pub run_target_ms: u32,
+    pub run_volume_ml: u32,
+    pub total_runtime_ms: u32,
+    pub total_volume_ml: u32,
+    pub flow_ml_per_min: u16,
+    /// None without a pressure sensor
+    pub pressure_kpa: Option<u16>,
+    /// None without a flow meter
+    pub measured_flow_ml_per_min: Option<u16>,
+    /// The volume the flow meter counted over the current or last run
+    pub measured_volume_ml: Option<u32>,
+    /// Set when a run was shut off for running dry. The pump refuses to run until it is sent [PumpMsg::TurnOff]
+    /// or the board restarts
+    pub dry_run: bool,
 }
 
+/// The pump's [OutputChannel] along with timed runs and volume accounting
 pub struct Pump<Pin: OutputPin> {
-    socket: Socket,
-    ctl: Pin,
+    output: OutputChannel<Pin>,
+    flow_ml_per_min: u16,
+    running: bool,
+    run_ms: u32,
+    run_target_ms: Option<u32>,
+    total_runtime_ms: u32,
+    last_update_ms: u32,
+    pressure: Option<AnalogSensor>,
+    min_pressure_kpa: u16,
+    last_sample_ms: u32,
+    flow_meter: Option<FlowMeter>,
+    min_flow_ml_per_min: u16,
+    /// The meter's pulse count when the current run started
+    run_start_pulses: u32,
+    /// The measured volume that ends the current run
+    run_target_ml: Option<u32>,
+    /// When flow or pressure first dropped below its minimum during this run
+    low_since_ms: Option<u32>,
+    dry_run: bool,
 }
 
 impl<Pin: OutputPin> Pump<Pin> {
-    pub fn new(
-        socket_block: SocketBlock,
-        ctl_pin: Pin,
-        spi: &mut Spi,
-        cs: &mut ChipSelectPin<PB2>,
-        serial: &mut Usart0<MHz16>,
-    ) -> Self {
-        let mode = socket_register::Mode::default().set_protocol_tcp();
-        let socket = W5500::socket_n(socket_block, mode, PUMP_PORT, spi, cs);
-        let _ = ufmt::uwriteln!(serial, "Created pump using port {}", PUMP_PORT);
-
+    pub fn new(output: OutputChannel<Pin>, flow_ml_per_min: u16) -> Self {
         Self {
-            socket,
-            ctl: ctl_pin,
+            output,
+            flow_ml_per_min,
+            running: false,
+            run_ms: 0,
+            run_target_ms: None,
+            total_runtime_ms: 0,
+            last_update_ms: clock::millis(),
+            pressure: None,
+            min_pressure_kpa: 0,
+            last_sample_ms: clock::millis(),
+            flow_meter: None,
+            min_flow_ml_per_min: 0,
+            run_start_pulses: 0,
+            run_target_ml: None,
+            low_since_ms: None,
+            dry_run: false,
+        }
+    }
+
+    /// Shuts the pump off when the pressure stays under `min_kpa` while it runs
+    pub fn set_pressure_sensor(&mut self, sensor: AnalogSensor, min_kpa: u16) {
+        self.pressure = Some(sensor);
+        self.min_pressure_kpa = min_kpa;
+    }
+
+    /// Measures the volume dispensed, and shuts the pump off when the flow stays under `min_ml_per_min` while it runs
+    pub fn set_flow_meter(&mut self, meter: FlowMeter, min_ml_per_min: u16) {
+        self.flow_meter = Some(meter);
+        self.min_flow_ml_per_min = min_ml_per_min;
+    }
+
+    /// The volume the flow meter counted since the current or last run started
+    fn measured_volume_ml(&self) -> Option<u32> {
+        let meter = self.flow_meter.as_ref()?;
+        Some(meter.volume_ml(meter.pulses().wrapping_sub(self.run_start_pulses)))
+    }
+
+    /// The volume the pump moves in the given time
+    pub fn volume_ml(&self, ms: u32) -> u32 {
+        (ms as u64 * self.flow_ml_per_min as u64 / 60_000) as u32
+    }
+
+    pub fn status(&self) -> PumpStatus {
+        PumpStatus {
+            running: self.running,
+            run_ms: self.run_ms,
+            run_target_ms: self.run_target_ms.unwrap_or(0),
+            run_volume_ml: self.volume_ml(self.run_ms),
+            total_runtime_ms: self.total_runtime_ms,
+            total_volume_ml: self.volume_ml(self.total_runtime_ms),
+            flow_ml_per_min: self.flow_ml_per_min,
+            pressure_kpa: self.pressure.as_ref().and_then(|sensor| sensor.value()),
+            measured_flow_ml_per_min: self
+                .flow_meter
+                .as_ref()
+                .map(|meter| meter.flow_ml_per_min()),
+            measured_volume_ml: self.measured_volume_ml(),
+            dry_run: self.dry_run,
         }
     }
 
@@ -52,16 +153,148 @@ impl<Pin: OutputPin> Pump<Pin> {
         cs: &mut ChipSelectPin<PB2>,
         serial: &mut Usart0<MHz16>,
     ) {
-        match self.socket.receive_connected(spi, cs, serial) {
-            Some(InternalMessage::Pump(PumpMsg::TurnOn)) => {
-                let _ = ufmt::uwriteln!(serial, "Pump on");
-                let _ = self.ctl.set_high();
+        let msg = match self.output.process(spi, cs, serial) {
+            Some(InternalMessage::Pump(msg)) => msg,
+            _ => return,
+        };
+        match msg {
+            // Hosts repeat this as a keep alive, so it must not restart a run
+            PumpMsg::TurnOn if !self.running => self.start(None, None),
+            PumpMsg::TurnOn => {}
+            PumpMsg::TurnOff => {
+                self.output.set_command(OutputCommand::Off);
+                if self.dry_run {
+                    crate::log_info!("Pump dry run cleared");
+                    self.dry_run = false;
+                    self.output.set_locked(false);
+                }
+            }
+            PumpMsg::RunFor(ms) => self.start(Some(ms), None),
+            PumpMsg::RunVolume(ml) if self.flow_meter.is_some() => {
+                // The time the volume should take at the configured rate, doubled, caps the run in case the meter fails
+                let ms = match self.flow_ml_per_min {
+                    0 => None,
+                    flow => Some((ml as u64 * 120_000 / flow as u64).min(u32::MAX as u64) as u32),
+                };
+                self.start(ms, Some(ml));
             }
-            Some(InternalMessage::Pump(PumpMsg::TurnOff)) => {
-                let _ = ufmt::uwriteln!(serial, "Pump off");
-                let _ = self.ctl.set_low();
+            PumpMsg::RunVolume(_) if self.flow_ml_per_min == 0 => {
+                crate::log_warn!("Pump has no flow rate configured");
             }
-            _ => {}
+            PumpMsg::RunVolume(ml) => {
+                let ms = ml as u64 * 60_000 / self.flow_ml_per_min as u64;
+                self.start(Some(ms.min(u32::MAX as u64) as u32), None);
+            }
+            PumpMsg::PollStatus => {
+                let status = self.status();
+                self.output
+                    .send(InternalMessage::Pump(PumpMsg::Status(status)), spi, cs);
+            }
+            PumpMsg::Status(_) => {}
+        }
+    }
+
+    fn start(&mut self, target_ms: Option<u32>, target_ml: Option<u32>) {
+        if estop::latched() {
+            crate::log_warn!("Pump run refused, E-stop latched");
+            return;
+        }
+        if self.dry_run {
+            crate::log_warn!("Pump run refused, it ran dry and has not been turned off since");
+            return;
         }
+        crate::log_info!("Pump run started");
+        self.output.set_command(OutputCommand::On);
+        self.begin_run(target_ms, target_ml);
+    }
+
+    fn begin_run(&mut self, target_ms: Option<u32>, target_ml: Option<u32>) {
+        self.running = true;
+        self.run_ms = 0;
+        self.run_target_ms = target_ms;
+        self.run_target_ml = target_ml;
+        self.run_start_pulses = self.flow_meter.as_ref().map_or(0, |meter| meter.pulses());
+        self.low_since_ms = None;
+    }
+
+    /// Whether the flow or pressure is below its minimum, false for sensors that are not fitted
+    fn starved(&self) -> bool {
+        let low_flow = self.flow_meter.as_ref().map_or(false, |meter| {
+            meter.flow_ml_per_min() < self.min_flow_ml_per_min
+        });
+        let low_pressure = self
+            .pressure
+            .as_ref()
+            .and_then(|sensor| sensor.value())
+            .map_or(false, |kpa| kpa < self.min_pressure_kpa);
+        low_flow || low_pressure
+    }
+
+    /// Shuts the pump off once it has been starved for [DRY_RUN_TIMEOUT_MS] past the [DRY_RUN_GRACE_MS] after starting
+    fn check_dry_run(&mut self) {
+        if !self.running || self.run_ms < DRY_RUN_GRACE_MS || !self.starved() {
+            self.low_since_ms = None;
+            return;
+        }
+        let since = *self.low_since_ms.get_or_insert(clock::millis());
+        if clock::elapsed_ms(since) >= DRY_RUN_TIMEOUT_MS {
+            crate::log_error!("Pump is running dry, shutting it off");
+            // Keeps hosts refreshing a lease, or driving the output channel directly, from turning it back on
+            self.output.set_locked(true);
+            self.dry_run = true;
+        }
+    }
+
+    /// Samples the sensors, counts the time the pump has been on since the last pass and ends finished or dry runs
+    pub fn update(&mut self, spi: &mut Spi, cs: &mut ChipSelectPin<PB2>, adc: &mut Adc) {
+        if let Some(sensor) = self.pressure.as_mut() {
+            if clock::elapsed_ms(self.last_sample_ms) >= PRESSURE_SAMPLE_MS {
+                self.last_sample_ms = clock::millis();
+                sensor.sample(adc);
+            }
+        }
+        if let Some(meter) = self.flow_meter.as_mut() {
+            meter.update();
+        }
+
+        let now = clock::millis();
+        let elapsed = now.wrapping_sub(self.last_update_ms);
+        self.last_update_ms = now;
+        if self.running {
+            self.run_ms = self.run_ms.saturating_add(elapsed);
+            self.total_runtime_ms = self.total_runtime_ms.saturating_add(elapsed);
+        }
+        if let Some(target) = self.run_target_ms {
+            if self.running && self.run_ms >= target {
+                self.output.set_command(OutputCommand::Off);
+            }
+        }
+        if let (Some(target), Some(measured)) = (self.run_target_ml, self.measured_volume_ml()) {
+            if self.running && measured >= target {
+                self.output.set_command(OutputCommand::Off);
+            }
+        }
+        self.check_dry_run();
+
+        // The output can also be driven through its own commands, which count as untimed runs
+        let on = self.output.state().active();
+        if on && !self.running {
+            self.begin_run(None, None);
+        } else if !on && self.running {
+            self.running = false;
+            let status = self.status();
+            crate::log_info!("Pump run ended after {}ms", status.run_ms);
+            self.output
+                .send(InternalMessage::Pump(PumpMsg::Status(status)), spi, cs);
+        }
+    }
+}
+
+impl<Pin: OutputPin> Task for Pump<Pin> {
+    fn poll(&mut self, io: &mut Io) {
+        self.process(io.spi, io.cs, io.serial);
+        self.update(io.spi, io.cs, io.adc);
+        self.output.update();
+        self.output.report(io.spi, io.cs);
     }
 }
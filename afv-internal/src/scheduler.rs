@@ -0,0 +1,139 @@
+use arduino_hal::{
+    clock::MHz16,
+    hal::{port::PB2, usart::Usart0},
+    spi::ChipSelectPin,
+    I2c, Spi,
+};
+use serde::{Deserialize, Serialize};
+use ufmt::derive::uDebug;
+
+use crate::{clock, fault, sensors::Adc};
+
+/// The most tasks a [Scheduler] keeps periodic timing for
+pub const MAX_TASKS: usize = 8;
+/// The window the [LoopStats] max and average are computed over
+pub const STATS_WINDOW_MS: u32 = 1000;
+
+/// The shared peripherals handed to every [Task]
+pub struct Io<'a> {
+    pub spi: &'a mut Spi,
+    pub cs: &'a mut ChipSelectPin<PB2>,
+    pub serial: &'a mut Usart0<MHz16>,
+    pub i2c: &'a mut I2c,
+    pub adc: &'a mut Adc,
+    /// The [LoopStats] as of the end of the previous pass
+    pub stats: LoopStats,
+}
+
+/// A unit of work run cooperatively by the [Scheduler].
+/// Tasks must never block; anything that waits should check the [clock] on a later poll instead.
+pub trait Task {
+    /// Event driven work such as servicing a socket. Called on every pass of the loop
+    fn poll(&mut self, io: &mut Io);
+    /// How often [Task::tick] should run in milliseconds, None if the task has no periodic work
+    fn period_ms(&self) -> Option<u32> {
+        None
+    }
+    /// Periodic work, `now` is the [clock::millis] value the tick was started at
+    fn tick(&mut self, _now: u32, _io: &mut Io) {}
+}
+
+/// Services the board was not configured to run are simply skipped
+impl<T: Task> Task for Option<T> {
+    fn poll(&mut self, io: &mut Io) {
+        if let Some(task) = self {
+            task.poll(io);
+        }
+    }
+    fn period_ms(&self) -> Option<u32> {
+        self.as_ref().and_then(|t| t.period_ms())
+    }
+    fn tick(&mut self, now: u32, io: &mut Io) {
+        if let Some(task) = self {
+            task.tick(now, io);
+        }
+    }
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+/// Main loop timing reported to the host
+pub struct LoopStats {
+    pub uptime_ms: u32,
+    /// Passes of the main loop since boot
+    pub loops: u32,
+    pub last_us: u32,
+    /// The longest pass in the last complete [STATS_WINDOW_MS] window
+    pub max_us: u32,
+    /// The mean pass time in the last complete [STATS_WINDOW_MS] window
+    pub average_us: u32,
+}
+
+/// Runs each [Task] in order, once per pass, and keeps the [LoopStats]
+pub struct Scheduler {
+    last_ticks: [u32; MAX_TASKS],
+    stats: LoopStats,
+    window_start: u32,
+    window_us: u32,
+    window_max_us: u32,
+    window_loops: u32,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        let now = clock::millis();
+        Self {
+            last_ticks: [now; MAX_TASKS],
+            stats: LoopStats::default(),
+            window_start: now,
+            window_us: 0,
+            window_max_us: 0,
+            window_loops: 0,
+        }
+    }
+    /// Runs a single pass over `tasks`. Tasks past [MAX_TASKS] are polled but never ticked.
+    /// Each pass kicks the watchdog, so a loop that stops passing resets a trial image, see [fault::TRIAL_WATCHDOG]
+    pub fn pass(&mut self, tasks: &mut [&mut dyn Task], io: &mut Io) {
+        fault::kick_watchdog();
+        let start = clock::micros();
+        io.stats = self.stats;
+        for (i, task) in tasks.iter_mut().enumerate() {
+            task.poll(io);
+            let period = match task.period_ms() {
+                Some(period) if i < MAX_TASKS => period,
+                _ => continue,
+            };
+            let now = clock::millis();
+            if now.wrapping_sub(self.last_ticks[i]) >= period {
+                self.last_ticks[i] = now;
+                task.tick(now, io);
+            }
+        }
+        self.record(clock::micros().wrapping_sub(start));
+    }
+    pub fn stats(&self) -> LoopStats {
+        self.stats
+    }
+    fn record(&mut self, pass_us: u32) {
+        let now = clock::millis();
+        self.window_us = self.window_us.saturating_add(pass_us);
+        self.window_max_us = self.window_max_us.max(pass_us);
+        self.window_loops += 1;
+        self.stats.uptime_ms = now;
+        self.stats.loops = self.stats.loops.wrapping_add(1);
+        self.stats.last_us = pass_us;
+        if now.wrapping_sub(self.window_start) >= STATS_WINDOW_MS {
+            self.stats.max_us = self.window_max_us;
+            self.stats.average_us = self.window_us / self.window_loops;
+            self.window_start = now;
+            self.window_us = 0;
+            self.window_max_us = 0;
+            self.window_loops = 0;
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
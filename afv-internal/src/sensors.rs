@@ -0,0 +1,170 @@
+use core::cell::Cell;
+
+use arduino_hal::{
+    pac::{ADC, EXINT},
+    port::{
+        mode::{Floating, Input, PullUp},
+        Pin,
+    },
+};
+use avr_device::interrupt::Mutex;
+
+use crate::clock;
+
+/// The window a [FlowMeter] counts pulses over before updating its flow rate
+pub const FLOW_WINDOW_MS: u32 = 1000;
+/// Each new ADC sample moves an [AnalogSensor]'s average by 1/2^AVERAGE_SHIFT of the difference
+const AVERAGE_SHIFT: u32 = 3;
+
+/// Pulses counted on INT0 (D2) and INT1 (D3)
+static PULSES: [Mutex<Cell<u32>>; 2] = [Mutex::new(Cell::new(0)), Mutex::new(Cell::new(0))];
+
+/// The ATmega328P's ADC, read one blocking conversion at a time against the AVcc reference
+pub struct Adc {
+    adc: ADC,
+}
+
+impl Adc {
+    pub fn new(adc: ADC) -> Adc {
+        adc.admux.write(|w| w.refs().avcc());
+        // 16MHz / 128 keeps the conversion clock inside the 50-200kHz needed for full resolution
+        adc.adcsra
+            .write(|w| w.aden().set_bit().adps().prescaler_128());
+        Adc { adc }
+    }
+    /// A 10 bit reading of ADC channel 0-7, takes about 104us
+    pub fn read(&mut self, channel: u8) -> u16 {
+        self.adc
+            .admux
+            .modify(|_, w| unsafe { w.mux().bits(channel & 0x07) });
+        self.adc.adcsra.modify(|_, w| w.adsc().set_bit());
+        while self.adc.adcsra.read().adsc().bit_is_set() {}
+        self.adc.adc.read().bits()
+    }
+}
+
+/// A straight line from raw ADC counts to the sensor's units
+#[derive(Clone, Copy)]
+pub struct AnalogCalibration {
+    /// The reading at zero, 102 for a 0.5V-4.5V transducer
+    pub zero_raw: u16,
+    /// The reading at full scale, 921 for a 0.5V-4.5V transducer
+    pub full_raw: u16,
+    pub full_scale: u16,
+}
+
+impl AnalogCalibration {
+    pub fn convert(&self, raw: u16) -> u16 {
+        let span = self.full_raw.saturating_sub(self.zero_raw).max(1) as u32;
+        let counts = raw.clamp(self.zero_raw, self.full_raw) - self.zero_raw;
+        (counts as u32 * self.full_scale as u32 / span) as u16
+    }
+}
+
+/// An analog sensor such as a pressure transducer, averaged over recent samples
+pub struct AnalogSensor {
+    channel: u8,
+    _pin: Pin<Input<Floating>>,
+    calibration: AnalogCalibration,
+    /// The raw average scaled up by 2^AVERAGE_SHIFT to keep its fraction
+    average: Option<u32>,
+}
+
+impl AnalogSensor {
+    /// `channel` is the ADC channel the pin is wired to, A0 is channel 0
+    pub fn new(channel: u8, pin: Pin<Input<Floating>>, calibration: AnalogCalibration) -> Self {
+        Self {
+            channel,
+            _pin: pin,
+            calibration,
+            average: None,
+        }
+    }
+    pub fn sample(&mut self, adc: &mut Adc) {
+        let raw = (adc.read(self.channel) as u32) << AVERAGE_SHIFT;
+        self.average = Some(match self.average {
+            Some(average) => average - (average >> AVERAGE_SHIFT) + (raw >> AVERAGE_SHIFT),
+            None => raw,
+        });
+    }
+    /// The averaged reading in the calibrated units, None until the first sample
+    pub fn value(&self) -> Option<u16> {
+        let raw = (self.average? >> AVERAGE_SHIFT) as u16;
+        Some(self.calibration.convert(raw))
+    }
+}
+
+/// A pulse output flow meter counted on one of the external interrupts, so only D2 or D3 can be used
+pub struct FlowMeter {
+    interrupt: usize,
+    _pin: Pin<Input<PullUp>>,
+    pulses_per_liter: u16,
+    window_start_ms: u32,
+    window_start_pulses: u32,
+    flow_ml_per_min: u16,
+}
+
+impl FlowMeter {
+    /// `interrupt` is 0 for D2 and 1 for D3
+    pub fn new(interrupt: usize, pin: Pin<Input<PullUp>>, pulses_per_liter: u16) -> Self {
+        let exint = unsafe { &*EXINT::ptr() };
+        avr_device::interrupt::free(|cs| PULSES[interrupt].borrow(cs).set(0));
+        // Count falling edges
+        match interrupt {
+            0 => exint.eicra.modify(|_, w| w.isc0().bits(0b10)),
+            _ => exint.eicra.modify(|_, w| w.isc1().bits(0b10)),
+        }
+        exint
+            .eimsk
+            .modify(|r, w| w.int().bits(r.int().bits() | 1 << interrupt));
+        Self {
+            interrupt,
+            _pin: pin,
+            pulses_per_liter: pulses_per_liter.max(1),
+            window_start_ms: clock::millis(),
+            window_start_pulses: 0,
+            flow_ml_per_min: 0,
+        }
+    }
+    /// Pulses counted since the meter was set up
+    pub fn pulses(&self) -> u32 {
+        avr_device::interrupt::free(|cs| PULSES[self.interrupt].borrow(cs).get())
+    }
+    pub fn volume_ml(&self, pulses: u32) -> u32 {
+        (pulses as u64 * 1000 / self.pulses_per_liter as u64) as u32
+    }
+    /// Recomputes the flow rate at the end of each [FLOW_WINDOW_MS] window
+    pub fn update(&mut self) {
+        let elapsed = clock::elapsed_ms(self.window_start_ms);
+        if elapsed < FLOW_WINDOW_MS {
+            return;
+        }
+        let pulses = self.pulses();
+        let ml = self.volume_ml(pulses.wrapping_sub(self.window_start_pulses));
+        self.flow_ml_per_min = (ml as u64 * 60_000 / elapsed as u64).min(u16::MAX as u64) as u16;
+        self.window_start_ms = clock::millis();
+        self.window_start_pulses = pulses;
+    }
+    /// The flow over the last complete window
+    pub fn flow_ml_per_min(&self) -> u16 {
+        self.flow_ml_per_min
+    }
+}
+
+#[cfg(target_arch = "avr")]
+#[avr_device::interrupt(atmega328p)]
+fn INT0() {
+    avr_device::interrupt::free(|cs| {
+        let counter = PULSES[0].borrow(cs);
+        counter.set(counter.get().wrapping_add(1));
+    })
+}
+
+#[cfg(target_arch = "avr")]
+#[avr_device::interrupt(atmega328p)]
+fn INT1() {
+    avr_device::interrupt::free(|cs| {
+        let counter = PULSES[1].borrow(cs);
+        counter.set(counter.get().wrapping_add(1));
+    })
+}
@@ -1,5 +1,14 @@
 use arduino_hal::{clock::MHz16, hal::usart::Usart0};
 use embedded_hal::digital::v2::OutputPin;
+
+use crate::{clock, config::StepperConfig, fault};
+
+/// The most step pin edges one [StepperOps::update] makes up for, a stepper further behind than this skips the rest
+/// of its backlog rather than rushing it
+pub const MAX_CATCH_UP_EDGES: u32 = 32;
+/// The shortest time between step pin edges made up in one [StepperOps::update], within the drivers' pulse width
+pub const MIN_EDGE_US: u32 = 2;
+
 pub enum StepperOpsError {
     AngleLimit,
 }
@@ -7,13 +16,21 @@ pub enum StepperOpsError {
 pub trait StepperOps {
     /// Should get the current angle
     fn current_step(&self) -> i32;
-    /// Will turn the stepper to the step index
+    /// Sets the step index the stepper moves to, returning the step it will end on.
+    /// The move is made by [StepperOps::update], this returns straight away
     fn to_step(
         &mut self,
         step: i32,
         exit_on_max_steps: bool,
         serial: &mut Usart0<MHz16>,
     ) -> Result<i32, StepperOpsError>;
+    /// Drives the step pin for the move in progress without blocking, returning whether the stepper is still moving.
+    /// Every edge that fell due since the last call is made, so the step rate does not depend on how often it is called
+    /// as long as that is within [MAX_CATCH_UP_EDGES] edges, such as from [Task::poll](crate::scheduler::Task::poll)
+    fn update(&mut self) -> bool;
+    /// Ends the move once the step in progress is finished
+    fn stop(&mut self);
+    /// Blocks until the stepper has been driven into its end stop and zeroed, so it is only used at startup
     fn home(&mut self, home_step: i32, serial: &mut Usart0<MHz16>);
 }
 
@@ -21,6 +38,14 @@ pub struct StepperMotor<StepPin: OutputPin, DirPin: OutputPin> {
     max_clockwise: i32,
     min_clockwise: i32,
     current_step: i32,
+    /// The step the move in progress ends on
+    target_step: i32,
+    /// The step pin edges left in the step in progress, two for each microstep
+    edges: u32,
+    /// The [clock::micros] value the last step pin edge was due at
+    last_edge_us: u32,
+    /// How long after [StepperMotor::last_edge_us] the next edge is due
+    wait_us: u32,
     step: StepPin,
     dir: DirPin,
     microsteps: Option<u32>,
@@ -55,6 +80,10 @@ impl<S: OutputPin, D: OutputPin> StepperMotor<S, D> {
             dir: dir_pin,
             microsteps,
             current_step: 0,
+            target_step: 0,
+            edges: 0,
+            last_edge_us: clock::micros(),
+            wait_us: 0,
             step_time_us,
             max_clockwise,
             min_clockwise,
@@ -62,6 +91,64 @@ impl<S: OutputPin, D: OutputPin> StepperMotor<S, D> {
             microstep_time,
         }
     }
+    pub fn from_config(step_pin: S, dir_pin: D, config: &StepperConfig) -> Self {
+        Self::new(
+            step_pin,
+            dir_pin,
+            config.max_clockwise,
+            config.min_clockwise,
+            config.microsteps,
+            config.step_time_us,
+            config.microstep_time,
+            config.inverted,
+        )
+    }
+}
+
+impl<S: OutputPin, D: OutputPin> StepperMotor<S, D> {
+    fn set_direction(&mut self, clockwise: bool) {
+        let _ = match clockwise != self.inverted {
+            true => self.dir.set_high(),
+            false => self.dir.set_low(),
+        };
+    }
+    fn moving(&self) -> bool {
+        self.edges != 0 || self.current_step != self.target_step
+    }
+    /// Moves towards the step, timing the first edge from now if the stepper was still
+    fn set_target(&mut self, step: i32) {
+        if !self.moving() {
+            self.last_edge_us = clock::micros();
+            self.wait_us = 0;
+        }
+        self.target_step = step;
+    }
+    /// Makes the next step pin edge, starting a step if none is in progress
+    fn edge(&mut self) {
+        if self.edges == 0 {
+            let clockwise = self.target_step > self.current_step;
+            self.set_direction(clockwise);
+            self.current_step += if clockwise { 1 } else { -1 };
+            self.edges = self.microsteps.unwrap_or(1) * 2;
+        }
+        // The pin rises on the first edge of each microstep and falls on the second
+        let _ = match self.edges % 2 == 0 {
+            true => self.step.set_high(),
+            false => self.step.set_low(),
+        };
+        self.edges -= 1;
+        self.wait_us = match self.edges {
+            0 => self.microstep_time + self.step_time_us,
+            _ => self.microstep_time,
+        };
+    }
+    /// Runs the move to completion, ignoring the step limits. Homing can outlast the watchdog, so it is kicked
+    fn run_to(&mut self, step: i32) {
+        self.set_target(step);
+        while self.update() {
+            fault::kick_watchdog();
+        }
+    }
 }
 
 impl<S: OutputPin, D: OutputPin> StepperOps for StepperMotor<S, D> {
@@ -81,57 +168,45 @@ impl<S: OutputPin, D: OutputPin> StepperOps for StepperMotor<S, D> {
             step,
             self.current_step()
         );
-        let steps = step - self.current_step();
-
-        self.current_step += steps;
-
-        if self.current_step > self.max_clockwise {
-            self.current_step = self.max_clockwise;
-            if exit_on_step_limit {
-                return Err(StepperOpsError::AngleLimit);
-            }
-        }
-        if self.current_step < self.min_clockwise {
-            self.current_step = self.min_clockwise;
-            if exit_on_step_limit {
-                return Err(StepperOpsError::AngleLimit);
-            }
+        let outside = step > self.max_clockwise || step < self.min_clockwise;
+        if outside && exit_on_step_limit {
+            return Err(StepperOpsError::AngleLimit);
         }
+        self.set_target(step.clamp(self.min_clockwise, self.max_clockwise));
+        Ok(self.target_step)
+    }
 
-        if steps.is_positive() {
-            if !self.inverted {
-                let _ = self.dir.set_high();
-            } else {
-                let _ = self.dir.set_low();
-            }
-        } else {
-            if !self.inverted {
-                let _ = self.dir.set_low();
-            } else {
-                let _ = self.dir.set_high();
+    fn update(&mut self) -> bool {
+        let now = clock::micros();
+        let mut made = 0;
+        while self.moving() {
+            if now.wrapping_sub(self.last_edge_us) < self.wait_us {
+                return true;
             }
-        }
-
-        for _ in 0..steps.abs() {
-            arduino_hal::delay_us(self.step_time_us);
-            let mut microsteps = 1;
-            if let Some(steps) = self.microsteps {
-                microsteps = steps;
+            if made == MAX_CATCH_UP_EDGES {
+                self.last_edge_us = now;
+                return true;
             }
-
-            for _ in 0..microsteps {
-                let _ = self.step.set_high();
-                arduino_hal::delay_us(self.microstep_time);
-                let _ = self.step.set_low();
-                arduino_hal::delay_us(self.microstep_time);
+            // Timed from when the edge was due rather than when it was made, so a slow pass is made up on the next
+            self.last_edge_us = self.last_edge_us.wrapping_add(self.wait_us);
+            if made > 0 {
+                arduino_hal::delay_us(MIN_EDGE_US);
             }
+            self.edge();
+            made += 1;
         }
+        false
+    }
 
-        Ok(self.current_step())
+    fn stop(&mut self) {
+        self.target_step = self.current_step;
     }
 
-    fn home(&mut self, home_step: i32, serial: &mut Usart0<MHz16>) {
-        let _ = self.to_step(home_step, false, serial);
-        let _ = self.to_step(0, false, serial);
+    fn home(&mut self, home_step: i32, _serial: &mut Usart0<MHz16>) {
+        // Drives out to the end stop at the home step, then back by the home step clamped to the limits, which is zero
+        self.current_step = 0;
+        self.run_to(home_step);
+        self.current_step = home_step.clamp(self.min_clockwise, self.max_clockwise);
+        self.run_to(0);
     }
 }
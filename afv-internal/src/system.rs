@@ -0,0 +1,133 @@
+use arduino_hal::{
+    clock::MHz16,
+    hal::{port::PB2, usart::Usart0},
+    spi::ChipSelectPin,
+    Spi,
+};
+
+use crate::{
+    config::{BoardConfig, ConfigMsg, ConfigStore},
+    fault,
+    network::InternalMessage,
+    scheduler::{Io, LoopStats, Task},
+    update::Updater,
+    w5500::{
+        socket_register::{self, Socket, SocketBlock},
+        W5500,
+    },
+    SYSTEM_PORT,
+};
+
+/// How often the loop stats are written to the serial port
+pub const HEARTBEAT_PERIOD_MS: u32 = 10_000;
+
+/// The board level service that every firmware image runs.
+/// It lets the host read, stage and commit the board's [BoardConfig], poll its [LoopStats] and update its firmware
+pub struct System {
+    socket: Socket,
+    store: ConfigStore,
+    updater: Updater,
+    active: BoardConfig,
+    staged: BoardConfig,
+    stats: LoopStats,
+}
+
+impl System {
+    /// `config` should be the configuration the board actually booted with
+    pub fn new(
+        socket_block: SocketBlock,
+        mut store: ConfigStore,
+        config: BoardConfig,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) -> Self {
+        let mode = socket_register::Mode::default().set_protocol_tcp();
+        let socket = W5500::socket_n(socket_block, mode, SYSTEM_PORT, spi, cs);
+        let _ = ufmt::uwriteln!(serial, "Created system using port {}", SYSTEM_PORT);
+        let updater = Updater::new(&mut store);
+        Self {
+            socket,
+            store,
+            updater,
+            active: config,
+            staged: config,
+            stats: LoopStats::default(),
+        }
+    }
+
+    pub fn process(
+        &mut self,
+        spi: &mut Spi,
+        cs: &mut ChipSelectPin<PB2>,
+        serial: &mut Usart0<MHz16>,
+    ) {
+        // The host acknowledged the fault report through the logger
+        if fault::take_clear_request() {
+            fault::clear(self.store.eeprom_mut());
+        }
+        self.updater.check_trial();
+        match self.socket.receive_connected(spi, cs, serial) {
+            Some(InternalMessage::Ping(val)) => {
+                self.socket.send(InternalMessage::Ping(val), spi, cs);
+            }
+            Some(InternalMessage::PollLoopStats) => {
+                self.socket
+                    .send(InternalMessage::LoopStats(self.stats), spi, cs);
+            }
+            Some(InternalMessage::Config(msg)) => {
+                if let Some(response) = self.config(msg, serial) {
+                    self.socket.send(InternalMessage::Config(response), spi, cs);
+                }
+            }
+            Some(InternalMessage::Update(msg)) => {
+                if let Some(response) = self.updater.process(msg, &mut self.store) {
+                    self.socket.send(InternalMessage::Update(response), spi, cs);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn config(&mut self, msg: ConfigMsg, serial: &mut Usart0<MHz16>) -> Option<ConfigMsg> {
+        match msg {
+            ConfigMsg::Read => Some(ConfigMsg::Current(self.active)),
+            ConfigMsg::ReadStaged => Some(ConfigMsg::Current(self.staged)),
+            ConfigMsg::Write(config) => {
+                let _ = ufmt::uwriteln!(serial, "Config staged");
+                self.staged = config;
+                Some(ConfigMsg::Current(self.staged))
+            }
+            ConfigMsg::Commit => {
+                let ok = self.store.store(&self.staged);
+                let _ = ufmt::uwriteln!(serial, "Config committed: {}", ok);
+                Some(ConfigMsg::Committed(ok))
+            }
+            ConfigMsg::Erase => {
+                let ok = self.store.erase();
+                let _ = ufmt::uwriteln!(serial, "Config erased: {}", ok);
+                Some(ConfigMsg::Committed(ok))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Task for System {
+    fn poll(&mut self, io: &mut Io) {
+        self.stats = io.stats;
+        self.process(io.spi, io.cs, io.serial);
+    }
+    fn period_ms(&self) -> Option<u32> {
+        Some(HEARTBEAT_PERIOD_MS)
+    }
+    fn tick(&mut self, now: u32, io: &mut Io) {
+        let _ = ufmt::uwriteln!(
+            io.serial,
+            "Up {}ms, loop avg {}us max {}us",
+            now,
+            self.stats.average_us,
+            self.stats.max_us
+        );
+    }
+}
@@ -8,7 +8,9 @@ use serde::{Deserialize, Serialize};
 use ufmt::derive::uDebug;
 
 use crate::{
+    estop,
     network::InternalMessage,
+    scheduler::{Io, Task},
     stepper::StepperOps,
     w5500::{
         socket_register::{self, Socket, SocketBlock},
@@ -73,6 +75,9 @@ impl<PS: StepperOps, TS: StepperOps> Turret<PS, TS> {
                             // let _ = ufmt::uwriteln!(serial, "Turret {} steps polled", self.port);
                             self.poll_steps(spi, cs, serial)
                         }
+                        TurretMsg::SetSteps(_) if estop::latched() => {
+                            crate::log_warn!("Turret {} move refused, E-stop latched", self.port);
+                        }
                         TurretMsg::SetSteps(msg) => {
                             let _ = ufmt::uwriteln!(serial, "Turret {} steps set", self.port);
                             self.set_steps(msg, serial);
@@ -80,6 +85,11 @@ impl<PS: StepperOps, TS: StepperOps> Turret<PS, TS> {
                         _ => {}
                     }
                 }
+                InternalMessage::EmergencyStop(msg) => {
+                    if let Some(reply) = estop::process(msg) {
+                        self.socket.send(reply, spi, cs);
+                    }
+                }
                 _ => {}
             }
         }
@@ -97,9 +107,23 @@ impl<PS: StepperOps, TS: StepperOps> Turret<PS, TS> {
         self.socket.send(msg, spi, cs);
         // let _ = ufmt::uwriteln!(serial, "Turret {} sent steps", self.port);
     }
+    /// Starts both steppers towards the steps, [Task::poll] moves them while the turret keeps answering
     fn set_steps(&mut self, steps: (i32, i32), serial: &mut Usart0<MHz16>) {
         let _ = self.pan_stepper.to_step(steps.0, true, serial);
         let _ = self.tilt_stepper.to_step(steps.1, true, serial);
         // let _ = ufmt::uwriteln!(serial, "Turret {} direction canged", self.port);
     }
 }
+
+impl<PS: StepperOps, TS: StepperOps> Task for Turret<PS, TS> {
+    fn poll(&mut self, io: &mut Io) {
+        self.process(io.spi, io.cs, io.serial);
+        // Checked between steps, so a stop latched through any service halts a move part way
+        if estop::latched() {
+            self.pan_stepper.stop();
+            self.tilt_stepper.stop();
+        }
+        self.pan_stepper.update();
+        self.tilt_stepper.update();
+    }
+}
@@ -0,0 +1,351 @@
+//! Images are staged by the running firmware and installed by the bootloader.
+//!
+//! The ATmega328p cannot rewrite the flash it is executing from, so the firmware only ever writes the staging area
+//! in the upper half of flash, through the `do_spm` entry point of the AFV bootloader (`src/bin/afv-bootloader.rs`).
+//! Boards still running optiboot have no such entry point, so staging is refused unless [BOOTLOADER_ID] is found at
+//! [BOOTLOADER_ID_ADDRESS]. The bootloader owns the swap and follows this contract on every reset, driven by the
+//! [UpdateRecord] at [UPDATE_ADDRESS] in the EEPROM:
+//!
+//! * [UpdateState::Staged]: Check the staged image against the record's length and crc. If it matches, swap the
+//!   application and staging areas page by page, so the previous image is kept in the staging area, and set
+//!   [UpdateState::Trial] before starting the application with the watchdog running at
+//!   [TRIAL_WATCHDOG](crate::fault::TRIAL_WATCHDOG). Otherwise set [UpdateState::Idle].
+//! * [UpdateState::Trial]: The trial image reset before the host confirmed it, whether it panicked, hung until the
+//!   watchdog expired or timed out. Swap the areas back and set [UpdateState::RolledBack].
+//! * Anything else: start the application.
+//!
+//! A trial image that is not confirmed within [TRIAL_TIMEOUT_MS] resets itself so the bootloader rolls it back.
+
+use serde::{Deserialize, Serialize};
+use ufmt::derive::uDebug;
+
+use crate::{
+    clock,
+    config::{crc16_update, ConfigStore},
+    eeprom::Eeprom,
+    fault,
+};
+
+/// The flash page size of the ATmega328p in bytes
+pub const PAGE_SIZE: usize = 128;
+/// Serde only supports arrays of up to 32 elements, so pages are sent as [PAGE_SLICES] slices
+pub const PAGE_SLICE_SIZE: usize = 32;
+pub const PAGE_SLICES: usize = PAGE_SIZE / PAGE_SLICE_SIZE;
+/// The byte address of the 1 KiB boot section the AFV bootloader is flashed to, selected by BOOTSZ = 10
+pub const BOOTLOADER_ADDRESS: u16 = 0x7c00;
+/// The byte address of the staging area, half of the flash below the bootloader.
+/// It is also the largest image that can be installed, and the running firmware has to end below it
+pub const STAGING_ADDRESS: u16 = BOOTLOADER_ADDRESS / 2;
+pub const IMAGE_CAPACITY: u16 = STAGING_ADDRESS;
+/// The byte address of the bootloader's `do_spm` entry point, the second word of the bootloader
+pub const DO_SPM_ADDRESS: u16 = BOOTLOADER_ADDRESS + 2;
+/// The byte address of the [BOOTLOADER_ID], the last four bytes of flash
+pub const BOOTLOADER_ID_ADDRESS: u16 = 0x7ffc;
+/// Identifies the AFV bootloader and the version of the contract above it follows
+pub const BOOTLOADER_ID: [u8; 4] = [b'A', b'F', b'B', 1];
+/// The EEPROM address of the [UpdateRecord], shared with the bootloader
+pub const UPDATE_ADDRESS: u16 = 768;
+/// How long a trial image has to be confirmed by the host before it rolls itself back
+pub const TRIAL_TIMEOUT_MS: u32 = 60_000;
+const UPDATE_MAGIC: [u8; 2] = [0x0b, 0x7e];
+const UPDATE_RECORD_SIZE: usize = 7;
+
+// SPMCSR commands, shared with the bootloader
+pub const SPM_FILL: u8 = 0x01;
+pub const SPM_ERASE: u8 = 0x03;
+pub const SPM_WRITE: u8 = 0x05;
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateState {
+    Idle = 0,
+    /// An image is being written to the staging area
+    Staging = 1,
+    /// A complete image is waiting for the bootloader to install it
+    Staged = 2,
+    /// The running image was just installed and has not been confirmed yet
+    Trial = 3,
+    /// The last installed image failed to report in and the previous image was restored
+    RolledBack = 4,
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateError {
+    TooLarge,
+    /// The page was not the next one expected
+    OutOfOrder,
+    BadCrc,
+    /// The page read back from flash did not match what was written
+    VerifyFailed,
+    /// The message is not valid in the current [UpdateState]
+    InvalidState,
+    /// The board is not running the AFV bootloader, so it can neither write nor install an image
+    NoBootloader,
+    /// The running firmware reaches into the staging area, staging would erase it
+    FirmwareTooLarge,
+}
+
+#[derive(uDebug, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum UpdateMsg {
+    /// Starts staging an image of `len` bytes whose [crate::config::crc16] is `crc`
+    Begin {
+        len: u16,
+        crc: u16,
+    },
+    /// One flash page of the image, `crc` covers `data`
+    Page {
+        page: u16,
+        data: [[u8; PAGE_SLICE_SIZE]; PAGE_SLICES],
+        crc: u16,
+    },
+    /// Marks the complete staged image for the bootloader and restarts the board
+    Install,
+    /// Accepts the running trial image
+    Confirm,
+    /// Requests the [UpdateState]
+    Status,
+    /// Response to [UpdateMsg::Page] once it is in flash
+    Ack(u16),
+    Error(UpdateError),
+    /// Response to [UpdateMsg::Begin], [UpdateMsg::Confirm] and [UpdateMsg::Status]
+    State(UpdateState),
+}
+
+#[derive(uDebug, Debug, Clone, Copy, PartialEq, Eq)]
+/// The EEPROM record shared with the bootloader
+pub struct UpdateRecord {
+    pub state: UpdateState,
+    pub len: u16,
+    pub crc: u16,
+}
+
+impl UpdateRecord {
+    pub fn load(eeprom: &Eeprom) -> UpdateRecord {
+        let mut record = [0u8; UPDATE_RECORD_SIZE];
+        let idle = UpdateRecord {
+            state: UpdateState::Idle,
+            len: 0,
+            crc: 0,
+        };
+        if eeprom.read(UPDATE_ADDRESS, &mut record).is_err() || record[..2] != UPDATE_MAGIC {
+            return idle;
+        }
+        let state = match record[2] {
+            1 => UpdateState::Staging,
+            2 => UpdateState::Staged,
+            3 => UpdateState::Trial,
+            4 => UpdateState::RolledBack,
+            _ => UpdateState::Idle,
+        };
+        UpdateRecord {
+            state,
+            len: u16::from_be_bytes([record[3], record[4]]),
+            crc: u16::from_be_bytes([record[5], record[6]]),
+        }
+    }
+    pub fn store(&self, eeprom: &mut Eeprom) {
+        let len = self.len.to_be_bytes();
+        let crc = self.crc.to_be_bytes();
+        let _ = eeprom.write(
+            UPDATE_ADDRESS,
+            &[
+                UPDATE_MAGIC[0],
+                UPDATE_MAGIC[1],
+                self.state as u8,
+                len[0],
+                len[1],
+                crc[0],
+                crc[1],
+            ],
+        );
+    }
+}
+
+/// Handles the [UpdateMsg]s the System service receives
+pub struct Updater {
+    record: UpdateRecord,
+    next_page: u16,
+    running_crc: u16,
+    booted_at: u32,
+}
+
+impl Updater {
+    pub fn new(store: &mut ConfigStore) -> Updater {
+        let mut record = UpdateRecord::load(store.eeprom_mut());
+        // Staging is interrupted by a reset, the partial image is useless
+        if record.state == UpdateState::Staging {
+            record.state = UpdateState::Idle;
+            record.store(store.eeprom_mut());
+        }
+        Self {
+            record,
+            next_page: 0,
+            running_crc: 0xffff,
+            booted_at: clock::millis(),
+        }
+    }
+    pub fn state(&self) -> UpdateState {
+        self.record.state
+    }
+    /// Resets the board, and so rolls back, once an unconfirmed trial image runs out of time
+    pub fn check_trial(&self) {
+        if self.record.state == UpdateState::Trial
+            && clock::elapsed_ms(self.booted_at) > TRIAL_TIMEOUT_MS
+        {
+            crate::log_error!("Update not confirmed, rolling back");
+            fault::watchdog_reset();
+        }
+    }
+    pub fn process(&mut self, msg: UpdateMsg, store: &mut ConfigStore) -> Option<UpdateMsg> {
+        match msg {
+            UpdateMsg::Status => Some(UpdateMsg::State(self.record.state)),
+            UpdateMsg::Begin { len, crc } => {
+                if self.record.state == UpdateState::Trial {
+                    return Some(UpdateMsg::Error(UpdateError::InvalidState));
+                }
+                if len == 0 || len > IMAGE_CAPACITY {
+                    return Some(UpdateMsg::Error(UpdateError::TooLarge));
+                }
+                // Checked before the first page so nothing jumps to a do_spm that is not there
+                if !bootloader_present() {
+                    return Some(UpdateMsg::Error(UpdateError::NoBootloader));
+                }
+                if firmware_end() >= STAGING_ADDRESS {
+                    return Some(UpdateMsg::Error(UpdateError::FirmwareTooLarge));
+                }
+                self.record = UpdateRecord {
+                    state: UpdateState::Staging,
+                    len,
+                    crc,
+                };
+                self.record.store(store.eeprom_mut());
+                self.next_page = 0;
+                self.running_crc = 0xffff;
+                crate::log_info!("Staging {} byte image", len);
+                Some(UpdateMsg::State(self.record.state))
+            }
+            UpdateMsg::Page { page, data, crc } => {
+                if self.record.state != UpdateState::Staging {
+                    return Some(UpdateMsg::Error(UpdateError::InvalidState));
+                }
+                if page != self.next_page {
+                    return Some(UpdateMsg::Error(UpdateError::OutOfOrder));
+                }
+                let mut bytes = [0u8; PAGE_SIZE];
+                for (i, slice) in data.iter().enumerate() {
+                    bytes[i * PAGE_SLICE_SIZE..(i + 1) * PAGE_SLICE_SIZE].copy_from_slice(slice);
+                }
+                if crate::config::crc16(&bytes) != crc {
+                    return Some(UpdateMsg::Error(UpdateError::BadCrc));
+                }
+                let address = STAGING_ADDRESS + page * PAGE_SIZE as u16;
+                if address as u32 + PAGE_SIZE as u32 > (STAGING_ADDRESS + IMAGE_CAPACITY) as u32 {
+                    return Some(UpdateMsg::Error(UpdateError::TooLarge));
+                }
+                write_page(address, &bytes);
+                if !verify_page(address, &bytes) {
+                    return Some(UpdateMsg::Error(UpdateError::VerifyFailed));
+                }
+                // Only the image's own bytes count towards its crc, not the padding of the last page
+                let image_bytes = (self.record.len as usize)
+                    .saturating_sub(page as usize * PAGE_SIZE)
+                    .min(PAGE_SIZE);
+                self.running_crc = crc16_update(self.running_crc, &bytes[..image_bytes]);
+                self.next_page += 1;
+                Some(UpdateMsg::Ack(page))
+            }
+            UpdateMsg::Install => {
+                let pages = (self.record.len as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+                if self.record.state != UpdateState::Staging || self.next_page as usize != pages {
+                    return Some(UpdateMsg::Error(UpdateError::InvalidState));
+                }
+                if self.running_crc != self.record.crc {
+                    self.record.state = UpdateState::Idle;
+                    self.record.store(store.eeprom_mut());
+                    return Some(UpdateMsg::Error(UpdateError::BadCrc));
+                }
+                self.record.state = UpdateState::Staged;
+                self.record.store(store.eeprom_mut());
+                crate::log_info!("Image staged, restarting");
+                fault::watchdog_reset();
+            }
+            UpdateMsg::Confirm => {
+                if self.record.state == UpdateState::Trial
+                    || self.record.state == UpdateState::RolledBack
+                {
+                    self.record.state = UpdateState::Idle;
+                    self.record.store(store.eeprom_mut());
+                }
+                Some(UpdateMsg::State(self.record.state))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether the AFV bootloader's [BOOTLOADER_ID] is at the end of flash
+fn bootloader_present() -> bool {
+    BOOTLOADER_ID
+        .iter()
+        .enumerate()
+        .all(|(i, byte)| read_flash(BOOTLOADER_ID_ADDRESS + i as u16) == *byte)
+}
+
+/// Writes one page of flash through the bootloader.
+/// The bootloader's `do_spm` waits for erases and writes and re-enables the RWW section itself, so the page can be read back straight away
+fn write_page(address: u16, data: &[u8; PAGE_SIZE]) {
+    avr_device::interrupt::free(|_| {
+        do_spm(address, SPM_ERASE, 0);
+        for i in (0..PAGE_SIZE).step_by(2) {
+            let word = u16::from_le_bytes([data[i], data[i + 1]]);
+            do_spm(address + i as u16, SPM_FILL, word);
+        }
+        do_spm(address, SPM_WRITE, 0);
+    });
+}
+
+fn verify_page(address: u16, data: &[u8; PAGE_SIZE]) -> bool {
+    data.iter()
+        .enumerate()
+        .all(|(i, byte)| read_flash(address + i as u16) == *byte)
+}
+
+#[cfg(target_arch = "avr")]
+fn do_spm(address: u16, command: u8, data: u16) {
+    // Function pointers on the AVR are word addresses
+    let do_spm: extern "C" fn(u16, u8, u16) =
+        unsafe { core::mem::transmute((DO_SPM_ADDRESS >> 1) as usize) };
+    do_spm(address, command, data);
+}
+
+#[cfg(target_arch = "avr")]
+pub fn read_flash(address: u16) -> u8 {
+    let byte: u8;
+    unsafe {
+        core::arch::asm!("lpm {0}, Z", out(reg) byte, in("Z") address);
+    }
+    byte
+}
+
+/// The byte address the running firmware's flash image ends at, its code followed by the initial values of .data
+#[cfg(target_arch = "avr")]
+fn firmware_end() -> u16 {
+    extern "C" {
+        // Defined by the linker script
+        static __data_load_end: u8;
+    }
+    unsafe { core::ptr::addr_of!(__data_load_end) as u16 }
+}
+
+// The host only needs the message types, it never touches flash
+#[cfg(not(target_arch = "avr"))]
+fn do_spm(_address: u16, _command: u8, _data: u16) {}
+
+#[cfg(not(target_arch = "avr"))]
+pub fn read_flash(_address: u16) -> u8 {
+    0xff
+}
+
+#[cfg(not(target_arch = "avr"))]
+fn firmware_end() -> u16 {
+    0
+}
@@ -84,6 +84,19 @@ impl SocketBlock{
     pub const SOCKET5: Self = Self{ctl: Bsb::SOCKET5, tx: Bsb::SOCKET5_TX, rx: Bsb::SOCKET5_RX};
     pub const SOCKET6: Self = Self{ctl: Bsb::SOCKET6, tx: Bsb::SOCKET6_TX, rx: Bsb::SOCKET6_RX};
     pub const SOCKET7: Self = Self{ctl: Bsb::SOCKET7, tx: Bsb::SOCKET7_TX, rx: Bsb::SOCKET7_RX};
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index{
+            0 => Some(Self::SOCKET0),
+            1 => Some(Self::SOCKET1),
+            2 => Some(Self::SOCKET2),
+            3 => Some(Self::SOCKET3),
+            4 => Some(Self::SOCKET4),
+            5 => Some(Self::SOCKET5),
+            6 => Some(Self::SOCKET6),
+            7 => Some(Self::SOCKET7),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, uDebug)]
@@ -310,6 +323,11 @@ impl Socket{
                 return false;
             },
             SocketStatus::Established => return true,
+            // The peer has hung up, disconnect so the socket closes and can listen again
+            SocketStatus::Close => {
+                self.write_cmd(Command::DISCONNECT, spi, cs);
+                return false;
+            },
             _ => return false
         }
     }
@@ -325,6 +343,10 @@ impl Socket{
     pub fn last_msg(&self) -> Option<InternalMessage> {
         self.last_msg.clone()
     }
+    /// Whether a peer was connected as of the last [Socket::receive_connected]
+    pub fn connected(&self) -> bool {
+        self.connected
+    }
     pub fn receive(&mut self, spi: &mut Spi, cs: &mut ChipSelectPin<PB2>, _serial: &mut Usart0<MHz16>) -> Option<InternalMessage>{
         if let SocketStatus::Closed = self.read_status(spi, cs){
             return None;
@@ -524,14 +546,14 @@ impl Socket{
         let mut msg = None;
         if self.server_connected(spi, cs, serial){
             if !self.connected{
-                // let _ = ufmt::uwriteln!(serial, "Socket {} connected", self.port);
+                crate::log_debug!("Socket {} connected", self.port);
                 self.connected = true;
             }
             msg = self.receive(spi, cs, serial);
         }
         else{
             if self.connected{
-                // let _ = ufmt::uwriteln!(serial, "Socket {} disconnected", self.port);
+                crate::log_debug!("Socket {} disconnected", self.port);
                 self.connected = false;
             }
         }
@@ -0,0 +1,138 @@
+use std::{net::IpAddr, path::PathBuf};
+
+use afv_internal::{
+    update::{UpdateError, UpdateMsg, UpdateState, TRIAL_TIMEOUT_MS},
+    FLIR_TURRET_PORT,
+};
+use clap::Parser;
+use gcs_afv::drivers::{firmware::FirmwareImage, system::SystemClient};
+use tokio::time::{sleep, Duration, Instant};
+
+/// How many times a page is resent when the board does not answer
+const PAGE_RETRIES: usize = 3;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Parser)]
+/// Pushes a firmware image to an AFV-INTERNAL board over the network.
+///
+/// The board stages the image and restarts into it through its bootloader. The new image is only kept once it
+/// reports back in, otherwise the board rolls back to the previous image on its own.
+struct UpdateArgs {
+    /// Find the board through the port of a service it runs
+    #[arg(short, long, default_value_t = FLIR_TURRET_PORT)]
+    port: u16,
+    /// Connect to the board at this address instead of scanning for it
+    #[arg(short, long)]
+    board: Option<IpAddr>,
+    /// The .elf or .hex file to install
+    image: PathBuf,
+}
+
+async fn stage(client: &SystemClient, image: &FirmwareImage) -> Result<(), String> {
+    match client
+        .begin_update(image.data().len() as u16, image.crc())
+        .await
+    {
+        Some(UpdateMsg::State(UpdateState::Staging)) => {}
+        Some(UpdateMsg::Error(e)) => return Err(format!("Board refused the image: {:?}", e)),
+        _ => return Err("Board did not start staging the image".into()),
+    }
+
+    let pages = image.pages();
+    for (page, data) in pages.iter().enumerate() {
+        let page = page as u16;
+        let mut attempt = 0;
+        loop {
+            match client.write_page(page, data).await {
+                Some(UpdateMsg::Ack(acked)) if acked == page => break,
+                // The page landed but its ack was lost
+                Some(UpdateMsg::Error(UpdateError::OutOfOrder)) if attempt > 0 => break,
+                Some(UpdateMsg::Error(e)) => {
+                    return Err(format!("Board rejected page {}: {:?}", page, e))
+                }
+                _ if attempt < PAGE_RETRIES => attempt += 1,
+                _ => return Err(format!("Board stopped answering at page {}", page)),
+            }
+        }
+        if page % 16 == 0 {
+            println!("Wrote page {}/{}", page + 1, pages.len());
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the board to come back up on the new image and confirms it
+async fn confirm(ip: IpAddr) -> Result<(), String> {
+    // Leave a margin so the confirmation lands before the board gives up on the image
+    let deadline = Instant::now() + Duration::from_millis(TRIAL_TIMEOUT_MS as u64 * 3 / 4);
+    while Instant::now() < deadline {
+        sleep(RECONNECT_INTERVAL).await;
+        let client = match SystemClient::connect(ip).await {
+            Some(c) => c,
+            None => continue,
+        };
+        return match client.update_state().await {
+            Some(UpdateState::Trial) => match client.confirm_update().await {
+                Some(UpdateState::Idle) => Ok(()),
+                _ => Err("Board did not accept the confirmation, it will roll back".into()),
+            },
+            Some(UpdateState::RolledBack) => {
+                let _ = client.confirm_update().await;
+                Err("The new image failed and the board rolled back".into())
+            }
+            Some(state) => Err(format!(
+                "Board restarted without installing the image ({:?}), check that its bootloader supports updates",
+                state
+            )),
+            None => continue,
+        };
+    }
+    Err("Board did not report in on the new image, it will roll back".into())
+}
+
+async fn run(args: UpdateArgs) -> Result<(), String> {
+    let image = FirmwareImage::from_file(&args.image)?;
+    println!(
+        "Loaded {} byte image with crc {:#06x}",
+        image.data().len(),
+        image.crc()
+    );
+
+    let client = match args.board {
+        Some(ip) => SystemClient::connect(ip).await,
+        None => {
+            println!("Searching for the board running port {}", args.port);
+            SystemClient::find(args.port).await
+        }
+    }
+    .ok_or("Could not connect to the board's system service")?;
+    let ip = client.ip();
+
+    stage(&client, &image).await?;
+    match client.install_update().await {
+        None => {}
+        Some(UpdateMsg::Error(e)) => {
+            return Err(format!("Board refused to install the image: {:?}", e))
+        }
+        Some(msg) => return Err(format!("Unexpected response to install: {:?}", msg)),
+    }
+    drop(client);
+    println!("Image staged, waiting for board {} to restart", ip);
+
+    confirm(ip).await?;
+    println!("Board {} is running the new image", ip);
+    Ok(())
+}
+
+fn main() {
+    pretty_env_logger::init();
+    let args = UpdateArgs::parse();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Could not build tokio runtime");
+    if let Err(e) = runtime.block_on(run(args)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
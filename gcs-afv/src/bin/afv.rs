@@ -1,15 +1,76 @@
+use std::{net::SocketAddr, path::PathBuf};
+
 use clap::Parser;
-use gcs_afv::operators::afv_launcher;
+use gcs_afv::{config::Config, operators::afv_launcher};
+use tokio::signal::ctrl_c;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 #[derive(Parser)]
 struct AfvArgs{
     #[arg(short, long)]
     server: bool,
+    /// The TOML configuration file, afv.toml in the working directory is used if it exists
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Connects a driver straight to its MCU instead of scanning for it, as DRIVER=IP:PORT such as pump=192.168.4.20:3034.
+    /// Overrides the [devices] section of the configuration file and can be repeated
+    #[arg(short, long = "device", value_parser = parse_device)]
+    devices: Vec<(String, SocketAddr)>,
+    /// Records the bus and the FLIR's IR video to this file, overriding the [recording] section of the configuration file
+    #[arg(short, long = "record")]
+    record: Option<PathBuf>,
+}
+
+fn parse_device(arg: &str) -> Result<(String, SocketAddr), String>{
+    let (driver, endpoint) = arg.split_once('=').ok_or("expected DRIVER=IP:PORT")?;
+    let endpoint = endpoint.parse().map_err(|e| format!("invalid endpoint {}: {}", endpoint, e))?;
+    Ok((driver.into(), endpoint))
 }
 
 fn main(){
     pretty_env_logger::init();
     let args = AfvArgs::parse();
+    let mut config = match Config::load(args.config.as_deref()){
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    config.network.server |= args.server;
+    if args.record.is_some(){
+        config.recording.path = args.record;
+    }
+    for (driver, endpoint) in args.devices{
+        if let Err(e) = config.devices.set(&driver, endpoint){
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = config.validate(){
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    }
     let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().expect("Could not build tokio runtime");
-    runtime.block_on(afv_launcher::launch(!args.server, None));
+    runtime.block_on(async{
+        let afv = afv_launcher::launch(config).await;
+        shutdown_signal().await;
+        // The pump, lights and siren are turned off before the drivers let go of them
+        afv.stop().await;
+    });
+}
+
+/// Completes on Ctrl-C, or the SIGTERM systemd stops the service with
+async fn shutdown_signal(){
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+        tokio::select!{
+            _ = ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = ctrl_c().await;
 }
\ No newline at end of file
@@ -0,0 +1,463 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use afv_internal::{
+    board,
+    config::{
+        BoardConfig, FlowMeterConfig, LidarConfig, LidarSensorConfig, OutputConfig,
+        PressureSensorConfig, PumpConfig, StepperConfig, TurretConfig,
+    },
+    lidar::MAX_LIDARS,
+    FLIR_TURRET_PORT,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use gcs_afv::drivers::system::SystemClient;
+
+#[derive(Parser)]
+/// Reads and updates the configuration stored in an AFV-INTERNAL board's EEPROM
+struct BoardConfigArgs {
+    /// Find the board through the port of a service it runs
+    #[arg(short, long, default_value_t = FLIR_TURRET_PORT)]
+    port: u16,
+    /// Connect to the board at this address instead of scanning for it
+    #[arg(short, long)]
+    board: Option<IpAddr>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the configuration the board is running, and the one staged for its next start if that differs
+    Show,
+    /// Print the timing of the board's main loop
+    Stats,
+    /// Change the board's network settings
+    SetNetwork {
+        #[arg(long)]
+        ip: Option<Ipv4Addr>,
+        #[arg(long)]
+        gateway: Option<Ipv4Addr>,
+        #[arg(long)]
+        subnet: Option<Ipv4Addr>,
+        /// Formatted as 00:08:dc:01:02:03
+        #[arg(long)]
+        mac: Option<String>,
+    },
+    /// Run a turret on the given socket block, starting from the main board's wiring if it was disabled
+    SetTurret { turret: TurretSelect, socket: u8 },
+    /// Change the wiring or calibration of one of the turret steppers
+    SetStepper {
+        turret: TurretSelect,
+        axis: AxisSelect,
+        #[arg(long)]
+        step_pin: Option<u8>,
+        #[arg(long)]
+        dir_pin: Option<u8>,
+        #[arg(long, allow_hyphen_values = true)]
+        max: Option<i32>,
+        #[arg(long, allow_hyphen_values = true)]
+        min: Option<i32>,
+        /// Zero disables microstepping
+        #[arg(long)]
+        microsteps: Option<u32>,
+        #[arg(long)]
+        step_time_us: Option<u32>,
+        #[arg(long)]
+        microstep_time: Option<u32>,
+        #[arg(long)]
+        inverted: Option<bool>,
+        #[arg(long, allow_hyphen_values = true)]
+        home: Option<i32>,
+    },
+    /// Run the lidar on the given socket block, starting from the main board's sensors if it was disabled
+    SetLidar { socket: u8 },
+    /// Add or rewire one of the lidar's sensors
+    SetLidarSensor {
+        sensor: u8,
+        /// The 7 bit I2C address the sensor is moved to at boot
+        #[arg(long, value_parser = parse_address)]
+        address: u8,
+        /// Leave out for a sensor that is always powered, at most one sensor may do so
+        #[arg(long)]
+        enable_pin: Option<u8>,
+    },
+    /// Stop polling one of the lidar's sensors
+    RemoveLidarSensor { sensor: u8 },
+    /// Run the pump, lights or siren on the given socket block and pin
+    SetOutput {
+        output: OutputSelect,
+        socket: u8,
+        pin: u8,
+    },
+    /// Set the pump's measured flow rate, used to time volume runs and estimate the water dispensed
+    SetPumpFlow { ml_per_min: u16 },
+    /// Read a pressure transducer on an analog pin, shutting the pump off when it runs under the minimum
+    SetPressureSensor {
+        /// One of A0-A3, as pin 14-17
+        pin: u8,
+        /// The ADC reading at 0 kPa, 102 for a 0.5V-4.5V transducer
+        #[arg(long, default_value_t = 102)]
+        zero_raw: u16,
+        /// The ADC reading at full scale, 921 for a 0.5V-4.5V transducer
+        #[arg(long, default_value_t = 921)]
+        full_raw: u16,
+        #[arg(long)]
+        full_scale_kpa: u16,
+        #[arg(long, default_value_t = 0)]
+        min_kpa: u16,
+    },
+    /// Count a flow meter's pulses on D2 or D3, shutting the pump off when the flow runs under the minimum
+    SetFlowMeter {
+        pin: u8,
+        #[arg(long)]
+        pulses_per_liter: u16,
+        #[arg(long, default_value_t = 0)]
+        min_ml_per_min: u16,
+    },
+    /// Stop reading the pump's pressure sensor or flow meter
+    RemovePumpSensors,
+    /// Stop running a service on the board
+    Disable { service: ServiceSelect },
+    /// Erase the stored configuration so the board uses its firmware defaults
+    Erase,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TurretSelect {
+    Flir,
+    Nozzle,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum AxisSelect {
+    Pan,
+    Tilt,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputSelect {
+    Pump,
+    Lights,
+    Siren,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ServiceSelect {
+    FlirTurret,
+    NozzleTurret,
+    Lidar,
+    Pump,
+    Lights,
+    Siren,
+}
+
+fn pump_config(config: &mut BoardConfig) -> Result<&mut PumpConfig, String> {
+    config
+        .pump
+        .as_mut()
+        .ok_or_else(|| "The pump is disabled on this board, enable it with set-output first".into())
+}
+
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let mut data = [0u8; 6];
+    let mut octets = mac.split(':');
+    for byte in data.iter_mut() {
+        *byte = u8::from_str_radix(octets.next()?, 16).ok()?;
+    }
+    if octets.next().is_some() {
+        return None;
+    }
+    Some(data)
+}
+
+/// Accepts addresses as decimal or 0x prefixed hex
+fn parse_address(address: &str) -> Result<u8, String> {
+    let parsed = match address.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => address.parse(),
+    };
+    match parsed {
+        Ok(address) if (0x08..0x78).contains(&address) => Ok(address),
+        _ => Err(format!("{} is not a 7 bit I2C address", address)),
+    }
+}
+
+fn lidar_sensor(
+    config: &mut BoardConfig,
+    sensor: u8,
+) -> Result<&mut Option<LidarSensorConfig>, String> {
+    let lidar = config
+        .lidar
+        .as_mut()
+        .ok_or("The lidar is disabled on this board, enable it with set-lidar first")?;
+    lidar
+        .sensors
+        .get_mut(sensor as usize)
+        .ok_or(format!("The lidar supports at most {} sensors", MAX_LIDARS))
+}
+
+fn turret(config: &mut BoardConfig, turret: TurretSelect) -> &mut Option<TurretConfig> {
+    match turret {
+        TurretSelect::Flir => &mut config.flir_turret,
+        TurretSelect::Nozzle => &mut config.nozzle_turret,
+    }
+}
+
+fn stepper(
+    config: &mut BoardConfig,
+    turret_select: TurretSelect,
+    axis: AxisSelect,
+) -> Option<&mut StepperConfig> {
+    let turret = turret(config, turret_select).as_mut()?;
+    match axis {
+        AxisSelect::Pan => Some(&mut turret.pan),
+        AxisSelect::Tilt => Some(&mut turret.tilt),
+    }
+}
+
+async fn run(args: BoardConfigArgs) -> Result<(), String> {
+    let client = match args.board {
+        Some(ip) => SystemClient::connect(ip).await,
+        None => {
+            println!("Searching for the board running port {}", args.port);
+            SystemClient::find(args.port).await
+        }
+    }
+    .ok_or("Could not connect to the board's system service")?;
+
+    // Changes build on whatever is staged, so several commands can be run before the board is restarted
+    let mut config = client
+        .read_staged_config()
+        .await
+        .ok_or("Board did not report its configuration")?;
+
+    match args.command {
+        Command::Show => {
+            let active = client
+                .read_config()
+                .await
+                .ok_or("Board did not report its configuration")?;
+            println!("Board {}: {:#?}", client.ip(), active);
+            if config != active {
+                println!("Staged until the board restarts: {:#?}", config);
+            }
+            return Ok(());
+        }
+        Command::Stats => {
+            let stats = client
+                .loop_stats()
+                .await
+                .ok_or("Board did not report its loop stats")?;
+            println!("Board {}: {:#?}", client.ip(), stats);
+            return Ok(());
+        }
+        Command::Erase => {
+            if !client.erase_config().await {
+                return Err("Board failed to erase its configuration".into());
+            }
+            println!("Configuration erased, restart the board to use its defaults");
+            return Ok(());
+        }
+        Command::SetNetwork {
+            ip,
+            gateway,
+            subnet,
+            mac,
+        } => {
+            if let Some(ip) = ip {
+                config.network.ip = ip.octets();
+            }
+            if let Some(gateway) = gateway {
+                config.network.gateway = gateway.octets();
+            }
+            if let Some(subnet) = subnet {
+                config.network.subnet = subnet.octets();
+            }
+            if let Some(mac) = mac {
+                config.network.mac = parse_mac(&mac).ok_or(format!("Invalid mac {}", mac))?;
+            }
+        }
+        Command::SetTurret {
+            turret: turret_select,
+            socket,
+        } => {
+            let default = match turret_select {
+                TurretSelect::Flir => board::FLIR_BOARD.flir_turret,
+                TurretSelect::Nozzle => board::FLIR_BOARD.nozzle_turret,
+            };
+            let turret = turret(&mut config, turret_select);
+            *turret = turret.or(default).map(|t| TurretConfig { socket, ..t });
+        }
+        Command::SetLidar { socket } => {
+            config.lidar = config
+                .lidar
+                .or(board::FLIR_BOARD.lidar)
+                .map(|l| LidarConfig { socket, ..l });
+        }
+        Command::SetLidarSensor {
+            sensor,
+            address,
+            enable_pin,
+        } => {
+            *lidar_sensor(&mut config, sensor)? = Some(LidarSensorConfig {
+                address,
+                enable_pin,
+            });
+            let sensors = config.lidar.map(|l| l.sensors).unwrap_or_default();
+            let always_on = sensors
+                .iter()
+                .flatten()
+                .filter(|s| s.enable_pin.is_none())
+                .count();
+            if always_on > 1 {
+                return Err("Only one lidar sensor may be left without an enable pin".into());
+            }
+            let mut addresses: Vec<u8> = sensors.iter().flatten().map(|s| s.address).collect();
+            addresses.sort();
+            addresses.dedup();
+            if addresses.len() != sensors.iter().flatten().count() {
+                return Err("Each lidar sensor needs its own address".into());
+            }
+        }
+        Command::RemoveLidarSensor { sensor } => {
+            *lidar_sensor(&mut config, sensor)? = None;
+        }
+        Command::SetOutput {
+            output: output_select,
+            socket,
+            pin,
+        } => {
+            let output = OutputConfig { socket, pin };
+            match output_select {
+                OutputSelect::Pump => {
+                    config.pump = config
+                        .pump
+                        .or(board::PERIPHERAL_BOARD.pump)
+                        .map(|p| PumpConfig { output, ..p });
+                }
+                OutputSelect::Lights => config.lights = Some(output),
+                OutputSelect::Siren => config.siren = Some(output),
+            }
+        }
+        Command::SetPumpFlow { ml_per_min } => {
+            pump_config(&mut config)?.flow_ml_per_min = ml_per_min;
+        }
+        Command::SetPressureSensor {
+            pin,
+            zero_raw,
+            full_raw,
+            full_scale_kpa,
+            min_kpa,
+        } => {
+            if !(board::A0..=board::A3).contains(&pin) {
+                return Err("The pressure sensor must be on one of A0-A3".into());
+            }
+            if full_raw <= zero_raw {
+                return Err("The full scale reading must be above the zero reading".into());
+            }
+            pump_config(&mut config)?.pressure = Some(PressureSensorConfig {
+                pin,
+                zero_raw,
+                full_raw,
+                full_scale_kpa,
+                min_kpa,
+            });
+        }
+        Command::SetFlowMeter {
+            pin,
+            pulses_per_liter,
+            min_ml_per_min,
+        } => {
+            if pin != board::D2 && pin != board::D3 {
+                return Err("The flow meter must be on D2 or D3".into());
+            }
+            pump_config(&mut config)?.flow_meter = Some(FlowMeterConfig {
+                pin,
+                pulses_per_liter,
+                min_ml_per_min,
+            });
+        }
+        Command::RemovePumpSensors => {
+            let pump = pump_config(&mut config)?;
+            pump.pressure = None;
+            pump.flow_meter = None;
+        }
+        Command::Disable { service } => match service {
+            ServiceSelect::FlirTurret => config.flir_turret = None,
+            ServiceSelect::NozzleTurret => config.nozzle_turret = None,
+            ServiceSelect::Lidar => config.lidar = None,
+            ServiceSelect::Pump => config.pump = None,
+            ServiceSelect::Lights => config.lights = None,
+            ServiceSelect::Siren => config.siren = None,
+        },
+        Command::SetStepper {
+            turret,
+            axis,
+            step_pin,
+            dir_pin,
+            max,
+            min,
+            microsteps,
+            step_time_us,
+            microstep_time,
+            inverted,
+            home,
+        } => {
+            let stepper = stepper(&mut config, turret, axis)
+                .ok_or("The turret is disabled on this board, enable it with set-turret first")?;
+            if let Some(step_pin) = step_pin {
+                stepper.step_pin = step_pin;
+            }
+            if let Some(dir_pin) = dir_pin {
+                stepper.dir_pin = dir_pin;
+            }
+            if let Some(max) = max {
+                stepper.max_clockwise = max;
+            }
+            if let Some(min) = min {
+                stepper.min_clockwise = min;
+            }
+            if let Some(microsteps) = microsteps {
+                stepper.microsteps = Some(microsteps).filter(|m| *m > 0);
+            }
+            if let Some(step_time_us) = step_time_us {
+                stepper.step_time_us = step_time_us;
+            }
+            if let Some(microstep_time) = microstep_time {
+                stepper.microstep_time = microstep_time;
+            }
+            if let Some(inverted) = inverted {
+                stepper.inverted = inverted;
+            }
+            if let Some(home) = home {
+                stepper.home_step = home;
+            }
+            if stepper.min_clockwise > stepper.max_clockwise {
+                return Err("The stepper's min must not be larger than its max".into());
+            }
+        }
+    }
+
+    match client.write_config(config).await {
+        Some(staged) if staged == config => {}
+        _ => return Err("Board did not stage the new configuration".into()),
+    }
+    if !client.commit_config().await {
+        return Err("Board failed to commit the new configuration".into());
+    }
+    println!("Configuration committed, restart the board for it to take effect");
+    Ok(())
+}
+
+fn main() {
+    pretty_env_logger::init();
+    let args = BoardConfigArgs::parse();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Could not build tokio runtime");
+    if let Err(e) = runtime.block_on(run(args)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,47 @@
+use std::net::Ipv4Addr;
+
+use clap::Parser;
+use gcs_afv::emulator::{Emulator, LidarModel};
+use tokio::time::{sleep, Duration};
+
+#[derive(Parser)]
+/// Emulates the AFV-INTERNAL boards on their real service ports so the AFV can run without hardware
+struct EmulatorArgs {
+    /// The address the emulated services listen on
+    #[arg(short, long, default_value_t = Ipv4Addr::UNSPECIFIED)]
+    bind: Ipv4Addr,
+    /// The distance the lidar reads on average
+    #[arg(long, default_value_t = 500)]
+    lidar_cm: u32,
+    /// How far the lidar reading swings either side of its average
+    #[arg(long, default_value_t = 100)]
+    lidar_swing_cm: u32,
+    /// Move the turrets instantly instead of at the speed of the real steppers
+    #[arg(long)]
+    instant: bool,
+}
+
+fn main() {
+    pretty_env_logger::init();
+    let args = EmulatorArgs::parse();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Could not build tokio runtime");
+    runtime.block_on(async {
+        let emulator = Emulator::new(LidarModel {
+            base_cm: args.lidar_cm,
+            amplitude_cm: args.lidar_swing_cm,
+            ..Default::default()
+        })
+        .bind(args.bind)
+        .motion_timing(!args.instant);
+        if let Err(e) = emulator.start().await {
+            eprintln!("Could not start the emulator: {}", e);
+            std::process::exit(1);
+        }
+        loop {
+            sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
@@ -0,0 +1,139 @@
+use std::net::IpAddr;
+
+use afv_internal::{
+    network::InternalMessage,
+    pump::{PumpMsg, PumpStatus},
+    PUMP_PORT, SOCKET_MSG_SIZE,
+};
+use clap::Parser;
+use gcs_afv::network::{
+    scanner::{ScanBuilder, ScanCount},
+    socket::Socket,
+};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout, Duration},
+};
+
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Parser)]
+/// Runs the pump for a set time or volume. The run is timed on the board, so it ends even if this is stopped
+struct PumpRunArgs {
+    /// Connect to the board at this address instead of scanning for it
+    #[arg(short, long)]
+    board: Option<IpAddr>,
+    /// Seconds to wait before starting the pump
+    #[arg(long, default_value_t = 0)]
+    delay: u64,
+    /// Run for this many seconds
+    #[arg(long, conflicts_with = "ml", required_unless_present = "ml")]
+    seconds: Option<u32>,
+    /// Run until this many ml are dispensed, measured by the board's flow meter or estimated from its flow rate
+    #[arg(long)]
+    ml: Option<u32>,
+    /// Turn the pump off first, clearing a dry run the board latched so it will run again
+    #[arg(long)]
+    clear_dry_run: bool,
+}
+
+async fn send(socket: &Socket, msg: PumpMsg) {
+    if let Some(data) = InternalMessage::Pump(msg).to_msg() {
+        if let Err(e) = socket.write_data(&data).await {
+            eprintln!("Could not send to the pump: {}", e);
+        }
+    }
+}
+
+async fn read_status(socket: &Socket) -> PumpStatus {
+    loop {
+        let mut data = [0u8; SOCKET_MSG_SIZE];
+        for i in 0..data.len() {
+            data[i] = socket.read_byte().await;
+        }
+        if let Some(InternalMessage::Pump(PumpMsg::Status(status))) =
+            InternalMessage::from_msg(&data)
+        {
+            return status;
+        }
+    }
+}
+
+async fn run(args: PumpRunArgs) -> Result<(), String> {
+    let stream = match args.board {
+        Some(ip) => TcpStream::connect((ip, PUMP_PORT))
+            .await
+            .map_err(|e| format!("Could not connect to the pump: {}", e))?,
+        None => {
+            println!("Searching for the pump on port {}", PUMP_PORT);
+            ScanBuilder::default()
+                .scan_count(ScanCount::Infinite)
+                .add_port(PUMP_PORT)
+                .dispatch()
+                .recv_async()
+                .await
+                .map_err(|_| "Could not find the pump")?
+        }
+    };
+    let socket = Socket::new(stream, false);
+
+    if args.clear_dry_run {
+        send(&socket, PumpMsg::TurnOff).await;
+    }
+    if args.delay > 0 {
+        println!("Starting the pump in {} s", args.delay);
+        sleep(Duration::from_secs(args.delay)).await;
+    }
+    match (args.seconds, args.ml) {
+        (Some(seconds), _) => send(&socket, PumpMsg::RunFor(seconds.saturating_mul(1000))).await,
+        (None, Some(ml)) => send(&socket, PumpMsg::RunVolume(ml)).await,
+        (None, None) => unreachable!("clap requires one of seconds or ml"),
+    }
+
+    loop {
+        sleep(STATUS_INTERVAL).await;
+        send(&socket, PumpMsg::PollStatus).await;
+        let status = match timeout(STATUS_INTERVAL * 5, read_status(&socket)).await {
+            Ok(status) => status,
+            Err(_) => return Err("The pump stopped answering".into()),
+        };
+        let volume = match status.measured_volume_ml {
+            Some(ml) => format!("{} ml measured", ml),
+            None => format!("about {} ml", status.run_volume_ml),
+        };
+        match status.pressure_kpa {
+            Some(kpa) => println!(
+                "{:.1} s, {}, {} kPa",
+                status.run_ms as f32 / 1000.0,
+                volume,
+                kpa
+            ),
+            None => println!("{:.1} s, {}", status.run_ms as f32 / 1000.0, volume),
+        }
+        if status.dry_run {
+            return Err(
+                "The pump was shut off for running dry, run again with --clear-dry-run once it has water".into(),
+            );
+        }
+        if !status.running {
+            println!(
+                "Pump stopped, {} ml dispensed since the board started",
+                status.total_volume_ml
+            );
+            return Ok(());
+        }
+    }
+}
+
+fn main() {
+    pretty_env_logger::init();
+    let args = PumpRunArgs::parse();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Could not build tokio runtime");
+    if let Err(e) = runtime.block_on(run(args)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod afv;
 pub mod naming;
 pub mod flir;
+pub mod emergency_stop;
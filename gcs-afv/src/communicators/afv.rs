@@ -3,14 +3,15 @@ use std::sync::Arc;
 use eframe::egui::Ui;
 use tokio::{sync::{broadcast, Mutex}, runtime::Handle};
 
-use crate::{network::{socket::Socket, afv_bridge::AfvBridge, NetMessage, scanner::ScanCount}, ui::Renderable};
+use crate::{network::{socket::Socket, afv_bridge::AfvBridge, NetMessage, scanner::ScanCount}, shutdown::Shutdown, ui::Renderable};
 
-use super::{naming::NamingSystemCommunicator, flir::FlirSystemCommunicator};
+use super::{naming::NamingSystemCommunicator, flir::FlirSystemCommunicator, emergency_stop::EmergencyStopCommunicator};
 
 pub struct AfvCommuncation{
     handle: Handle,
     tx: broadcast::Sender<NetMessage>,
     naming_system: NamingSystemCommunicator,
+    emergency_stop: EmergencyStopCommunicator,
     flir_system: Option<FlirSystemCommunicator>,
 }
 
@@ -27,12 +28,14 @@ impl AfvCommuncation{
     }
     async fn start_communication(socket: Socket) -> Self{
         let (tx,_rx) = broadcast::channel(10000);
-        AfvBridge::start_communication(tx.clone(), socket);
+        // The GCS keeps its link to each AFV for as long as it runs
+        AfvBridge::start_communication(tx.clone(), socket, &Shutdown::new());
         
 
         Self{
             tx: tx.clone(),
             naming_system: NamingSystemCommunicator::new(tx.clone()).await,
+            emergency_stop: EmergencyStopCommunicator::new(tx.clone()),
             flir_system: Default::default(),
             handle: Handle::current(),
         }
@@ -41,6 +44,7 @@ impl AfvCommuncation{
 
 impl Renderable for AfvCommuncation{
     fn render(&mut self, ui: &mut Ui) {
+        self.emergency_stop.render(ui);
         let flir_system = self.flir_system.get_or_insert_with(||{self.handle.block_on(FlirSystemCommunicator::new(self.tx.clone(), ui))});
         flir_system.render(ui);
     }
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use eframe::{
+    egui::{Button, RichText, Ui},
+    epaint::Color32,
+};
+use tokio::sync::{broadcast, watch};
+
+use crate::{drivers::estop::EmergencyStopMessage, network::NetMessage, ui::Renderable};
+
+/// What the E-stop is recorded as triggered by when it is pressed in the Control Station
+pub const GCS_ESTOP_SOURCE: &str = "Control Station";
+
+#[derive(Clone)]
+/// Shows the AFV's E-stop above everything else, with the button to latch it and, once latched, to reset it
+pub struct EmergencyStopCommunicator {
+    net_tx: broadcast::Sender<NetMessage>,
+    latched_watch: Arc<watch::Sender<Option<bool>>>,
+    latched_receiver: watch::Receiver<Option<bool>>,
+}
+
+impl EmergencyStopCommunicator {
+    pub fn new(net_tx: broadcast::Sender<NetMessage>) -> Self {
+        let (latched_watch, latched_receiver) = watch::channel(None);
+        let comm = Self {
+            net_tx,
+            latched_watch: Arc::new(latched_watch),
+            latched_receiver,
+        };
+
+        tokio::spawn(comm.clone().status_task());
+
+        comm
+    }
+    async fn status_task(self) {
+        let mut net_rx = self.net_tx.subscribe();
+
+        loop {
+            let latched = match net_rx.recv().await {
+                Ok(NetMessage::EmergencyStop(EmergencyStopMessage::Stop(_))) => true,
+                Ok(NetMessage::EmergencyStop(EmergencyStopMessage::Reset)) => false,
+                Ok(NetMessage::EmergencyStop(EmergencyStopMessage::Status(latched))) => latched,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let _ = self.latched_watch.send(Some(latched));
+        }
+    }
+}
+
+impl Renderable for EmergencyStopCommunicator {
+    fn render(&mut self, ui: &mut Ui) {
+        let latched = *self.latched_receiver.borrow();
+        if latched == Some(true) {
+            ui.label(
+                RichText::new("EMERGENCY STOP LATCHED")
+                    .heading()
+                    .strong()
+                    .color(Color32::RED),
+            );
+            if ui.button("Reset E-stop").clicked() {
+                let _ = self
+                    .net_tx
+                    .send(NetMessage::EmergencyStop(EmergencyStopMessage::Reset));
+            }
+        } else {
+            let stop = Button::new(
+                RichText::new("EMERGENCY STOP")
+                    .heading()
+                    .strong()
+                    .color(Color32::WHITE),
+            )
+            .fill(Color32::RED);
+            if ui.add(stop).clicked() {
+                let _ = self.net_tx.send(NetMessage::EmergencyStop(
+                    EmergencyStopMessage::Stop(GCS_ESTOP_SOURCE.into()),
+                ));
+            }
+            if latched.is_none() {
+                ui.label("E-stop state not reported by the AFV");
+            }
+        }
+        ui.separator();
+    }
+}
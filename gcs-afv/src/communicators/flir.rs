@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use afv_internal::NOZZLE_TURRET_PORT;
+use afv_internal::{output::{OutputCommand, OutputState}, NOZZLE_TURRET_PORT};
 use eframe::{
     egui::{
         self,
@@ -19,12 +19,19 @@ use tokio::{
 };
 
 use crate::{
-    drivers::{flir::FlirDriverMessage, lights},
+    drivers::{flir::FlirDriverMessage, lease::{self, LeaseMessage}, lights},
     network::NetMessage,
-    operators::flir::{FlirAnalysis, FlirOperator, FlirOperatorMessage, FlirOperatorSettings, self},
+    operators::{flir::{FlirAnalysis, FlirOperator, FlirOperatorMessage, FlirOperatorSettings, self}, pump::{InterlockStatus, PumpOperatorMessage}},
     ui::Renderable,
 };
 
+/// The lease the lights button holds while the lights are switched on
+pub const LIGHTS_LEASE_HOLDER: &str = "flir-ui";
+/// Long enough to ride out a few slow frames, short enough that the lights go off soon after the UI closes
+pub const LIGHTS_LEASE_TTL: Duration = Duration::from_secs(3);
+/// How often the arm is resent while the pump is armed, well inside the pump's arm timeout
+pub const ARM_RENEW_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Clone)]
 pub struct FlirSystemCommunicator {
     net_tx: broadcast::Sender<NetMessage>,
@@ -45,6 +52,12 @@ pub struct FlirSystemCommunicator {
     auto_target_request_notify: Arc<Notify>,
     lights_request_notify: Arc<Notify>,
     lights_on: bool,
+    lights_state_watch: Arc<watch::Sender<Option<OutputState>>>,
+    lights_state_receiver: watch::Receiver<Option<OutputState>>,
+    arm_request_notify: Arc<Notify>,
+    armed: bool,
+    interlock_watch: Arc<watch::Sender<Option<InterlockStatus>>>,
+    interlock_receiver: watch::Receiver<Option<InterlockStatus>>,
 
     //Ui parameters
     adjustable_settings: FlirOperatorSettings,
@@ -59,6 +72,8 @@ impl FlirSystemCommunicator {
         let gui_image_watch = watch::channel(ColorImage::example());
         let settings_watch = watch::channel(Default::default());
         let image_analysis_watch = watch::channel(Default::default());
+        let lights_state_watch = watch::channel(None);
+        let interlock_watch = watch::channel(None);
 
         let comm = Self {
             net_tx,
@@ -83,6 +98,12 @@ impl FlirSystemCommunicator {
             auto_target: Default::default(),
             lights_request_notify: Default::default(),
             lights_on: false,
+            lights_state_watch: Arc::new(lights_state_watch.0),
+            lights_state_receiver: lights_state_watch.1,
+            arm_request_notify: Default::default(),
+            armed: false,
+            interlock_watch: Arc::new(interlock_watch.0),
+            interlock_receiver: interlock_watch.1,
         };
 
         tokio::spawn(comm.clone().nal_intake_task());
@@ -92,6 +113,9 @@ impl FlirSystemCommunicator {
         tokio::spawn(comm.clone().auto_target_request_task());
         tokio::spawn(comm.clone().analysis_intake_task());
         tokio::spawn(comm.clone().lights_request_task());
+        tokio::spawn(comm.clone().lights_state_task());
+        tokio::spawn(comm.clone().arm_request_task());
+        tokio::spawn(comm.clone().interlock_task());
 
         debug!("Starting new flir communication system");
 
@@ -177,10 +201,49 @@ impl FlirSystemCommunicator {
     async fn lights_request_task(self) {
         loop {
             self.lights_request_notify.notified().await;
-            sleep(lights::LIGHTS_COMMAND_INTERVAL).await;
+            sleep(lease::LEASE_REFRESH_INTERVAL).await;
+            let _ = self.net_tx.send(NetMessage::LightDriver(
+                lights::LightsDriverMessage::Lease(LeaseMessage::Acquire {
+                    holder: LIGHTS_LEASE_HOLDER.into(),
+                    ttl_ms: LIGHTS_LEASE_TTL.as_millis() as u32,
+                    command: OutputCommand::On,
+                }),
+            ));
+        }
+    }
+    async fn lights_state_task(self) {
+        let mut net_rx = self.net_tx.subscribe();
+
+        loop {
+            if let Ok(NetMessage::LightDriver(lights::LightsDriverMessage::State(state))) =
+                net_rx.recv().await
+            {
+                let _ = self.lights_state_watch.send(Some(state));
+            }
+        }
+    }
+    async fn arm_request_task(self) {
+        loop {
+            self.arm_request_notify.notified().await;
             let _ = self
                 .net_tx
-                .send(NetMessage::LightDriver(lights::LightsDriverMessage::TurnOn));
+                .send(NetMessage::PumpOperator(PumpOperatorMessage::Arm));
+            sleep(ARM_RENEW_INTERVAL).await;
+        }
+    }
+    async fn interlock_task(self) {
+        let mut net_rx = self.net_tx.subscribe();
+
+        loop {
+            match net_rx.recv().await {
+                Ok(NetMessage::PumpOperator(PumpOperatorMessage::Interlock(status))) => {
+                    let _ = self.interlock_watch.send(Some(status));
+                }
+                Ok(NetMessage::PumpOperator(PumpOperatorMessage::Refused(command, reason))) => {
+                    error!("Pump refused {:?}: {:?}", command, reason);
+                }
+                _ => {}
+            }
         }
     }
     async fn settings_update_task(self) {
@@ -303,6 +366,7 @@ impl Renderable for FlirSystemCommunicator {
             self.lights_request_notify.notify_one();
             if ui.button("Lights off").clicked(){
                 self.lights_on = false;
+                let _ = self.net_tx.send(NetMessage::LightDriver(lights::LightsDriverMessage::Lease(LeaseMessage::Release(LIGHTS_LEASE_HOLDER.into()))));
             }
             
         }
@@ -311,6 +375,35 @@ impl Renderable for FlirSystemCommunicator {
                 self.lights_on = true;
             }
         }
+        if self.armed{
+            self.arm_request_notify.notify_one();
+            if ui.button("Disarm pump").clicked(){
+                self.armed = false;
+                let _ = self.net_tx.send(NetMessage::PumpOperator(PumpOperatorMessage::Disarm));
+            }
+        }
+        else{
+            if ui.button("Arm pump").clicked(){
+                self.armed = true;
+            }
+        }
+        let interlock = match &*self.interlock_receiver.borrow(){
+            Some(status) if status.estop => "Pump E-stopped".to_string(),
+            Some(InterlockStatus{ refusal: Some(reason), .. }) => format!("Pump held: {:?}", reason),
+            Some(_) => "Pump ready".to_string(),
+            None => "Pump interlock not reporting".to_string(),
+        };
+        ui.label(interlock);
+        // The E-stop disarms the pump, so it has to be armed again once it is reset
+        if self.interlock_receiver.borrow().as_ref().map_or(false, |status| status.estop){
+            self.armed = false;
+        }
+        let lights_state = match *self.lights_state_receiver.borrow(){
+            Some(state) if state.active() => "Lights are on",
+            Some(_) => "Lights are off",
+            None => "Lights not reporting",
+        };
+        ui.label(lights_state);
 
         
         let drag = DragValue::new(&mut self.adjustable_settings.fliter_value)
@@ -0,0 +1,317 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+use crate::{
+    drivers::{
+        lidar::{DEFAULT_FILTER_WINDOW, LIDAR_STREAM_INTERVAL_MS, READING_TIMEOUT},
+        pump::PUMP_STATUS_INTERVAL,
+        turret::POLL_STEPS_INTERVAL,
+    },
+    operators::{
+        flir::FLIRFOV,
+        nozzle::{LOCK_ON_ANGLE, MIN_RANGE_SAMPLES, NOZZLE_TURRET_OFFSET, WATER_SPEED},
+    },
+};
+
+/// The configuration file the `afv` and `gcs` binaries load when no other is given
+pub const DEFAULT_CONFIG_PATH: &str = "afv.toml";
+
+/// The settings the `afv` and `gcs` binaries read from their TOML configuration file.
+///
+/// Every field is optional in the file, anything left out keeps its default,
+/// and the defaults are what the AFV ran with before it had a configuration file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub drivers: DriversConfig,
+    pub devices: DevicesConfig,
+    pub operators: OperatorsConfig,
+    pub network: NetworkConfig,
+    pub intervals: IntervalsConfig,
+    pub calibration: CalibrationConfig,
+    pub recording: RecordingConfig,
+}
+
+/// Which drivers the AFV starts
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DriversConfig {
+    pub flir_turret: bool,
+    pub nozzle_turret: bool,
+    pub lidar: bool,
+    pub pump: bool,
+    pub lights: bool,
+    pub siren: bool,
+    pub board_log: bool,
+}
+
+impl Default for DriversConfig {
+    fn default() -> Self {
+        Self {
+            flir_turret: true,
+            nozzle_turret: true,
+            lidar: true,
+            pump: true,
+            lights: true,
+            siren: true,
+            board_log: true,
+        }
+    }
+}
+
+/// Where each driver's MCU service is, as an IP:port endpoint.
+///
+/// A driver with an endpoint connects straight to it, every other enabled driver is found
+/// by the shared scan in [Discovery](crate::network::discovery::Discovery).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DevicesConfig {
+    pub flir_turret: Option<SocketAddr>,
+    pub nozzle_turret: Option<SocketAddr>,
+    pub lidar: Option<SocketAddr>,
+    pub pump: Option<SocketAddr>,
+    pub lights: Option<SocketAddr>,
+    pub siren: Option<SocketAddr>,
+}
+
+impl DevicesConfig {
+    /// Sets a driver's endpoint by its name in the configuration file, such as `pump`
+    pub fn set(&mut self, driver: &str, endpoint: SocketAddr) -> Result<(), String> {
+        let device = match driver {
+            "flir_turret" => &mut self.flir_turret,
+            "nozzle_turret" => &mut self.nozzle_turret,
+            "lidar" => &mut self.lidar,
+            "pump" => &mut self.pump,
+            "lights" => &mut self.lights,
+            "siren" => &mut self.siren,
+            _ => return Err(format!("{} is not a driver with an endpoint", driver)),
+        };
+        *device = Some(endpoint);
+        Ok(())
+    }
+}
+
+/// Which operators the AFV starts
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OperatorsConfig {
+    pub naming: bool,
+    pub flir: bool,
+    pub nozzle: bool,
+    pub range_map: bool,
+}
+
+impl Default for OperatorsConfig {
+    fn default() -> Self {
+        Self {
+            naming: true,
+            flir: true,
+            nozzle: true,
+            range_map: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Runs the AFV's bridge as a server that the GCS connects to, rather than scanning for the GCS
+    pub server: bool,
+    /// Connects the AFV's bridge straight to the GCS at this address instead of scanning for it
+    pub gcs_address: Option<SocketAddr>,
+    /// How many scans the AFV makes for the GCS
+    pub afv_scans: u32,
+    /// How many scans the GCS makes for AFVs
+    pub gcs_scans: u32,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            server: false,
+            gcs_address: None,
+            afv_scans: 3,
+            gcs_scans: 2,
+        }
+    }
+}
+
+/// How often the drivers poll their boards, in ms
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntervalsConfig {
+    /// How often the turrets are polled for their angle
+    pub poll_steps_ms: u64,
+    /// How often the lidar firmware pushes readings
+    pub lidar_stream_ms: u16,
+    /// How often the pump is polled for its status
+    pub pump_status_ms: u64,
+}
+
+impl IntervalsConfig {
+    pub fn poll_steps(&self) -> Duration {
+        Duration::from_millis(self.poll_steps_ms)
+    }
+    pub fn pump_status(&self) -> Duration {
+        Duration::from_millis(self.pump_status_ms)
+    }
+}
+
+impl Default for IntervalsConfig {
+    fn default() -> Self {
+        Self {
+            poll_steps_ms: POLL_STEPS_INTERVAL.as_millis() as u64,
+            lidar_stream_ms: LIDAR_STREAM_INTERVAL_MS,
+            pump_status_ms: PUMP_STATUS_INTERVAL.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibrationConfig {
+    /// The FLIR camera's half field of view, pan then tilt, in degrees
+    pub flir_fov_deg: [f32; 2],
+    /// Where the nozzle turret sits relative to the FLIR turret, in m
+    pub nozzle_offset_m: [f32; 2],
+    /// How fast water leaves the nozzle, in m/s
+    pub water_speed_m_s: f32,
+    /// How close, pan then tilt in degrees, the FLIR turret must be to a target before the nozzle is aimed at it
+    pub lock_on_angle_deg: [f32; 2],
+    /// The fewest filtered lidar readings a firing solution is computed from
+    pub min_range_samples: usize,
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        Self {
+            flir_fov_deg: [FLIRFOV.0, FLIRFOV.1],
+            nozzle_offset_m: NOZZLE_TURRET_OFFSET,
+            water_speed_m_s: WATER_SPEED,
+            lock_on_angle_deg: LOCK_ON_ANGLE,
+            min_range_samples: MIN_RANGE_SAMPLES,
+        }
+    }
+}
+
+/// Where the AFV records its bus, see [Recorder](crate::recorder::Recorder), and exports its range maps
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Records the bus and the FLIR's IR video to this file, replacing it, while the AFV runs
+    pub path: Option<PathBuf>,
+    /// The only directory range maps are exported to, exports are refused when it is not set
+    pub range_map_dir: Option<PathBuf>,
+}
+
+impl Config {
+    /// Loads and validates a configuration file
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+        let config: Self = toml::from_str(&text)
+            .map_err(|e| format!("Invalid configuration {:?}: {}", path, e))?;
+        config
+            .validate()
+            .map_err(|e| format!("Invalid configuration {:?}: {}", path, e))?;
+        Ok(config)
+    }
+    /// Loads the configuration file at the path, falling back to the defaults only when
+    /// no path was given and [DEFAULT_CONFIG_PATH] does not exist
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        match path {
+            Some(path) => Self::from_file(path),
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))
+            }
+            None => Ok(Self::default()),
+        }
+    }
+    /// Checks the settings a file can hold but the AFV cannot run with
+    pub fn validate(&self) -> Result<(), String> {
+        let intervals = &self.intervals;
+        if intervals.poll_steps_ms == 0 {
+            return Err("intervals.poll_steps_ms must be above 0".into());
+        }
+        if intervals.lidar_stream_ms == 0 {
+            return Err("intervals.lidar_stream_ms must be above 0".into());
+        }
+        if intervals.lidar_stream_ms as u128 >= READING_TIMEOUT.as_millis() {
+            return Err(format!(
+                "intervals.lidar_stream_ms must be below the lidar's reading timeout of {} ms",
+                READING_TIMEOUT.as_millis()
+            ));
+        }
+        if intervals.pump_status_ms == 0 {
+            return Err("intervals.pump_status_ms must be above 0".into());
+        }
+
+        let calibration = &self.calibration;
+        if calibration
+            .flir_fov_deg
+            .iter()
+            .any(|fov| !(*fov > 0.0 && *fov < 90.0))
+        {
+            return Err("calibration.flir_fov_deg must be between 0 and 90 degrees".into());
+        }
+        if calibration.nozzle_offset_m.iter().any(|o| !o.is_finite()) {
+            return Err("calibration.nozzle_offset_m must be a finite distance".into());
+        }
+        if !(calibration.water_speed_m_s > 0.0 && calibration.water_speed_m_s.is_finite()) {
+            return Err("calibration.water_speed_m_s must be above 0".into());
+        }
+        if calibration
+            .lock_on_angle_deg
+            .iter()
+            .any(|angle| !(*angle > 0.0 && angle.is_finite()))
+        {
+            return Err("calibration.lock_on_angle_deg must be above 0".into());
+        }
+        if !(1..=DEFAULT_FILTER_WINDOW).contains(&calibration.min_range_samples) {
+            return Err(format!(
+                "calibration.min_range_samples must be between 1 and the lidar filter window of {}",
+                DEFAULT_FILTER_WINDOW
+            ));
+        }
+
+        let drivers = &self.drivers;
+        if self.operators.nozzle && !(drivers.flir_turret && drivers.nozzle_turret && drivers.lidar)
+        {
+            return Err(
+                "operators.nozzle needs drivers.flir_turret, drivers.nozzle_turret and drivers.lidar"
+                    .into(),
+            );
+        }
+        // The pump's interlock only lets it run while the nozzle turret reports its angle
+        if drivers.pump && !drivers.nozzle_turret {
+            return Err("drivers.pump needs drivers.nozzle_turret".into());
+        }
+
+        let devices = &self.devices;
+        for (name, endpoint, enabled) in [
+            ("flir_turret", devices.flir_turret, drivers.flir_turret),
+            (
+                "nozzle_turret",
+                devices.nozzle_turret,
+                drivers.nozzle_turret,
+            ),
+            ("lidar", devices.lidar, drivers.lidar),
+            ("pump", devices.pump, drivers.pump),
+            ("lights", devices.lights, drivers.lights),
+            ("siren", devices.siren, drivers.siren),
+        ] {
+            if endpoint.is_some() && !enabled {
+                return Err(format!(
+                    "devices.{} is set but drivers.{} is disabled",
+                    name, name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use std::{collections::HashSet, net::IpAddr};
+
+use afv_internal::{
+    fault::{FaultMsg, FaultReport},
+    logging::{LogLevel, LogMsg},
+    network::InternalMessage,
+    LOG_PORT, SOCKET_MSG_SIZE,
+};
+use log::{error, info, log, warn, Level};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpStream, sync::broadcast};
+
+use crate::{
+    network::{discovery::Discovery, socket::Socket, NetMessage},
+    shutdown::Shutdown,
+};
+
+/// The log target board records are written to, use `RUST_LOG=afv_internal=debug` to filter on it
+pub const BOARD_LOG_TARGET: &str = "afv_internal";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum BoardLogMessage {
+    /// A log record from the board at `board`
+    Record {
+        board: IpAddr,
+        uptime_ms: u32,
+        level: LogLevel,
+        /// Records the board dropped from its buffer before this one
+        dropped: u8,
+        text: String,
+    },
+    /// Why the board at `board` last reset, sent each time the driver connects to it
+    Reset { board: IpAddr, report: FaultReport },
+    /// Sets the most verbose level the board at the address records, or every board if None
+    SetLevel(Option<IpAddr>, LogLevel),
+}
+
+#[derive(Clone)]
+/// The BoardLogDriver finds every AFV-INTERNAL board through its [LOG_PORT] and forwards the records they
+/// buffer onto the main bus and into the host's log output, tagged with the board's address.
+/// Boards are found by the shared scan in [Discovery], which needs [LOG_PORT] among its board ports.
+///
+/// Boards also report why they last reset when the driver connects, which is logged and acknowledged here.
+pub struct BoardLogDriver {
+    net_tx: broadcast::Sender<NetMessage>,
+    shutdown: Shutdown,
+}
+
+#[derive(Clone)]
+struct BoardLog {
+    board: IpAddr,
+    net_tx: broadcast::Sender<NetMessage>,
+    log_socket: Socket,
+}
+
+impl BoardLogDriver {
+    /// None if the discovery is not looking for boards on [LOG_PORT]
+    pub async fn new(
+        net_tx: broadcast::Sender<NetMessage>,
+        discovery: &Discovery,
+        shutdown: &Shutdown,
+    ) -> Option<Self> {
+        let boards = match discovery.boards(LOG_PORT) {
+            Some(boards) => boards,
+            None => {
+                error!("Discovery is not looking for boards on port {}", LOG_PORT);
+                return None;
+            }
+        };
+        let driver = Self {
+            net_tx,
+            shutdown: shutdown.clone(),
+        };
+        shutdown.spawn(driver.clone().find_boards_task(boards));
+        Some(driver)
+    }
+
+    /// Every board that is found gets its own forwarding tasks. Their sockets reconnect on their own, so a
+    /// board found again by a later scan round is skipped
+    async fn find_boards_task(self, boards: flume::Receiver<TcpStream>) {
+        let mut known = HashSet::new();
+        while let Ok(stream) = boards.recv_async().await {
+            let board = match stream.peer_addr() {
+                Ok(addr) => addr.ip(),
+                Err(_) => continue,
+            };
+            if !known.insert(board) {
+                continue;
+            }
+            let board = BoardLog {
+                board,
+                net_tx: self.net_tx.clone(),
+                log_socket: Socket::new(stream, false),
+            };
+            info!("Receiving logs from board {}", board.board);
+            self.shutdown.spawn(board.clone().forward_messages_task());
+            self.shutdown.spawn(board.set_level_task());
+        }
+    }
+}
+
+impl BoardLog {
+    async fn forward_messages_task(self) {
+        loop {
+            let mut data = [0u8; SOCKET_MSG_SIZE];
+            for i in 0..data.len() {
+                data[i] = self.log_socket.read_byte().await;
+            }
+
+            let record = match InternalMessage::from_msg(&data) {
+                Some(InternalMessage::Log(LogMsg::Record(record))) => record,
+                Some(InternalMessage::Fault(FaultMsg::Report(report))) => {
+                    self.report_reset(report).await;
+                    continue;
+                }
+                _ => continue,
+            };
+            let level = match record.level {
+                LogLevel::Error => Level::Error,
+                LogLevel::Warn => Level::Warn,
+                LogLevel::Info => Level::Info,
+                LogLevel::Debug => Level::Debug,
+            };
+            if record.dropped > 0 {
+                log!(
+                    target: BOARD_LOG_TARGET,
+                    Level::Warn,
+                    "[{}] {} records dropped",
+                    self.board,
+                    record.dropped
+                );
+            }
+            log!(
+                target: BOARD_LOG_TARGET,
+                level,
+                "[{} {}ms] {}",
+                self.board,
+                record.uptime_ms,
+                record.text()
+            );
+            let _ = self
+                .net_tx
+                .send(NetMessage::BoardLog(BoardLogMessage::Record {
+                    board: self.board,
+                    uptime_ms: record.uptime_ms,
+                    level: record.level,
+                    dropped: record.dropped,
+                    text: record.text().to_string(),
+                }));
+        }
+    }
+    async fn report_reset(&self, report: FaultReport) {
+        let cause = report.reset_cause;
+        match report.fault {
+            Some(fault) => error!(
+                "Board {} reset after panicking at ...{}:{}:{}",
+                self.board,
+                fault.file(),
+                fault.line,
+                fault.column
+            ),
+            None if cause.watchdog() => error!("Board {} reset by its watchdog", self.board),
+            None if cause.brown_out() => warn!("Board {} reset by a brown out", self.board),
+            None => info!("Board {} reset, MCUSR {:#04x}", self.board, cause.0),
+        }
+        let _ = self
+            .net_tx
+            .send(NetMessage::BoardLog(BoardLogMessage::Reset {
+                board: self.board,
+                report,
+            }));
+        if let Some(msg) = InternalMessage::Fault(FaultMsg::Acknowledge).to_msg() {
+            let _ = self.log_socket.write_data(&msg).await;
+        }
+    }
+    async fn set_level_task(self) {
+        let mut net_rx = self.net_tx.subscribe();
+        loop {
+            let level = match net_rx.recv().await {
+                Ok(NetMessage::BoardLog(BoardLogMessage::SetLevel(board, level)))
+                    if board.map_or(true, |b| b == self.board) =>
+                {
+                    level
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if let Some(msg) = InternalMessage::Log(LogMsg::SetLevel(level)).to_msg() {
+                let _ = self.log_socket.write_data(&msg).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use afv_internal::{estop::EStopMsg, network::InternalMessage};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, watch},
+    time::{interval, Duration},
+};
+
+use crate::{
+    network::{
+        socket::{Socket, SocketState},
+        NetMessage,
+    },
+    shutdown::Shutdown,
+};
+
+/// How often the latch is resent to the boards and its status published, so boards that restart
+/// and drivers that start late pick it up
+pub const ESTOP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum EmergencyStopMessage {
+    /// Latches the E-stop on every driver and board, along with what triggered it
+    Stop(String),
+    /// Clears the latch, sent from the GCS
+    Reset,
+    /// Published every [ESTOP_INTERVAL] by the AFV, a latched status also latches drivers that missed the stop
+    Status(bool),
+}
+
+#[derive(Clone)]
+/// Follows the vehicle wide E-stop on the bus. Each driver keeps one, checking it before sending commands
+/// and handing it the sockets of the boards it talks to.
+///
+/// The latch only clears on an explicit [EmergencyStopMessage::Reset].
+pub struct EmergencyStop {
+    net_tx: broadcast::Sender<NetMessage>,
+    clear_rx: watch::Receiver<bool>,
+    /// Ends the E-stop's tasks
+    shutdown: Shutdown,
+}
+
+impl EmergencyStop {
+    pub fn new(net_tx: broadcast::Sender<NetMessage>, shutdown: Shutdown) -> Self {
+        let (clear_tx, clear_rx) = watch::channel(true);
+        let estop = Self {
+            net_tx,
+            clear_rx,
+            shutdown,
+        };
+
+        estop.shutdown.spawn(estop.clone().latch_task(clear_tx));
+
+        estop
+    }
+    pub fn latched(&self) -> bool {
+        !*self.clear_rx.borrow()
+    }
+    /// True while the E-stop is clear, for use as a [LeasedOutput](crate::drivers::lease::LeasedOutput) permit
+    pub fn permit(&self) -> watch::Receiver<bool> {
+        self.clear_rx.clone()
+    }
+    /// Passes the latch on to the board behind the socket, resending it while latched in case the board restarts.
+    ///
+    /// The board is also asked for its own latch whenever the socket connects. The driver reading the socket hands
+    /// the answer to the returned [BoardLatch], so a board latched while no host was watching latches the vehicle too.
+    pub fn forward_to(&self, socket: Socket) -> BoardLatch {
+        let board = BoardLatch {
+            estop: self.clone(),
+            board: socket.peer_addr().to_string(),
+            polled: Arc::new(AtomicBool::new(false)),
+        };
+        self.shutdown
+            .spawn(self.clone().forward_task(socket, board.clone()));
+        board
+    }
+    /// Publishes the latch's [EmergencyStopMessage::Status], run once on the AFV
+    pub fn publish_status(&self) {
+        self.shutdown.spawn(self.clone().status_task());
+    }
+    async fn latch_task(self, clear_tx: watch::Sender<bool>) {
+        let mut net_rx = self.net_tx.subscribe();
+
+        loop {
+            let clear = match net_rx.recv().await {
+                Ok(NetMessage::EmergencyStop(EmergencyStopMessage::Stop(_)))
+                | Ok(NetMessage::EmergencyStop(EmergencyStopMessage::Status(true))) => false,
+                Ok(NetMessage::EmergencyStop(EmergencyStopMessage::Reset)) => true,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            clear_tx.send_if_modified(|current| std::mem::replace(current, clear) != clear);
+        }
+    }
+    async fn forward_task(mut self, socket: Socket, board: BoardLatch) {
+        let mut resend = interval(ESTOP_INTERVAL);
+        let mut socket_state = socket.state();
+        // A reset that fails to send is tried again with the next resend
+        let mut unsent = false;
+        let mut poll = true;
+
+        loop {
+            if poll {
+                poll = false;
+                if let Some(data) = InternalMessage::EmergencyStop(EStopMsg::PollState).to_msg() {
+                    // Set first, the answer can be read before the write returns
+                    board.polled.store(true, Ordering::Relaxed);
+                    if socket.write_data(&data).await.is_err() {
+                        board.polled.store(false, Ordering::Relaxed);
+                    }
+                }
+            }
+            tokio::select! {
+                changed = socket_state.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    // The board may have latched or restarted while the socket was down
+                    poll = *socket_state.borrow() == SocketState::Connected;
+                    continue;
+                }
+                changed = self.clear_rx.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+                _ = resend.tick() => {
+                    if !self.latched() && !unsent {
+                        continue;
+                    }
+                }
+            }
+            let msg = match self.latched() {
+                true => EStopMsg::Stop,
+                false => EStopMsg::Reset,
+            };
+            if let Some(data) = InternalMessage::EmergencyStop(msg).to_msg() {
+                unsent = socket.write_data(&data).await.is_err();
+            }
+        }
+    }
+    async fn status_task(mut self) {
+        let mut interval = interval(ESTOP_INTERVAL);
+
+        loop {
+            tokio::select! {
+                changed = self.clear_rx.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    match self.latched() {
+                        true => error!("Emergency stop latched"),
+                        false => info!("Emergency stop reset"),
+                    }
+                }
+                _ = interval.tick() => {}
+            }
+            let status = EmergencyStopMessage::Status(self.latched());
+            let _ = self.net_tx.send(NetMessage::EmergencyStop(status));
+        }
+    }
+}
+
+#[derive(Clone)]
+/// The board end of an [EmergencyStop::forward_to], fed the [EStopMsg::State] replies the board sends
+pub struct BoardLatch {
+    estop: EmergencyStop,
+    /// The board's address, given as what triggered a stop it started
+    board: String,
+    /// Set once the board has been asked for its latch, until the answer is read
+    polled: Arc<AtomicBool>,
+}
+
+impl BoardLatch {
+    /// Latches the vehicle if this is the answer to the poll sent on connect and the board is latched.
+    /// Replies to the stops and resets forwarded to the board are ignored, they may be stale by the time they are read
+    pub fn state(&self, latched: bool) {
+        if !self.polled.swap(false, Ordering::Relaxed) || !latched || self.estop.latched() {
+            return;
+        }
+        error!("Board {} has its E-stop latched", self.board);
+        let _ = self
+            .estop
+            .net_tx
+            .send(NetMessage::EmergencyStop(EmergencyStopMessage::Stop(
+                format!("Board {} was latched", self.board),
+            )));
+    }
+}
@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use afv_internal::update::{IMAGE_CAPACITY, PAGE_SIZE};
+
+const PT_LOAD: u32 = 1;
+/// The AVR toolchain places RAM and EEPROM above this address in its ELF files
+const AVR_FLASH_END: u32 = 0x80_0000;
+
+/// A flat flash image, starting at address zero, that can be pushed to a board
+/// with [crate::drivers::system::SystemClient]
+pub struct FirmwareImage {
+    data: Vec<u8>,
+}
+
+impl FirmwareImage {
+    /// Loads an Intel HEX file, or an ELF file such as the one `cargo build` produces
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents =
+            std::fs::read(path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+        if contents.starts_with(b"\x7fELF") {
+            Self::from_elf(&contents)
+        } else {
+            let text = String::from_utf8(contents).map_err(|_| "Image is neither ELF nor HEX")?;
+            Self::from_hex(&text)
+        }
+    }
+    pub fn from_hex(text: &str) -> Result<Self, String> {
+        let mut image = Self { data: vec![] };
+        let mut base = 0u32;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || format!("Invalid HEX record on line {}", number + 1);
+            let hex = line.strip_prefix(':').ok_or_else(error)?;
+            if hex.len() % 2 != 0 {
+                return Err(error());
+            }
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| error())?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(error());
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(format!("Bad checksum on line {}", number + 1));
+            }
+            let address = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let payload = &bytes[4..bytes.len() - 1];
+            match bytes[3] {
+                0x00 => image.place(base + address, payload)?,
+                0x01 => break,
+                0x02 => base = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 4,
+                0x04 => base = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 16,
+                // Start addresses do not affect the image
+                0x03 | 0x05 => {}
+                _ => return Err(error()),
+            }
+        }
+        image.check()
+    }
+    /// Uses the physical address of every loadable segment, which for the AVR is its place in flash
+    pub fn from_elf(elf: &[u8]) -> Result<Self, String> {
+        let u16_at = |offset: usize| -> Result<u32, String> {
+            elf.get(offset..offset + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as u32)
+                .ok_or("Truncated ELF file".into())
+        };
+        let u32_at = |offset: usize| -> Result<u32, String> {
+            elf.get(offset..offset + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or("Truncated ELF file".into())
+        };
+        // Only 32 bit little endian files, which is what the AVR toolchain produces
+        if elf.get(4..6) != Some(&[1, 1]) {
+            return Err("Only 32 bit little endian ELF files are supported".into());
+        }
+        let ph_offset = u32_at(0x1c)? as usize;
+        let ph_size = u16_at(0x2a)? as usize;
+        let ph_count = u16_at(0x2c)? as usize;
+
+        let mut image = Self { data: vec![] };
+        for i in 0..ph_count {
+            let header = ph_offset + i * ph_size;
+            let (kind, offset, address, size) = (
+                u32_at(header)?,
+                u32_at(header + 4)? as usize,
+                u32_at(header + 12)?,
+                u32_at(header + 16)? as usize,
+            );
+            if kind != PT_LOAD || size == 0 || address >= AVR_FLASH_END {
+                continue;
+            }
+            let segment = elf
+                .get(offset..offset + size)
+                .ok_or("Truncated ELF segment")?;
+            image.place(address, segment)?;
+        }
+        image.check()
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    /// The image split into flash pages, the last one padded with erased flash
+    pub fn pages(&self) -> Vec<[u8; PAGE_SIZE]> {
+        self.data
+            .chunks(PAGE_SIZE)
+            .map(|chunk| {
+                let mut page = [0xff; PAGE_SIZE];
+                page[..chunk.len()].copy_from_slice(chunk);
+                page
+            })
+            .collect()
+    }
+    pub fn crc(&self) -> u16 {
+        afv_internal::config::crc16(&self.data)
+    }
+
+    fn place(&mut self, address: u32, bytes: &[u8]) -> Result<(), String> {
+        let end = address as usize + bytes.len();
+        if end > IMAGE_CAPACITY as usize {
+            return Err(format!(
+                "Image does not fit in the {} bytes available for updates",
+                IMAGE_CAPACITY
+            ));
+        }
+        if self.data.len() < end {
+            self.data.resize(end, 0xff);
+        }
+        self.data[address as usize..end].copy_from_slice(bytes);
+        Ok(())
+    }
+    fn check(self) -> Result<Self, String> {
+        if self.data.is_empty() {
+            return Err("Image contains no flash data".into());
+        }
+        Ok(self)
+    }
+}
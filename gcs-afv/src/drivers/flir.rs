@@ -13,9 +13,12 @@ use tokio::{
 };
 use url::Url;
 
-use crate::network::{
-    scanner::{ScanBuilder, ScanCount},
-    NetMessage,
+use crate::{
+    network::{
+        scanner::{ScanBuilder, ScanCount},
+        NetMessage,
+    },
+    shutdown::Shutdown,
 };
 
 pub const STREAM_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
@@ -45,7 +48,11 @@ pub struct FlirDriver {
 
 impl FlirDriver {
     /// This will create a new flir driver and register it on the main bus channel (net_tx)
-    pub async fn new(net_tx: broadcast::Sender<NetMessage>, visual_stream: bool) -> FlirDriver {
+    pub async fn new(
+        net_tx: broadcast::Sender<NetMessage>,
+        visual_stream: bool,
+        shutdown: &Shutdown,
+    ) -> FlirDriver {
         let driver = Self {
             ir_nal_stream: broadcast::channel(100).0,
             visual_nal_stream: broadcast::channel(100).0,
@@ -54,15 +61,15 @@ impl FlirDriver {
             visual_network_watch: Arc::new(watch::channel(Instant::now()).0),
         };
 
-        tokio::spawn(driver.clone().ir_stream_task());
+        shutdown.spawn(driver.clone().ir_stream_task());
         if visual_stream {
-            tokio::spawn(driver.clone().visual_stream_task());
+            shutdown.spawn(driver.clone().visual_stream_task());
         }
 
-        tokio::spawn(driver.clone().network_ir_stream_task());
-        tokio::spawn(driver.clone().network_visual_stream());
-        tokio::spawn(driver.clone().network_ir_stream_watch_task());
-        tokio::spawn(driver.clone().network_visual_stream_watch_task());
+        shutdown.spawn(driver.clone().network_ir_stream_task());
+        shutdown.spawn(driver.clone().network_visual_stream());
+        shutdown.spawn(driver.clone().network_ir_stream_watch_task());
+        shutdown.spawn(driver.clone().network_visual_stream_watch_task());
 
         driver
     }
@@ -70,6 +77,10 @@ impl FlirDriver {
     pub fn ir_stream_rx(&self) -> broadcast::Receiver<Vec<u8>> {
         self.ir_nal_stream.subscribe()
     }
+    /// The sender behind [FlirDriver::ir_stream_rx], for whatever has to subscribe to the stream more than once
+    pub fn ir_stream(&self) -> broadcast::Sender<Vec<u8>> {
+        self.ir_nal_stream.clone()
+    }
     /// This upates the time of last request for the network ir stream task
     async fn network_ir_stream_watch_task(self) {
         let mut net_rx = self.net_tx.subscribe();
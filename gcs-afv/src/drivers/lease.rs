@@ -0,0 +1,365 @@
+use std::future::pending;
+
+use afv_internal::{
+    network::InternalMessage,
+    output::{OutputCommand, OutputMsg},
+};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, watch},
+    time::{interval, sleep_until, Duration, Instant},
+};
+
+use crate::{
+    network::{socket::Socket, NetMessage},
+    shutdown::Shutdown,
+};
+
+/// How often the leading lease's command is resent while it is held, so a board that restarts picks it back up
+pub const LEASE_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// The TTL of the lease behind a driver's TurnOn and Hold messages
+pub const KEEPALIVE_TTL: Duration = Duration::from_secs(2);
+/// The holder TurnOn and Hold acquire their lease under
+pub const KEEPALIVE_HOLDER: &str = "keepalive";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum LeaseMessage {
+    /// Takes the named lease, or updates it if the holder already has one.
+    /// The output runs the command of the most recently taken lease until it is released or expires
+    Acquire {
+        holder: String,
+        ttl_ms: u32,
+        command: OutputCommand,
+    },
+    /// Extends the named lease by its TTL
+    Renew(String),
+    Release(String),
+    /// Drops every lease on the output, such as when the pump runs dry. Taken whether or not the output has its permit
+    ReleaseAll,
+    /// Published by the driver whenever a lease is taken, changes command, is released or expires
+    Holders(Vec<LeaseHolder>),
+}
+
+impl LeaseMessage {
+    /// The lease a driver's TurnOn and Hold messages stand for
+    pub fn keepalive(command: OutputCommand) -> Self {
+        LeaseMessage::Acquire {
+            holder: KEEPALIVE_HOLDER.into(),
+            ttl_ms: KEEPALIVE_TTL.as_millis() as u32,
+            command,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LeaseHolder {
+    pub holder: String,
+    pub command: OutputCommand,
+    pub remaining_ms: u32,
+}
+
+struct Lease {
+    holder: String,
+    command: OutputCommand,
+    ttl: Duration,
+    expires: Instant,
+}
+
+/// The leases held on one output, in the order they were taken
+#[derive(Default)]
+pub struct Leases {
+    leases: Vec<Lease>,
+}
+
+impl Leases {
+    /// Returns whether the holders or their commands changed. A holder taking its lease again
+    /// keeps its place, so renewing through [LeaseMessage::Acquire] does not take over the output
+    pub fn acquire(
+        &mut self,
+        holder: &str,
+        ttl: Duration,
+        command: OutputCommand,
+        now: Instant,
+    ) -> bool {
+        if let Some(lease) = self.leases.iter_mut().find(|l| l.holder == holder) {
+            let changed = lease.command != command;
+            lease.command = command;
+            lease.ttl = ttl;
+            lease.expires = now + ttl;
+            return changed;
+        }
+        self.leases.push(Lease {
+            holder: holder.into(),
+            command,
+            ttl,
+            expires: now + ttl,
+        });
+        true
+    }
+    /// Returns false if the holder has no lease to renew
+    pub fn renew(&mut self, holder: &str, now: Instant) -> bool {
+        match self.leases.iter_mut().find(|l| l.holder == holder) {
+            Some(lease) => {
+                lease.expires = now + lease.ttl;
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn release(&mut self, holder: &str) -> bool {
+        let held = self.leases.len();
+        self.leases.retain(|l| l.holder != holder);
+        self.leases.len() != held
+    }
+    /// Returns whether any lease was held
+    pub fn release_all(&mut self) -> bool {
+        let held = !self.leases.is_empty();
+        self.leases.clear();
+        held
+    }
+    /// Drops every lease past its TTL, returning whether any were
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let held = self.leases.len();
+        self.leases.retain(|l| l.expires > now);
+        self.leases.len() != held
+    }
+    /// The command of the most recently taken lease, None once every lease is gone
+    pub fn command(&self) -> Option<OutputCommand> {
+        self.leases.last().map(|l| l.command)
+    }
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.leases.iter().map(|l| l.expires).min()
+    }
+    pub fn holders(&self, now: Instant) -> Vec<LeaseHolder> {
+        self.leases
+            .iter()
+            .map(|l| LeaseHolder {
+                holder: l.holder.clone(),
+                command: l.command,
+                remaining_ms: l.expires.saturating_duration_since(now).as_millis() as u32,
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+/// Drives an output channel from the leases held on it, turning the output off once when every lease is gone.
+///
+/// The output is left alone while no lease is held, so commands the board times itself, such as a pump run,
+/// are not cut short.
+///
+/// An output given a permit only takes leases while it is true, and drops every lease when it turns false.
+pub struct LeasedOutput {
+    net_tx: broadcast::Sender<NetMessage>,
+    socket: Socket,
+    channel: u8,
+    /// Picks the lease requests for this output off the bus
+    to_lease: fn(NetMessage) -> Option<LeaseMessage>,
+    /// Wraps the holders this output publishes
+    from_lease: fn(LeaseMessage) -> NetMessage,
+    permit: Option<watch::Receiver<bool>>,
+}
+
+impl LeasedOutput {
+    pub fn new(
+        net_tx: broadcast::Sender<NetMessage>,
+        socket: Socket,
+        channel: u8,
+        to_lease: fn(NetMessage) -> Option<LeaseMessage>,
+        from_lease: fn(LeaseMessage) -> NetMessage,
+        permit: Option<watch::Receiver<bool>>,
+        shutdown: &Shutdown,
+    ) -> Self {
+        let output = Self {
+            net_tx,
+            socket,
+            channel,
+            to_lease,
+            from_lease,
+            permit,
+        };
+
+        shutdown.spawn(output.clone().lease_task());
+
+        output
+    }
+    async fn lease_task(mut self) {
+        let mut net_rx = self.net_tx.subscribe();
+        let mut permit = self.permit.take();
+        let mut leases = Leases::default();
+        let mut refresh = interval(LEASE_REFRESH_INTERVAL);
+        // None until the first command goes out, so the output is turned off once when the driver connects.
+        // A command that fails to send is tried again on the next pass
+        let mut sent = None;
+
+        loop {
+            let permitted = permit.as_ref().map_or(true, |permit| *permit.borrow());
+            let expiry = leases.next_expiry();
+            let mut changed = false;
+            let mut resend = false;
+            tokio::select! {
+                msg = net_rx.recv() => match msg {
+                    Ok(msg) => {
+                        if let Some(lease) = (self.to_lease)(msg) {
+                            changed = self.apply(&mut leases, lease, permitted);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                _ = refresh.tick() => resend = true,
+                permitted = async {
+                    match permit.as_mut() {
+                        Some(permit) => permit.changed().await.map(|_| *permit.borrow()),
+                        None => pending().await,
+                    }
+                } => match permitted {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        debug!("Output {} lost its permit, dropping every lease", self.channel);
+                        changed = leases.command().is_some();
+                        leases = Leases::default();
+                    }
+                    // Nothing is left to give the permit back, so the output is turned off for good
+                    Err(_) => {
+                        if let Some(msg) = InternalMessage::Output(OutputMsg::Command(self.channel, OutputCommand::Off)).to_msg() {
+                            let _ = self.socket.write_data(&msg).await;
+                        }
+                        return;
+                    }
+                },
+                _ = sleep_until(expiry.unwrap_or_else(Instant::now)), if expiry.is_some() => {}
+            }
+
+            let now = Instant::now();
+            if leases.expire(now) {
+                debug!("A lease on output {} expired", self.channel);
+                changed = true;
+            }
+            if changed {
+                let holders = LeaseMessage::Holders(leases.holders(now));
+                let _ = self.net_tx.send((self.from_lease)(holders));
+            }
+
+            let command = match leases.command() {
+                Some(command) if resend || sent != Some(command) => command,
+                None if sent != Some(OutputCommand::Off) => OutputCommand::Off,
+                _ => continue,
+            };
+            if let Some(msg) =
+                InternalMessage::Output(OutputMsg::Command(self.channel, command)).to_msg()
+            {
+                if self.socket.write_data(&msg).await.is_err() {
+                    continue;
+                }
+            }
+            sent = Some(command);
+        }
+    }
+    fn apply(&self, leases: &mut Leases, msg: LeaseMessage, permitted: bool) -> bool {
+        let now = Instant::now();
+        match msg {
+            LeaseMessage::Acquire { .. } | LeaseMessage::Renew(_) if !permitted => false,
+            LeaseMessage::Acquire {
+                holder,
+                ttl_ms,
+                command,
+            } => leases.acquire(&holder, Duration::from_millis(ttl_ms as u64), command, now),
+            LeaseMessage::Renew(holder) => {
+                if !leases.renew(&holder, now) {
+                    debug!(
+                        "{} has no lease on output {} to renew",
+                        holder, self.channel
+                    );
+                }
+                false
+            }
+            LeaseMessage::Release(holder) => leases.release(&holder),
+            LeaseMessage::ReleaseAll => {
+                debug!("Dropping every lease on output {}", self.channel);
+                leases.release_all()
+            }
+            LeaseMessage::Holders(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn the_most_recent_lease_leads() {
+        let now = Instant::now();
+        let mut leases = Leases::default();
+        assert_eq!(leases.command(), None);
+        assert!(leases.acquire("operator", TTL, OutputCommand::On, now));
+        assert!(leases.acquire("test", TTL, OutputCommand::Duty(128), now));
+        assert_eq!(leases.command(), Some(OutputCommand::Duty(128)));
+
+        assert!(leases.release("test"));
+        assert_eq!(leases.command(), Some(OutputCommand::On));
+        assert!(!leases.release("test"));
+    }
+
+    #[test]
+    fn taking_a_lease_again_keeps_its_place() {
+        let now = Instant::now();
+        let mut leases = Leases::default();
+        leases.acquire("operator", TTL, OutputCommand::On, now);
+        leases.acquire("test", TTL, OutputCommand::Duty(128), now);
+
+        assert!(!leases.acquire("operator", TTL, OutputCommand::On, now));
+        assert_eq!(leases.command(), Some(OutputCommand::Duty(128)));
+        assert!(leases.acquire("operator", TTL, OutputCommand::Pulse(500), now));
+        assert_eq!(leases.command(), Some(OutputCommand::Duty(128)));
+        let holders: Vec<String> = leases.holders(now).into_iter().map(|h| h.holder).collect();
+        assert_eq!(holders, ["operator", "test"]);
+    }
+
+    #[test]
+    fn leases_expire_after_their_ttl() {
+        let now = Instant::now();
+        let mut leases = Leases::default();
+        leases.acquire("short", Duration::from_secs(1), OutputCommand::On, now);
+        leases.acquire("long", Duration::from_secs(3), OutputCommand::Off, now);
+        assert_eq!(leases.next_expiry(), Some(now + Duration::from_secs(1)));
+
+        assert!(!leases.expire(now + Duration::from_millis(999)));
+        assert!(leases.expire(now + Duration::from_secs(1)));
+        assert_eq!(leases.command(), Some(OutputCommand::Off));
+        assert!(leases.expire(now + Duration::from_secs(3)));
+        assert_eq!(leases.command(), None);
+        assert_eq!(leases.next_expiry(), None);
+    }
+
+    #[test]
+    fn renewing_extends_a_lease_by_its_ttl() {
+        let now = Instant::now();
+        let mut leases = Leases::default();
+        assert!(!leases.renew("operator", now));
+        leases.acquire("operator", TTL, OutputCommand::On, now);
+
+        let later = now + Duration::from_millis(1500);
+        assert!(leases.renew("operator", later));
+        assert!(!leases.expire(now + TTL));
+        assert_eq!(leases.holders(now + TTL)[0].remaining_ms, 1500);
+        assert!(leases.expire(later + TTL));
+    }
+
+    #[test]
+    fn release_all_drops_every_lease() {
+        let now = Instant::now();
+        let mut leases = Leases::default();
+        assert!(!leases.release_all());
+        leases.acquire("operator", TTL, OutputCommand::On, now);
+        leases.acquire("test", TTL, OutputCommand::On, now);
+
+        assert!(leases.release_all());
+        assert_eq!(leases.command(), None);
+        assert!(leases.holders(now).is_empty());
+    }
+}
@@ -1,59 +1,180 @@
-use afv_internal::{lidar::LidarMsg, network::InternalMessage, LIDAR_PORT, SOCKET_MSG_SIZE};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use afv_internal::{
+    lidar::{AcquisitionSettings, LidarMeasurement, LidarMsg},
+    network::InternalMessage,
+    LIDAR_PORT, SOCKET_MSG_SIZE,
+};
+use futures::future::{BoxFuture, FutureExt};
 use log::debug;
 use serde::{Deserialize, Serialize};
 use tokio::{
     sync::broadcast,
-    time::{sleep, Duration},
+    time::{sleep, Duration, Instant},
 };
 
-use crate::network::{
-    scanner::{ScanBuilder, ScanCount},
-    socket::Socket,
-    NetMessage,
+use crate::{
+    drivers::supervisor::Driver,
+    network::{socket::Socket, NetMessage},
+    shutdown::Shutdown,
 };
 
-pub const POLL_LIDAR_INTERNVAL: Duration = Duration::from_millis(500);
+/// How often the firmware pushes readings once subscribed, by default
+pub const LIDAR_STREAM_INTERVAL_MS: u16 = 100;
+/// A subscription is dropped whenever the socket reconnects, so it is renewed this often
+pub const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(2);
+/// The driver is unhealthy once no reading has arrived for this long
+pub const READING_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many valid readings each sensor's [RangeFilter] keeps by default
+pub const DEFAULT_FILTER_WINDOW: usize = 10;
+/// Readings further than this many median absolute deviations from the median are rejected
+pub const OUTLIER_DEVIATIONS: f32 = 3.0;
+/// The smallest deviation used for rejection, so a window of identical readings does not reject sensor noise
+pub const MIN_DEVIATION_CM: f32 = 5.0;
+/// The sensor mounted with the FLIR camera
+pub const FLIR_LIDAR: u8 = 0;
+/// The sensor mounted on the nozzle turret, on boards that have one
+pub const NOZZLE_LIDAR: u8 = 1;
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+/// A summary of the readings in a sensor's [RangeFilter] window after outliers are rejected
+pub struct FilteredRange {
+    pub sensor: u8,
+    pub median_cm: f32,
+    /// The moving average of the readings that were kept
+    pub mean_cm: f32,
+    pub variance: f32,
+    pub min_cm: u16,
+    pub max_cm: u16,
+    /// The readings the statistics were taken over
+    pub samples: usize,
+    /// The readings in the window that were rejected as outliers
+    pub rejected: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum LidarDriverMessage {
+    /// Requests an extra reading from every sensor outside of the stream
     PollLidar,
-    LidarDistanceCm(u32),
+    /// Changes how a sensor takes its measurements
+    Configure(u8, AcquisitionSettings),
+    /// Whether the sensor accepted its new settings
+    Configured(u8, bool),
+    /// The reading taken by the sensor with the given index, check [LidarMeasurement::valid] before ranging on it
+    Measurement(u8, LidarMeasurement),
+    /// Published after every valid reading, this is what should be ranged on
+    FilteredRange(FilteredRange),
+    /// Changes how many readings each sensor's [RangeFilter] keeps
+    SetFilterWindow(usize),
+}
+
+#[derive(Clone, Debug)]
+/// Keeps a sensor's most recent valid readings and rejects the ones far from their median
+pub struct RangeFilter {
+    window: usize,
+    readings: VecDeque<u16>,
+}
+
+impl RangeFilter {
+    pub fn new(window: usize) -> RangeFilter {
+        let window = window.max(1);
+        Self {
+            window,
+            readings: VecDeque::with_capacity(window),
+        }
+    }
+    /// Drops the oldest readings if the window shrinks
+    pub fn set_window(&mut self, window: usize) {
+        self.window = window.max(1);
+        while self.readings.len() > self.window {
+            self.readings.pop_front();
+        }
+    }
+    pub fn push(&mut self, distance_cm: u16) {
+        if self.readings.len() == self.window {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(distance_cm);
+    }
+    pub fn range(&self, sensor: u8) -> Option<FilteredRange> {
+        let median = median_of(self.readings.iter().map(|r| *r as f32).collect())?;
+        let deviation = median_of(
+            self.readings
+                .iter()
+                .map(|r| (*r as f32 - median).abs())
+                .collect(),
+        )?
+        .max(MIN_DEVIATION_CM);
+        let kept: Vec<u16> = self
+            .readings
+            .iter()
+            .copied()
+            .filter(|r| (*r as f32 - median).abs() <= deviation * OUTLIER_DEVIATIONS)
+            .collect();
+        let samples = kept.len();
+        let mean = kept.iter().map(|r| *r as f32).sum::<f32>() / samples as f32;
+        let variance =
+            kept.iter().map(|r| (*r as f32 - mean).powi(2)).sum::<f32>() / samples as f32;
+        Some(FilteredRange {
+            sensor,
+            median_cm: median,
+            mean_cm: mean,
+            variance,
+            min_cm: kept.iter().copied().min()?,
+            max_cm: kept.iter().copied().max()?,
+            samples,
+            rejected: self.readings.len() - samples,
+        })
+    }
+}
+
+fn median_of(mut values: Vec<f32>) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let middle = values.len() / 2;
+    match values.len() % 2 {
+        0 => Some((values[middle - 1] + values[middle]) / 2.0),
+        _ => Some(values[middle]),
+    }
 }
 
 #[derive(Clone)]
 /// The LidarDriver struct manages the connection the the AFV's Lidar. It does this by repeatedly sending distance readings on the main bus
 ///
 /// Port addressing in this sense means that each "Turret" that is run on an Arduino starts its own TCP server
-/// on a specific port. This means that no matter what IP address/Arduino a specific turret is run on it can still be 
+/// on a specific port. This means that no matter what IP address/Arduino a specific turret is run on it can still be
 /// found automatically.
 pub struct LidarDriver {
     net_tx: broadcast::Sender<NetMessage>,
     lidar_socket: Socket,
+    filters: Arc<Mutex<HashMap<u8, RangeFilter>>>,
+    filter_window: Arc<Mutex<usize>>,
+    last_reading: Arc<Mutex<Instant>>,
+    /// How often the firmware is asked to push readings, in ms
+    stream_interval_ms: u16,
 }
 
 impl LidarDriver {
-    /// This function auto connects to the Lidar firmware running on an Arduino and then spawns the monitoring tasks on the bus.
-    pub async fn new(net_tx: broadcast::Sender<NetMessage>) -> Option<Self> {
-        let lidar_socket = match ScanBuilder::default()
-            .scan_count(ScanCount::Infinite)
-            .add_port(LIDAR_PORT)
-            .dispatch()
-            .recv_async()
-            .await
-        {
-            Ok(stream) => Socket::new(stream, false),
-            Err(_) => return None,
-        };
-
-        let lidar = Self {
+    /// This function builds the driver on a socket connected to the Lidar firmware running on an Arduino,
+    /// its monitoring tasks are run by a [DriverSupervisor](crate::drivers::supervisor::DriverSupervisor).
+    pub fn new(
+        net_tx: broadcast::Sender<NetMessage>,
+        lidar_socket: Socket,
+        stream_interval_ms: u16,
+    ) -> Self {
+        Self {
             net_tx,
             lidar_socket,
-        };
-
-        tokio::spawn(lidar.clone().forward_messages_task());
-        tokio::spawn(lidar.clone().poll_lidar_task());
-
-        Some(lidar)
+            filters: Default::default(),
+            filter_window: Arc::new(Mutex::new(DEFAULT_FILTER_WINDOW)),
+            last_reading: Arc::new(Mutex::new(Instant::now())),
+            stream_interval_ms,
+        }
     }
 
     /// This task is responsible for forwarding tasks sent from the host target lidar process to the main bus
@@ -65,24 +186,185 @@ impl LidarDriver {
             }
 
             match InternalMessage::from_msg(&data) {
-                Some(InternalMessage::Lidar(LidarMsg::LidarDistanceCm(distance))) => {
-                    let _ = self.net_tx.send(NetMessage::LidarDriver(
-                        LidarDriverMessage::LidarDistanceCm(distance),
-                    ));
-                    println!("Lidar Distance: {:?} cm", distance);
+                Some(InternalMessage::Lidar(LidarMsg::Measurement(sensor, measurement))) => {
+                    *self.last_reading.lock().unwrap() = Instant::now();
+                    let _ =
+                        self.net_tx
+                            .send(NetMessage::LidarDriver(LidarDriverMessage::Measurement(
+                                sensor,
+                                measurement,
+                            )));
+                    if !measurement.valid {
+                        debug!("Lidar {} invalid reading: {:?}", sensor, measurement);
+                        continue;
+                    }
+                    if let Some(range) = self.filter(sensor, measurement.distance_cm) {
+                        debug!("Lidar {} range: {:?}", sensor, range);
+                        let _ = self.net_tx.send(NetMessage::LidarDriver(
+                            LidarDriverMessage::FilteredRange(range),
+                        ));
+                    }
+                }
+                Some(InternalMessage::Lidar(LidarMsg::Configured(sensor, configured))) => {
+                    let _ =
+                        self.net_tx
+                            .send(NetMessage::LidarDriver(LidarDriverMessage::Configured(
+                                sensor, configured,
+                            )));
                 }
                 _ => {}
             }
         }
     }
-    /// This task is responsible for polling distance from the host target lidar process
-    async fn poll_lidar_task(self) {
+    /// Adds a valid reading to the sensor's filter and returns its new range
+    fn filter(&self, sensor: u8, distance_cm: u16) -> Option<FilteredRange> {
+        let window = *self.filter_window.lock().unwrap();
+        let mut filters = self.filters.lock().unwrap();
+        let filter = filters
+            .entry(sensor)
+            .or_insert_with(|| RangeFilter::new(window));
+        filter.push(distance_cm);
+        filter.range(sensor)
+    }
+    /// This task keeps the firmware pushing readings, rather than polling it for each one
+    async fn subscribe_task(self) {
+        loop {
+            debug!("Subscribing to lidar readings");
+            if let Some(msg) =
+                InternalMessage::Lidar(LidarMsg::Subscribe(self.stream_interval_ms)).to_msg()
+            {
+                let _ = self.lidar_socket.write_data(&msg).await;
+            }
+            sleep(RESUBSCRIBE_INTERVAL).await;
+        }
+    }
+    /// This task forwards lidar commands from the main bus to the firmware
+    async fn command_task(self) {
+        let mut net_rx = self.net_tx.subscribe();
         loop {
-            sleep(POLL_LIDAR_INTERNVAL).await;
-            debug!("Polling lidar for distance");
-            if let Some(msg) = InternalMessage::Lidar(LidarMsg::PollLidar).to_msg() {
-                self.lidar_socket.write_data(&msg).await;
+            let msg = match net_rx.recv().await {
+                Ok(NetMessage::LidarDriver(LidarDriverMessage::PollLidar)) => LidarMsg::PollLidar,
+                Ok(NetMessage::LidarDriver(LidarDriverMessage::Configure(sensor, settings))) => {
+                    LidarMsg::Configure(sensor, settings)
+                }
+                Ok(NetMessage::LidarDriver(LidarDriverMessage::SetFilterWindow(window))) => {
+                    *self.filter_window.lock().unwrap() = window;
+                    for filter in self.filters.lock().unwrap().values_mut() {
+                        filter.set_window(window);
+                    }
+                    continue;
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if let Some(msg) = InternalMessage::Lidar(msg).to_msg() {
+                let _ = self.lidar_socket.write_data(&msg).await;
             }
         }
     }
 }
+
+impl Driver for LidarDriver {
+    /// How often the firmware pushes readings, in ms
+    type Config = u16;
+
+    fn name(_: &u16) -> String {
+        "Lidar".into()
+    }
+    fn port(_: &u16) -> u16 {
+        LIDAR_PORT
+    }
+    fn attach(
+        net_tx: broadcast::Sender<NetMessage>,
+        stream_interval_ms: u16,
+        socket: Socket,
+        _: &Shutdown,
+    ) -> Self {
+        Self::new(net_tx, socket, stream_interval_ms)
+    }
+    fn tasks(&self) -> Vec<BoxFuture<'static, ()>> {
+        vec![
+            self.clone().forward_messages_task().boxed(),
+            self.clone().subscribe_task().boxed(),
+            self.clone().command_task().boxed(),
+        ]
+    }
+    fn healthy(&self) -> bool {
+        self.lidar_socket.is_connected()
+            && self.last_reading.lock().unwrap().elapsed() < READING_TIMEOUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(readings: &[u16]) -> RangeFilter {
+        let mut filter = RangeFilter::new(readings.len());
+        for reading in readings {
+            filter.push(*reading);
+        }
+        filter
+    }
+
+    #[test]
+    fn median_of_odd_and_even_counts() {
+        assert_eq!(median_of(vec![]), None);
+        assert_eq!(median_of(vec![3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median_of(vec![4.0, 1.0, 3.0, 2.0]), Some(2.5));
+    }
+
+    #[test]
+    fn readings_far_from_the_median_are_rejected() {
+        let range = filter(&[100, 102, 98, 101, 99, 500])
+            .range(FLIR_LIDAR)
+            .unwrap();
+        assert_eq!(range.median_cm, 100.5);
+        assert_eq!(range.samples, 5);
+        assert_eq!(range.rejected, 1);
+        assert_eq!(range.mean_cm, 100.0);
+        assert_eq!((range.min_cm, range.max_cm), (98, 102));
+    }
+
+    #[test]
+    fn an_even_window_uses_the_middle_pair_for_both_medians() {
+        // Median 135 and deviation 20, so readings up to 60 from the median are kept
+        let range = filter(&[100, 110, 120, 130, 140, 150, 160, 400])
+            .range(FLIR_LIDAR)
+            .unwrap();
+        assert_eq!(range.median_cm, 135.0);
+        assert_eq!(range.samples, 7);
+        assert_eq!(range.rejected, 1);
+        assert_eq!((range.min_cm, range.max_cm), (100, 160));
+    }
+
+    #[test]
+    fn identical_readings_fall_back_to_the_minimum_deviation() {
+        let range = filter(&[100; 10]).range(FLIR_LIDAR).unwrap();
+        assert_eq!(range.samples, 10);
+        assert_eq!(range.variance, 0.0);
+
+        // With no spread the deviation is MIN_DEVIATION_CM, keeping noise within 15 cm
+        let mut noisy = filter(&[100; 10]);
+        noisy.set_window(12);
+        noisy.push(110);
+        noisy.push(120);
+        let range = noisy.range(FLIR_LIDAR).unwrap();
+        assert_eq!(range.samples, 11);
+        assert_eq!(range.rejected, 1);
+        assert_eq!(range.max_cm, 110);
+    }
+
+    #[test]
+    fn the_window_keeps_the_latest_readings() {
+        let mut filter = RangeFilter::new(3);
+        assert_eq!(filter.range(FLIR_LIDAR), None);
+        for reading in [100, 200, 300, 400] {
+            filter.push(reading);
+        }
+        assert_eq!(filter.range(FLIR_LIDAR).unwrap().min_cm, 200);
+        filter.set_window(1);
+        let range = filter.range(FLIR_LIDAR).unwrap();
+        assert_eq!((range.samples, range.median_cm), (1, 400.0));
+    }
+}
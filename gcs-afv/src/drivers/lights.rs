@@ -1,70 +1,94 @@
-use afv_internal::{LIGHTS_PORT, network::InternalMessage, lights::LightsMsg};
-use log::error;
+use afv_internal::{estop::EStopMsg, LIGHTS_PORT, SOCKET_MSG_SIZE, network::InternalMessage, output::{OutputCommand, OutputMsg, OutputState, LIGHTS_CHANNEL}};
+use futures::future::{BoxFuture, FutureExt};
 use serde::{Serialize, Deserialize};
-use tokio::{sync::broadcast, time::{Instant, interval, Duration, timeout}};
+use tokio::sync::broadcast;
 
-use crate::network::{NetMessage, socket::Socket, scanner::{ScanBuilder, ScanCount}};
-
-pub const LIGHTS_COMMAND_INTERVAL:Duration = Duration::from_secs(1);
+use crate::{drivers::{estop::{BoardLatch, EmergencyStop}, lease::{LeaseMessage, LeasedOutput}, supervisor::Driver}, network::{NetMessage, socket::Socket}, shutdown::Shutdown};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum LightsDriverMessage{
     TurnOn,
+    /// Keeps the lights running the command, such as a pattern, for as long as this is repeated, through the [keepalive](crate::drivers::lease::KEEPALIVE_HOLDER) lease
+    Hold(OutputCommand),
+    /// Takes, renews or releases a lease on the lights, which run while any lease is held
+    Lease(LeaseMessage),
+    /// What the board reports the lights doing, sent when the driver connects and whenever it changes
+    State(OutputState),
 }
 
 #[derive(Clone)]
 pub struct LightsDriver{
     net_tx: broadcast::Sender<NetMessage>,
     light_socket: Socket,
+    board_latch: BoardLatch,
 }
 
 impl LightsDriver{
-    pub async fn new(net_tx: broadcast::Sender<NetMessage>) -> Option<Self>{
-        
-        let light_socket = match ScanBuilder::default().scan_count(ScanCount::Infinite).add_port(LIGHTS_PORT).dispatch().recv_async().await{
-            Ok(stream) => {
-                Socket::new(stream, false)
-            },
-            Err(_) => return None,
-        };
-
+    /// Builds the driver on a socket connected to the lights, their task is run by a [DriverSupervisor](crate::drivers::supervisor::DriverSupervisor)
+    pub fn new(net_tx: broadcast::Sender<NetMessage>, light_socket: Socket, shutdown: &Shutdown) -> Self{
+        let estop = EmergencyStop::new(net_tx.clone(), shutdown.clone());
+        let board_latch = estop.forward_to(light_socket.clone());
         let lights = Self{
             net_tx,
             light_socket,
+            board_latch,
         };
 
-        tokio::spawn(lights.clone().forward_messages_task());
-        tokio::spawn(lights.clone().command_lights_task());
+        LeasedOutput::new(lights.net_tx.clone(), lights.light_socket.clone(), LIGHTS_CHANNEL, lights_lease, |lease| NetMessage::LightDriver(LightsDriverMessage::Lease(lease)), Some(estop.permit()), shutdown);
 
-        Some(lights)
+        lights
     }
     async fn forward_messages_task(self){
-        
-    }
-    async fn command_lights_task(self){
-        let mut net_rx = self.net_tx.subscribe();
-        let mut last_cmd = Instant::now();
-        let mut interval = interval(LIGHTS_COMMAND_INTERVAL);
-
         loop{
-            if let Ok(Ok(NetMessage::LightDriver(LightsDriverMessage::TurnOn))) = timeout(LIGHTS_COMMAND_INTERVAL + Duration::from_secs(1), net_rx.recv()).await{
-                last_cmd = Instant::now();
+            let mut data = [0u8; SOCKET_MSG_SIZE];
+            for i in 0..data.len(){
+                data[i] = self.light_socket.read_byte().await;
             }
-            net_rx = self.net_tx.subscribe();
-            interval.tick().await;
 
-            if Instant::now().duration_since(last_cmd) < LIGHTS_COMMAND_INTERVAL{
-                if let Some(msg) = InternalMessage::Lights(LightsMsg::TurnOn).to_msg(){
-                    error!("Turning lights on");
-                    self.light_socket.write_data(&msg).await;
+            match InternalMessage::from_msg(&data){
+                Some(InternalMessage::Output(OutputMsg::State(LIGHTS_CHANNEL, state))) => {
+                    let _ = self.net_tx.send(NetMessage::LightDriver(LightsDriverMessage::State(state)));
                 }
-                continue;
+                Some(InternalMessage::EmergencyStop(EStopMsg::State(latched))) => self.board_latch.state(latched),
+                _ => {}
             }
+        }
+    }
+}
+
+impl Driver for LightsDriver{
+    type Config = ();
 
-            if let Some(msg) = InternalMessage::Lights(LightsMsg::TurnOff).to_msg(){
-                self.light_socket.write_data(&msg).await;
+    fn name(_: &()) -> String{
+        "Lights".into()
+    }
+    fn port(_: &()) -> u16{
+        LIGHTS_PORT
+    }
+    fn attach(net_tx: broadcast::Sender<NetMessage>, _: (), socket: Socket, shutdown: &Shutdown) -> Self{
+        Self::new(net_tx, socket, shutdown)
+    }
+    fn tasks(&self) -> Vec<BoxFuture<'static, ()>>{
+        vec![self.clone().forward_messages_task().boxed()]
+    }
+    fn healthy(&self) -> bool{
+        self.light_socket.is_connected()
+    }
+    fn shutdown(&self) -> BoxFuture<'static, ()>{
+        let light_socket = self.light_socket.clone();
+        async move{
+            if let Some(msg) = InternalMessage::Output(OutputMsg::Command(LIGHTS_CHANNEL, OutputCommand::Off)).to_msg(){
+                let _ = light_socket.write_data(&msg).await;
             }
+        }.boxed()
+    }
+}
 
-        }
+fn lights_lease(msg: NetMessage) -> Option<LeaseMessage>{
+    match msg{
+        NetMessage::LightDriver(LightsDriverMessage::TurnOn) => Some(LeaseMessage::keepalive(OutputCommand::On)),
+        NetMessage::LightDriver(LightsDriverMessage::Hold(command)) => Some(LeaseMessage::keepalive(command)),
+        NetMessage::LightDriver(LightsDriverMessage::Lease(lease)) => Some(lease),
+        _ => None,
     }
-}
\ No newline at end of file
+}
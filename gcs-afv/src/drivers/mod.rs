@@ -15,3 +15,23 @@ pub mod siren;
 
 /// This driver controls the lights.
 pub mod lights;
+
+/// This module latches the vehicle wide emergency stop every driver honours and passes it on to the AFV-INTERNAL boards.
+pub mod estop;
+
+/// This module holds the leases the pump, lights and siren drivers keep their outputs on with.
+pub mod lease;
+
+/// This driver talks to the board level system service every AFV-INTERNAL board runs,
+/// which is used to manage each board's stored configuration.
+pub mod system;
+
+/// This driver forwards the logs every AFV-INTERNAL board buffers onto the bus and into the host's log output.
+pub mod board_log;
+
+/// This module loads firmware images that can be pushed to AFV-INTERNAL boards through the [system] driver.
+pub mod firmware;
+
+/// This module holds the [Driver](supervisor::Driver) lifecycle every MCU driver shares and the supervisor
+/// that keeps drivers running and publishes their connection state.
+pub mod supervisor;
@@ -1,67 +1,257 @@
-use afv_internal::{PUMP_PORT, network::InternalMessage, pump::PumpMsg};
+use std::sync::{Arc, Mutex};
+
+use afv_internal::{PUMP_PORT, SOCKET_MSG_SIZE, estop::EStopMsg, network::InternalMessage, output::{OutputCommand, OutputMsg, OutputState, PUMP_CHANNEL}, pump::{PumpMsg, PumpStatus}};
+use futures::future::{BoxFuture, FutureExt};
+use log::{info, error};
 use serde::{Serialize, Deserialize};
-use tokio::{sync::broadcast, time::{Instant, Duration, timeout, interval}};
+use tokio::{sync::{broadcast, watch}, time::{Duration, Instant, sleep}};
 
-use crate::network::{NetMessage, socket::Socket, scanner::{ScanBuilder, ScanCount}};
+use crate::{drivers::{estop::{BoardLatch, EmergencyStop}, lease::{LeaseMessage, LeasedOutput}, supervisor::Driver}, operators::pump::PumpOperator, network::{NetMessage, socket::Socket}, shutdown::Shutdown};
 
-pub const PUMP_COMMAND_INTERVAL:u64 = 1;
+/// The default for [PumpDriverConfig::status_interval]
+pub const PUMP_STATUS_INTERVAL: Duration = Duration::from_secs(5);
+/// The driver is unhealthy once the board has missed this many status polls
+pub const MISSED_STATUS_LIMIT: u32 = 3;
+/// The tank is assumed full at startup, send [PumpDriverMessage::RefillTank] when it is filled to a different level
+pub const DEFAULT_TANK_CAPACITY_ML: u32 = 20_000;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum PumpDriverMessage{
     TurnOn,
+    /// Keeps the pump running the command, such as a pattern, for as long as this is repeated, through the [keepalive](crate::drivers::lease::KEEPALIVE_HOLDER) lease
+    Hold(OutputCommand),
+    /// Takes, renews or releases a lease on the pump, which runs while any lease is held.
+    /// The pump is turned off once when the last lease goes, so it does not cut a timed run short
+    Lease(LeaseMessage),
+    /// Runs the pump for the given ms, timed on the board
+    RunFor(u32),
+    /// Runs the pump until about this many ml are dispensed
+    RunVolume(u32),
+    /// The board's run time and volume estimate, along with its pressure and flow readings if it has the sensors
+    Status(PumpStatus),
+    /// Published once when the board shuts the pump off for running dry, which drops every lease on the pump.
+    /// The board refuses to run it again until it is sent [PumpDriverMessage::ClearDryRun] or restarts
+    DryRun,
+    /// Turns the pump off, which clears a dry run so it can be run again
+    ClearDryRun,
+    /// What the board reports the pump's output doing, sent when the driver connects and whenever it changes
+    State(OutputState),
+    /// Published alongside every [PumpDriverMessage::Status]
+    WaterUsage(WaterUsage),
+    /// Resets the remaining water to the given ml
+    RefillTank(u32),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WaterUsage{
+    /// Everything dispensed since the driver started, across board restarts
+    pub used_ml: u32,
+    /// What is estimated to be left in the tank
+    pub remaining_ml: u32,
+}
+
+/// The board's totals restart with it, so usage is counted from the change between reports
+struct UsageTracker{
+    last_total_ml: Option<u32>,
+    usage: WaterUsage,
+}
+
+impl UsageTracker{
+    fn record(&mut self, total_ml: u32) -> WaterUsage{
+        let dispensed = match self.last_total_ml{
+            Some(last) if total_ml >= last => total_ml - last,
+            // The board restarted since the last report
+            Some(_) => total_ml,
+            None => 0,
+        };
+        self.last_total_ml = Some(total_ml);
+        self.usage.used_ml = self.usage.used_ml.saturating_add(dispensed);
+        self.usage.remaining_ml = self.usage.remaining_ml.saturating_sub(dispensed);
+        self.usage
+    }
+}
+
+/// What a [PumpDriver] is started with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PumpDriverConfig{
+    /// How often the board is polled for its status
+    pub status_interval: Duration,
+    /// How often the nozzle turret is polled for its angle, which the [PumpOperator] interlock waits on
+    pub nozzle_poll_interval: Duration,
 }
 
 #[derive(Clone)]
 pub struct PumpDriver{
     net_tx: broadcast::Sender<NetMessage>,
     pump_socket: Socket,
+    usage: Arc<Mutex<UsageTracker>>,
+    dry_run: Arc<Mutex<bool>>,
+    /// When the board last sent anything
+    last_heard: Arc<Mutex<Instant>>,
+    status_interval: Duration,
+    /// From the [PumpOperator] interlock, commands that would run the pump are dropped while it is false
+    permit: watch::Receiver<bool>,
+    board_latch: BoardLatch,
 }
 
 impl PumpDriver{
-    pub async fn new(net_tx: broadcast::Sender<NetMessage>) -> Option<Self>{
-        
-        let pump_socket = match ScanBuilder::default().scan_count(ScanCount::Infinite).add_port(PUMP_PORT).dispatch().recv_async().await{
-            Ok(stream) => {
-                Socket::new(stream, false)
-            },
-            Err(_) => return None,
-        };
+    /// Builds the driver on a socket connected to the pump, its tasks are run by a [DriverSupervisor](crate::drivers::supervisor::DriverSupervisor)
+    pub fn new(net_tx: broadcast::Sender<NetMessage>, pump_socket: Socket, config: PumpDriverConfig, shutdown: &Shutdown) -> Self{
+        let permit = PumpOperator::new(net_tx.clone(), config.nozzle_poll_interval, shutdown).permit();
 
+        let board_latch = EmergencyStop::new(net_tx.clone(), shutdown.clone()).forward_to(pump_socket.clone());
         let pump = Self{
             net_tx,
             pump_socket,
+            usage: Arc::new(Mutex::new(UsageTracker{
+                last_total_ml: None,
+                usage: WaterUsage{ used_ml: 0, remaining_ml: DEFAULT_TANK_CAPACITY_ML },
+            })),
+            dry_run: Arc::new(Mutex::new(false)),
+            last_heard: Arc::new(Mutex::new(Instant::now())),
+            status_interval: config.status_interval,
+            permit,
+            board_latch,
         };
 
-        tokio::spawn(pump.clone().forward_messages_task());
-        tokio::spawn(pump.clone().command_pump_task());
+        LeasedOutput::new(pump.net_tx.clone(), pump.pump_socket.clone(), PUMP_CHANNEL, pump_lease, |lease| NetMessage::PumpDriver(PumpDriverMessage::Lease(lease)), Some(pump.permit.clone()), shutdown);
 
-        Some(pump)
+        pump
     }
 
     async fn forward_messages_task(self){
-        
+        loop{
+            let mut data = [0u8; SOCKET_MSG_SIZE];
+            for i in 0..data.len(){
+                data[i] = self.pump_socket.read_byte().await;
+            }
+
+            let msg = InternalMessage::from_msg(&data);
+            if msg.is_some(){
+                *self.last_heard.lock().unwrap() = Instant::now();
+            }
+            match msg{
+                Some(InternalMessage::Pump(PumpMsg::Status(status))) => {
+                    let usage = self.usage.lock().unwrap().record(status.total_volume_ml);
+                    if !status.running && status.run_ms > 0{
+                        info!("Pump ran {} ms, about {} ml, {} ml left", status.run_ms, status.run_volume_ml, usage.remaining_ml);
+                    }
+                    let was_dry = std::mem::replace(&mut *self.dry_run.lock().unwrap(), status.dry_run);
+                    if status.dry_run && !was_dry{
+                        error!("Pump shut off for running dry, pressure {:?} kPa, flow {:?} ml/min", status.pressure_kpa, status.measured_flow_ml_per_min);
+                        let _ = self.net_tx.send(NetMessage::PumpDriver(PumpDriverMessage::DryRun));
+                    }
+                    let _ = self.net_tx.send(NetMessage::PumpDriver(PumpDriverMessage::Status(status)));
+                    let _ = self.net_tx.send(NetMessage::PumpDriver(PumpDriverMessage::WaterUsage(usage)));
+                }
+                Some(InternalMessage::Output(OutputMsg::State(PUMP_CHANNEL, state))) => {
+                    let _ = self.net_tx.send(NetMessage::PumpDriver(PumpDriverMessage::State(state)));
+                }
+                Some(InternalMessage::EmergencyStop(EStopMsg::State(latched))) => self.board_latch.state(latched),
+                _ => {}
+            }
+        }
+    }
+    async fn poll_status_task(self){
+        loop{
+            sleep(self.status_interval).await;
+            if let Some(msg) = InternalMessage::Pump(PumpMsg::PollStatus).to_msg(){
+                let _ = self.pump_socket.write_data(&msg).await;
+            }
+        }
     }
     async fn command_pump_task(self){
         let mut net_rx = self.net_tx.subscribe();
-        let mut last_cmd = Instant::now();
-        let mut interval = interval(Duration::from_secs(PUMP_COMMAND_INTERVAL));
 
         loop{
-            if let Ok(Ok(NetMessage::PumpDriver(PumpDriverMessage::TurnOn))) = timeout(Duration::from_secs(PUMP_COMMAND_INTERVAL + 1), net_rx.recv()).await{
-                last_cmd = Instant::now();
+            // Timed runs end themselves on the board, so they are sent straight away rather than leased
+            let run = match net_rx.recv().await{
+                Ok(NetMessage::PumpDriver(PumpDriverMessage::RunFor(ms))) => PumpMsg::RunFor(ms),
+                Ok(NetMessage::PumpDriver(PumpDriverMessage::RunVolume(ml))) => PumpMsg::RunVolume(ml),
+                // Sent whatever the permit, as it only turns the pump off
+                Ok(NetMessage::PumpDriver(PumpDriverMessage::ClearDryRun)) => {
+                    info!("Clearing the pump's dry run");
+                    if let Some(msg) = InternalMessage::Pump(PumpMsg::TurnOff).to_msg(){
+                        if let Err(e) = self.pump_socket.write_data(&msg).await{
+                            error!("Pump dry run not cleared: {}", e);
+                        }
+                    }
+                    continue;
+                }
+                Ok(NetMessage::PumpDriver(PumpDriverMessage::RefillTank(ml))) => {
+                    self.usage.lock().unwrap().usage.remaining_ml = ml;
+                    continue;
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            // The PumpOperator publishes why
+            if !*self.permit.borrow(){
+                continue;
             }
-            interval.tick().await;
-
-            if Instant::now().duration_since(last_cmd) < Duration::from_secs(PUMP_COMMAND_INTERVAL){
-                if let Some(msg) = InternalMessage::Pump(PumpMsg::TurnOn).to_msg(){
-                    self.pump_socket.write_data(&msg).await;
+            if let Some(msg) = InternalMessage::Pump(run).to_msg(){
+                if let Err(e) = self.pump_socket.write_data(&msg).await{
+                    error!("Pump run dropped: {}", e);
                 }
+            }
+        }
+    }
+    /// Leases are dropped by the LeasedOutput, this also stops runs the board is timing
+    async fn interlock_task(mut self){
+        loop{
+            if self.permit.changed().await.is_err(){
+                return;
+            }
+            if *self.permit.borrow(){
                 continue;
             }
-
             if let Some(msg) = InternalMessage::Pump(PumpMsg::TurnOff).to_msg(){
-                self.pump_socket.write_data(&msg).await;
+                let _ = self.pump_socket.write_data(&msg).await;
             }
         }
     }
-}
\ No newline at end of file
+}
+
+impl Driver for PumpDriver{
+    type Config = PumpDriverConfig;
+
+    fn name(_: &PumpDriverConfig) -> String{
+        "Pump".into()
+    }
+    fn port(_: &PumpDriverConfig) -> u16{
+        PUMP_PORT
+    }
+    fn attach(net_tx: broadcast::Sender<NetMessage>, config: PumpDriverConfig, socket: Socket, shutdown: &Shutdown) -> Self{
+        Self::new(net_tx, socket, config, shutdown)
+    }
+    fn tasks(&self) -> Vec<BoxFuture<'static, ()>>{
+        vec![
+            self.clone().forward_messages_task().boxed(),
+            self.clone().interlock_task().boxed(),
+            self.clone().poll_status_task().boxed(),
+            self.clone().command_pump_task().boxed(),
+        ]
+    }
+    fn healthy(&self) -> bool{
+        self.pump_socket.is_connected() && self.last_heard.lock().unwrap().elapsed() < self.status_interval * MISSED_STATUS_LIMIT
+    }
+    fn shutdown(&self) -> BoxFuture<'static, ()>{
+        let pump_socket = self.pump_socket.clone();
+        async move{
+            if let Some(msg) = InternalMessage::Pump(PumpMsg::TurnOff).to_msg(){
+                let _ = pump_socket.write_data(&msg).await;
+            }
+        }.boxed()
+    }
+}
+
+fn pump_lease(msg: NetMessage) -> Option<LeaseMessage>{
+    match msg{
+        NetMessage::PumpDriver(PumpDriverMessage::TurnOn) => Some(LeaseMessage::keepalive(OutputCommand::On)),
+        NetMessage::PumpDriver(PumpDriverMessage::Hold(command)) => Some(LeaseMessage::keepalive(command)),
+        NetMessage::PumpDriver(PumpDriverMessage::Lease(lease)) => Some(lease),
+        // The board latches a dry run, leases refreshing the pump would only be refused
+        NetMessage::PumpDriver(PumpDriverMessage::DryRun) => Some(LeaseMessage::ReleaseAll),
+        _ => None,
+    }
+}
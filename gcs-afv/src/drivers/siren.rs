@@ -1,66 +1,94 @@
-use afv_internal::{SIREN_PORT, network::InternalMessage};
+use afv_internal::{estop::EStopMsg, SIREN_PORT, SOCKET_MSG_SIZE, network::InternalMessage, output::{OutputCommand, OutputMsg, OutputState, SIREN_CHANNEL}};
+use futures::future::{BoxFuture, FutureExt};
 use serde::{Serialize, Deserialize};
-use tokio::{sync::broadcast, time::{Instant, interval, Duration, timeout}};
+use tokio::sync::broadcast;
 
-use crate::network::{NetMessage, socket::Socket, scanner::{ScanBuilder, ScanCount}};
-
-pub const SIREN_COMMAND_INTERVAL: u64 = 1;
+use crate::{drivers::{estop::{BoardLatch, EmergencyStop}, lease::{LeaseMessage, LeasedOutput}, supervisor::Driver}, network::{NetMessage, socket::Socket}, shutdown::Shutdown};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum SirenDriverMessage{
     TurnOn,
+    /// Keeps the siren running the command, such as a pattern, for as long as this is repeated, through the [keepalive](crate::drivers::lease::KEEPALIVE_HOLDER) lease
+    Hold(OutputCommand),
+    /// Takes, renews or releases a lease on the siren, which runs while any lease is held
+    Lease(LeaseMessage),
+    /// What the board reports the siren doing, sent when the driver connects and whenever it changes
+    State(OutputState),
 }
 
 #[derive(Clone)]
 pub struct SirenDriver{
     net_tx: broadcast::Sender<NetMessage>,
     light_socket: Socket,
+    board_latch: BoardLatch,
 }
 
 impl SirenDriver{
-    pub async fn new(net_tx: broadcast::Sender<NetMessage>) -> Option<Self>{
-        
-        let siren_socket = match ScanBuilder::default().scan_count(ScanCount::Infinite).add_port(SIREN_PORT).dispatch().recv_async().await{
-            Ok(stream) => {
-                Socket::new(stream, false)
-            },
-            Err(_) => return None,
-        };
-
+    /// Builds the driver on a socket connected to the siren, its task is run by a [DriverSupervisor](crate::drivers::supervisor::DriverSupervisor)
+    pub fn new(net_tx: broadcast::Sender<NetMessage>, siren_socket: Socket, shutdown: &Shutdown) -> Self{
+        let estop = EmergencyStop::new(net_tx.clone(), shutdown.clone());
+        let board_latch = estop.forward_to(siren_socket.clone());
         let siren = Self{
             net_tx,
             light_socket: siren_socket,
+            board_latch,
         };
 
-        tokio::spawn(siren.clone().forward_messages_task());
-        tokio::spawn(siren.clone().command_siren_task());
+        LeasedOutput::new(siren.net_tx.clone(), siren.light_socket.clone(), SIREN_CHANNEL, siren_lease, |lease| NetMessage::SirenDriver(SirenDriverMessage::Lease(lease)), Some(estop.permit()), shutdown);
 
-        Some(siren)
+        siren
     }
     async fn forward_messages_task(self){
-        
-    }
-    async fn command_siren_task(self){
-        let mut net_rx = self.net_tx.subscribe();
-        let mut last_cmd = Instant::now();
-        let mut interval = interval(Duration::from_secs(SIREN_COMMAND_INTERVAL));
-
         loop{
-            if let Ok(Ok(NetMessage::SirenDriver(SirenDriverMessage::TurnOn))) = timeout(Duration::from_secs(SIREN_COMMAND_INTERVAL + 1), net_rx.recv()).await{
-                last_cmd = Instant::now();
+            let mut data = [0u8; SOCKET_MSG_SIZE];
+            for i in 0..data.len(){
+                data[i] = self.light_socket.read_byte().await;
             }
-            interval.tick().await;
 
-            if Instant::now().duration_since(last_cmd) < Duration::from_secs(SIREN_COMMAND_INTERVAL){
-                if let Some(msg) = InternalMessage::Siren(afv_internal::sirens::SirenMsg::TurnOn).to_msg(){
-                    self.light_socket.write_data(&msg).await;
+            match InternalMessage::from_msg(&data){
+                Some(InternalMessage::Output(OutputMsg::State(SIREN_CHANNEL, state))) => {
+                    let _ = self.net_tx.send(NetMessage::SirenDriver(SirenDriverMessage::State(state)));
                 }
-                continue;
+                Some(InternalMessage::EmergencyStop(EStopMsg::State(latched))) => self.board_latch.state(latched),
+                _ => {}
             }
+        }
+    }
+}
 
-            if let Some(msg) = InternalMessage::Siren(afv_internal::sirens::SirenMsg::TurnOff).to_msg(){
-                self.light_socket.write_data(&msg).await;
+impl Driver for SirenDriver{
+    type Config = ();
+
+    fn name(_: &()) -> String{
+        "Siren".into()
+    }
+    fn port(_: &()) -> u16{
+        SIREN_PORT
+    }
+    fn attach(net_tx: broadcast::Sender<NetMessage>, _: (), socket: Socket, shutdown: &Shutdown) -> Self{
+        Self::new(net_tx, socket, shutdown)
+    }
+    fn tasks(&self) -> Vec<BoxFuture<'static, ()>>{
+        vec![self.clone().forward_messages_task().boxed()]
+    }
+    fn healthy(&self) -> bool{
+        self.light_socket.is_connected()
+    }
+    fn shutdown(&self) -> BoxFuture<'static, ()>{
+        let light_socket = self.light_socket.clone();
+        async move{
+            if let Some(msg) = InternalMessage::Output(OutputMsg::Command(SIREN_CHANNEL, OutputCommand::Off)).to_msg(){
+                let _ = light_socket.write_data(&msg).await;
             }
-        }
+        }.boxed()
     }
-}
\ No newline at end of file
+}
+
+fn siren_lease(msg: NetMessage) -> Option<LeaseMessage>{
+    match msg{
+        NetMessage::SirenDriver(SirenDriverMessage::TurnOn) => Some(LeaseMessage::keepalive(OutputCommand::On)),
+        NetMessage::SirenDriver(SirenDriverMessage::Hold(command)) => Some(LeaseMessage::keepalive(command)),
+        NetMessage::SirenDriver(SirenDriverMessage::Lease(lease)) => Some(lease),
+        _ => None,
+    }
+}
@@ -0,0 +1,205 @@
+use std::marker::PhantomData;
+
+use futures::future::{select_all, BoxFuture, FutureExt};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::broadcast,
+    task::JoinHandle,
+    time::{interval, sleep, Duration, Instant},
+};
+
+use crate::{
+    network::{discovery::Discovery, socket::Socket, NetMessage},
+    shutdown::Shutdown,
+};
+
+/// How often each driver's health is checked and its [ConnectionState] published
+pub const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+/// The wait before the first reconnect or restart, doubled for each one after it
+pub const MIN_BACKOFF: Duration = Duration::from_millis(500);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Restarts are forgotten after this long
+pub const RESTART_WINDOW: Duration = Duration::from_secs(60);
+/// A driver restarted more often than this within [RESTART_WINDOW] is reported [ConnectionState::Lost]
+pub const MAX_RESTARTS: usize = 5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The driver's MCU has not been found yet
+    Searching,
+    Connected,
+    /// The driver is unhealthy, or one of its tasks was restarted within [RESTART_WINDOW]
+    Degraded,
+    /// The driver's tasks keep failing. It is shut down when it becomes lost, and its tasks are still restarted
+    Lost,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SupervisorMessage {
+    /// Published by a driver's supervisor every [HEALTH_INTERVAL] and whenever it changes, along with the driver's name
+    ConnectionState(String, ConnectionState),
+}
+
+/// The lifecycle every driver that talks to one MCU service shares, run by a [DriverSupervisor]
+pub trait Driver: Clone + Send + Sync + 'static {
+    /// What the driver needs to run, such as which of several boards it talks to
+    type Config: Clone + Send + Sync + 'static;
+
+    /// Names the driver in logs and in its [ConnectionState]
+    fn name(config: &Self::Config) -> String;
+    /// The port of the MCU service the driver talks to, which is how [Discovery] finds it
+    fn port(config: &Self::Config) -> u16;
+    /// Builds the driver on the connection to its MCU. Any helper tasks the driver spawns itself go through the shutdown
+    fn attach(
+        net_tx: broadcast::Sender<NetMessage>,
+        config: Self::Config,
+        socket: Socket,
+        shutdown: &Shutdown,
+    ) -> Self;
+    /// The driver's long running tasks. Each is spawned by the supervisor, which takes a fresh one from here
+    /// to restart it with if it ends or panics
+    fn tasks(&self) -> Vec<BoxFuture<'static, ()>>;
+    /// Whether the MCU is connected and answering, the driver is [ConnectionState::Degraded] while it is not
+    fn healthy(&self) -> bool {
+        true
+    }
+    /// Puts whatever the driver controls in a safe state, when it is lost and when the AFV shuts down
+    fn shutdown(&self) -> BoxFuture<'static, ()> {
+        async {}.boxed()
+    }
+}
+
+/// Doubles from [MIN_BACKOFF] for each attempt up to [MAX_BACKOFF]
+fn backoff(attempt: usize) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(1 << attempt.min(16) as u32)
+        .min(MAX_BACKOFF)
+}
+
+/// Connects a [Driver] to the MCU [Discovery] finds for it and keeps its tasks running,
+/// restarting any that end with a backoff and publishing the driver's [ConnectionState] on the bus.
+///
+/// Once the [Shutdown] is triggered the driver's tasks are stopped and the driver is put in a safe state.
+pub struct DriverSupervisor<D: Driver> {
+    net_tx: broadcast::Sender<NetMessage>,
+    discovery: Discovery,
+    shutdown: Shutdown,
+    name: String,
+    state: Option<ConnectionState>,
+    driver: PhantomData<D>,
+}
+
+impl<D: Driver> DriverSupervisor<D> {
+    pub fn spawn(
+        net_tx: broadcast::Sender<NetMessage>,
+        discovery: Discovery,
+        shutdown: Shutdown,
+        config: D::Config,
+    ) {
+        let supervisor = Self {
+            net_tx,
+            discovery,
+            shutdown: shutdown.clone(),
+            name: D::name(&config),
+            state: None,
+            driver: PhantomData,
+        };
+
+        shutdown.track(tokio::spawn(supervisor.supervise_task(config)));
+    }
+    fn publish(&mut self, state: ConnectionState) {
+        if self.state != Some(state) {
+            match state {
+                ConnectionState::Connected => info!("{} is connected", self.name),
+                ConnectionState::Lost => error!("{} is lost", self.name),
+                _ => warn!("{} is {:?}", self.name, state),
+            }
+            self.state = Some(state);
+        }
+        let _ = self
+            .net_tx
+            .send(NetMessage::Supervisor(SupervisorMessage::ConnectionState(
+                self.name.clone(),
+                state,
+            )));
+    }
+    async fn connect(&mut self, config: D::Config) -> D {
+        self.publish(ConnectionState::Searching);
+        let port = D::port(&config);
+        let mut attempt = 0;
+        loop {
+            if let Some(stream) = self.discovery.find(port).await {
+                info!("{} connected to MCU", self.name);
+                let socket = Socket::new(stream, false);
+                return D::attach(self.net_tx.clone(), config, socket, &self.shutdown);
+            }
+            warn!("{} could not find its MCU", self.name);
+            sleep(backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+    async fn supervise_task(mut self, config: D::Config) {
+        let shutdown = self.shutdown.clone();
+        let driver = tokio::select! {
+            driver = self.connect(config) => driver,
+            _ = shutdown.triggered() => return,
+        };
+        let mut tasks: Vec<JoinHandle<()>> = driver.tasks().into_iter().map(tokio::spawn).collect();
+        if tasks.is_empty() {
+            return;
+        }
+        self.publish(ConnectionState::Connected);
+
+        let mut restarts: Vec<Instant> = vec![];
+        let mut health = interval(HEALTH_INTERVAL);
+        loop {
+            let ended = tokio::select! {
+                (result, index, _) = select_all(tasks.iter_mut()) => Some((result, index)),
+                _ = health.tick() => None,
+                _ = shutdown.triggered() => break,
+            };
+
+            let now = Instant::now();
+            restarts.retain(|at| now.duration_since(*at) < RESTART_WINDOW);
+            let ended = ended.map(|(result, index)| {
+                match result {
+                    Err(e) if e.is_panic() => error!("{} task {} panicked", self.name, index),
+                    _ => warn!("{} task {} ended", self.name, index),
+                }
+                restarts.push(now);
+                index
+            });
+
+            let state = if restarts.len() > MAX_RESTARTS {
+                ConnectionState::Lost
+            } else if !restarts.is_empty() || !driver.healthy() {
+                ConnectionState::Degraded
+            } else {
+                ConnectionState::Connected
+            };
+            let lost = state == ConnectionState::Lost && self.state != Some(state);
+            self.publish(state);
+
+            if let Some(index) = ended {
+                if lost {
+                    driver.shutdown().await;
+                }
+                tokio::select! {
+                    _ = sleep(backoff(restarts.len() - 1)) => {}
+                    _ = shutdown.triggered() => break,
+                }
+                if let Some(task) = driver.tasks().into_iter().nth(index) {
+                    tasks[index] = tokio::spawn(task);
+                }
+            }
+        }
+
+        for task in tasks {
+            task.abort();
+            let _ = task.await;
+        }
+        driver.shutdown().await;
+        info!("{} is shut down", self.name);
+    }
+}
@@ -0,0 +1,231 @@
+use std::{net::IpAddr, sync::Arc};
+
+use afv_internal::{
+    config::{BoardConfig, ConfigMsg},
+    network::InternalMessage,
+    scheduler::LoopStats,
+    update::{UpdateMsg, UpdateState, PAGE_SIZE, PAGE_SLICES, PAGE_SLICE_SIZE},
+    SOCKET_MSG_SIZE, SYSTEM_PORT,
+};
+use log::{debug, info};
+use tokio::{
+    net::TcpStream,
+    task::JoinHandle,
+    time::{timeout, Duration},
+};
+
+use crate::network::{
+    scanner::{ScanBuilder, ScanCount},
+    socket::Socket,
+};
+
+pub const SYSTEM_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+/// The SystemClient talks to the System service that every AFV-INTERNAL board runs on [SYSTEM_PORT].
+///
+/// Boards are usually found through the port of a service they run, so the board running the nozzle turret
+/// can be managed without knowing its IP address.
+pub struct SystemClient {
+    ip: IpAddr,
+    system_socket: Socket,
+    /// Whole messages from the board, read by a task of their own so a request timing out never splits a frame
+    message_rx: flume::Receiver<InternalMessage>,
+    _reader: Arc<ReaderTask>,
+}
+
+/// Stops reading the board's messages once the last clone of the client is dropped
+struct ReaderTask(JoinHandle<()>);
+
+impl Drop for ReaderTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl SystemClient {
+    /// Connects directly to the system service of the board at `ip`
+    pub async fn connect(ip: IpAddr) -> Option<Self> {
+        let stream = match TcpStream::connect((ip, SYSTEM_PORT)).await {
+            Ok(s) => s,
+            Err(_) => return None,
+        };
+        info!("System client connected to board {}", ip);
+        let system_socket = Socket::new(stream, false);
+        let (message_tx, message_rx) = flume::unbounded();
+        let reader = tokio::spawn(Self::read_messages_task(system_socket.clone(), message_tx));
+        Some(Self {
+            ip,
+            system_socket,
+            message_rx,
+            _reader: Arc::new(ReaderTask(reader)),
+        })
+    }
+    /// Scans for the board running the service on `service_port` and connects to its system service
+    pub async fn find(service_port: u16) -> Option<Self> {
+        let stream = match ScanBuilder::default()
+            .scan_count(ScanCount::Infinite)
+            .add_port(service_port)
+            .dispatch()
+            .recv_async()
+            .await
+        {
+            Ok(stream) => stream,
+            Err(_) => return None,
+        };
+        let ip = match stream.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(_) => return None,
+        };
+        debug!("Board running port {} found at {}", service_port, ip);
+        // The service socket is only used to find the board
+        drop(stream);
+        Self::connect(ip).await
+    }
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+    /// Returns the configuration the board is currently running
+    pub async fn read_config(&self) -> Option<BoardConfig> {
+        match self.config_request(ConfigMsg::Read).await {
+            Some(ConfigMsg::Current(config)) => Some(config),
+            _ => None,
+        }
+    }
+    /// Returns the configuration staged on the board, which changes should be made to so earlier
+    /// changes that are waiting on a restart are kept
+    pub async fn read_staged_config(&self) -> Option<BoardConfig> {
+        match self.config_request(ConfigMsg::ReadStaged).await {
+            Some(ConfigMsg::Current(config)) => Some(config),
+            _ => None,
+        }
+    }
+    /// Stages a new configuration on the board, returning the configuration the board staged
+    pub async fn write_config(&self, config: BoardConfig) -> Option<BoardConfig> {
+        match self.config_request(ConfigMsg::Write(config)).await {
+            Some(ConfigMsg::Current(config)) => Some(config),
+            _ => None,
+        }
+    }
+    /// Persists the staged configuration. It is used the next time the board starts
+    pub async fn commit_config(&self) -> bool {
+        matches!(
+            self.config_request(ConfigMsg::Commit).await,
+            Some(ConfigMsg::Committed(true))
+        )
+    }
+    /// Erases the stored configuration so the board falls back to its firmware defaults
+    pub async fn erase_config(&self) -> bool {
+        matches!(
+            self.config_request(ConfigMsg::Erase).await,
+            Some(ConfigMsg::Committed(true))
+        )
+    }
+    /// Returns the timing of the board's main loop
+    pub async fn loop_stats(&self) -> Option<LoopStats> {
+        let data = InternalMessage::PollLoopStats.to_msg()?;
+        self.send(&data).await?;
+
+        let response = async {
+            loop {
+                if let InternalMessage::LoopStats(stats) = self.read_message().await {
+                    return stats;
+                }
+            }
+        };
+        timeout(SYSTEM_REQUEST_TIMEOUT, response).await.ok()
+    }
+
+    /// Starts staging a firmware image of `len` bytes
+    pub async fn begin_update(&self, len: u16, crc: u16) -> Option<UpdateMsg> {
+        self.update_request(UpdateMsg::Begin { len, crc }).await
+    }
+    /// Writes one page of the image being staged, pages must be written in order
+    pub async fn write_page(&self, page: u16, data: &[u8; PAGE_SIZE]) -> Option<UpdateMsg> {
+        let mut slices = [[0u8; PAGE_SLICE_SIZE]; PAGE_SLICES];
+        for (slice, chunk) in slices.iter_mut().zip(data.chunks(PAGE_SLICE_SIZE)) {
+            slice.copy_from_slice(chunk);
+        }
+        let crc = afv_internal::config::crc16(data);
+        self.update_request(UpdateMsg::Page {
+            page,
+            data: slices,
+            crc,
+        })
+        .await
+    }
+    /// Hands the staged image to the bootloader. The board restarts without answering unless the image is rejected
+    pub async fn install_update(&self) -> Option<UpdateMsg> {
+        self.update_request(UpdateMsg::Install).await
+    }
+    pub async fn update_state(&self) -> Option<UpdateState> {
+        match self.update_request(UpdateMsg::Status).await {
+            Some(UpdateMsg::State(state)) => Some(state),
+            _ => None,
+        }
+    }
+    /// Accepts the trial image the board is running so it is not rolled back
+    pub async fn confirm_update(&self) -> Option<UpdateState> {
+        match self.update_request(UpdateMsg::Confirm).await {
+            Some(UpdateMsg::State(state)) => Some(state),
+            _ => None,
+        }
+    }
+
+    async fn update_request(&self, msg: UpdateMsg) -> Option<UpdateMsg> {
+        let data = InternalMessage::Update(msg).to_msg()?;
+        self.send(&data).await?;
+
+        let response = async {
+            loop {
+                if let InternalMessage::Update(response) = self.read_message().await {
+                    if let UpdateMsg::Ack(_) | UpdateMsg::Error(_) | UpdateMsg::State(_) = response
+                    {
+                        return response;
+                    }
+                }
+            }
+        };
+        timeout(SYSTEM_REQUEST_TIMEOUT, response).await.ok()
+    }
+    async fn config_request(&self, msg: ConfigMsg) -> Option<ConfigMsg> {
+        let data = InternalMessage::Config(msg).to_msg()?;
+        self.send(&data).await?;
+
+        let response = async {
+            loop {
+                if let InternalMessage::Config(response) = self.read_message().await {
+                    if let ConfigMsg::Current(_) | ConfigMsg::Committed(_) = response {
+                        return response;
+                    }
+                }
+            }
+        };
+        timeout(SYSTEM_REQUEST_TIMEOUT, response).await.ok()
+    }
+    /// Sends a request, first dropping any answer to an earlier one that timed out
+    async fn send(&self, data: &[u8]) -> Option<()> {
+        let _ = self.message_rx.drain();
+        self.system_socket.write_data(data).await.ok()
+    }
+    /// Waits on the next message from the board, the client's reader task never ends while it is alive
+    async fn read_message(&self) -> InternalMessage {
+        match self.message_rx.recv_async().await {
+            Ok(msg) => msg,
+            Err(_) => std::future::pending().await,
+        }
+    }
+    async fn read_messages_task(system_socket: Socket, message_tx: flume::Sender<InternalMessage>) {
+        loop {
+            let mut data = [0u8; SOCKET_MSG_SIZE];
+            for i in 0..data.len() {
+                data[i] = system_socket.read_byte().await;
+            }
+            if let Some(msg) = InternalMessage::from_msg(&data) {
+                if message_tx.send(msg).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
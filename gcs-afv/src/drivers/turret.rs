@@ -1,20 +1,25 @@
 use afv_internal::{
-    network::InternalMessage, stepper, PAN_STEPPER_STEPS_REV, SOCKET_MSG_SIZE,
+    estop::EStopMsg, network::InternalMessage, stepper, PAN_STEPPER_STEPS_REV, SOCKET_MSG_SIZE,
     TILT_STEPPER_STEPS_REV,
 };
-use log::{debug, error, info};
+use futures::future::{BoxFuture, FutureExt};
+use log::{debug, error};
 use serde::{Deserialize, Serialize};
 use tokio::{
     sync::broadcast,
     time::{sleep, Duration},
 };
 
-use crate::network::{
-    scanner::{ScanBuilder, ScanCount},
-    socket::Socket,
-    NetMessage,
+use crate::{
+    drivers::{
+        estop::{BoardLatch, EmergencyStop},
+        supervisor::Driver,
+    },
+    network::{socket::Socket, NetMessage},
+    shutdown::Shutdown,
 };
 
+/// The default for [TurretDriverConfig::poll_steps_interval]
 pub const POLL_STEPS_INTERVAL: Duration = Duration::from_millis(1000);
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -24,54 +29,55 @@ pub enum TurretDriverMessage {
     SetAbsoluteAngle(u16, [f32; 2]),
 }
 
+/// What a [TurretDriver] is started with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TurretDriverConfig {
+    /// The port the turret's firmware listens on, which is also the turret's address on the bus
+    pub port: u16,
+    /// How often the turret is polled for its angle
+    pub poll_steps_interval: Duration,
+}
+
 #[derive(Clone)]
 /// The TurretDriver is the struct that connects to a particular port addressed turret on the AFV
 /// and sends command to the arduino running the turret's control firmware.
 ///
 /// Port addressing in this sense means that each "Turret" that is run on an Arduino starts its own TCP server
-/// on a specific port. This means that no matter what IP address/Arduino a specific turret is run on it can still be 
+/// on a specific port. This means that no matter what IP address/Arduino a specific turret is run on it can still be
 /// found automatically.
 pub struct TurretDriver {
     port: u16,
+    poll_steps_interval: Duration,
     net_tx: broadcast::Sender<NetMessage>,
     turret_socket: Socket,
+    /// Moves are dropped while it is latched
+    estop: EmergencyStop,
+    board_latch: BoardLatch,
 }
 
 impl TurretDriver {
-    /// This functon creates a new turret targeting a specifc port and adds it to the main bus.
-    ///
-    /// * `port` - The target turret port
-    pub async fn new(net_tx: broadcast::Sender<NetMessage>, port: u16) -> Option<Self> {
-        let turret_socket = match ScanBuilder::default()
-            .scan_count(ScanCount::Infinite)
-            .add_port(port)
-            .dispatch()
-            .recv_async()
-            .await
-        {
-            Ok(stream) => Socket::new(stream, false),
-            Err(_) => return None,
-        };
-
-        // let stream = match TcpStream::connect((Ipv4Addr::new(192,168,4,20), port)).await{
-        //     Ok(s) => s,
-        //     _ => return None
-        // };
-        // let turret_socket = Socket::new(stream, false);
-
-        info!("Turret {} connected to MCU", port);
-
-        let turret = Self {
+    /// This functon builds the driver on a socket connected to the turret's port, found by
+    /// [Discovery](crate::network::discovery::Discovery) or given as an endpoint. Its tasks are run by a
+    /// [DriverSupervisor](crate::drivers::supervisor::DriverSupervisor).
+    pub fn new(
+        net_tx: broadcast::Sender<NetMessage>,
+        turret_socket: Socket,
+        config: TurretDriverConfig,
+        shutdown: &Shutdown,
+    ) -> Self {
+        let port = config.port;
+
+        let estop = EmergencyStop::new(net_tx.clone(), shutdown.clone());
+        let board_latch = estop.forward_to(turret_socket.clone());
+
+        Self {
             port,
+            poll_steps_interval: config.poll_steps_interval,
             net_tx,
             turret_socket,
-        };
-
-        tokio::spawn(turret.clone().forward_messages_task());
-        tokio::spawn(turret.clone().poll_steps_task());
-        tokio::spawn(turret.clone().set_steps_task());
-
-        Some(turret)
+            estop,
+            board_latch,
+        }
     }
 
     /// This task is responsible for forwarding tasks sent from the host target turret to the main bus
@@ -98,6 +104,9 @@ impl TurretDriver {
                         )));
                     println!("Steps {:?}", (pan_steps, tilt_steps));
                 }
+                Some(InternalMessage::EmergencyStop(EStopMsg::State(latched))) => {
+                    self.board_latch.state(latched)
+                }
                 Some(InternalMessage::Ping(val)) => {
                     println!("Pinged {}", val);
                 }
@@ -112,12 +121,12 @@ impl TurretDriver {
     /// This task is responsible for frequenlty poll the current step that the target turret is at
     async fn poll_steps_task(self) {
         loop {
-            sleep(POLL_STEPS_INTERVAL).await;
+            sleep(self.poll_steps_interval).await;
             debug!("Polling turret {} for steps", self.port);
             if let Some(msg) =
                 InternalMessage::Turret(afv_internal::turret::TurretMsg::PollSteps).to_msg()
             {
-                self.turret_socket.write_data(&msg).await;
+                let _ = self.turret_socket.write_data(&msg).await;
             }
         }
     }
@@ -153,6 +162,10 @@ impl TurretDriver {
                     if port != self.port {
                         continue;
                     }
+                    if self.estop.latched() {
+                        error!("Turret {} move dropped, E-stop latched", self.port);
+                        continue 'outer;
+                    }
                     if let Some(msg) =
                         InternalMessage::Turret(afv_internal::turret::TurretMsg::SetSteps((
                             stepper::convert_angle_steps(
@@ -166,7 +179,9 @@ impl TurretDriver {
                         )))
                         .to_msg()
                     {
-                        self.turret_socket.write_data(&msg).await;
+                        if let Err(e) = self.turret_socket.write_data(&msg).await {
+                            error!("Turret {} move dropped: {}", self.port, e);
+                        }
                     }
                     continue 'outer;
                 }
@@ -187,6 +202,11 @@ impl TurretDriver {
                 }
             }
 
+            if self.estop.latched() {
+                error!("Turret {} move dropped, E-stop latched", self.port);
+                continue;
+            }
+
             let new_pan_angle = pan_angle + pan_angle_change;
             let new_tilt_angle = tilt_angle + tilt_angle_change;
 
@@ -201,10 +221,41 @@ impl TurretDriver {
             )))
             .to_msg()
             {
-                self.turret_socket.write_data(&msg).await;
+                if let Err(e) = self.turret_socket.write_data(&msg).await {
+                    error!("Turret {} move dropped: {}", self.port, e);
+                }
             }
 
             net_rx = self.net_tx.subscribe();
         }
     }
 }
+
+impl Driver for TurretDriver {
+    type Config = TurretDriverConfig;
+
+    fn name(config: &TurretDriverConfig) -> String {
+        format!("Turret {}", config.port)
+    }
+    fn port(config: &TurretDriverConfig) -> u16 {
+        config.port
+    }
+    fn attach(
+        net_tx: broadcast::Sender<NetMessage>,
+        config: TurretDriverConfig,
+        socket: Socket,
+        shutdown: &Shutdown,
+    ) -> Self {
+        Self::new(net_tx, socket, config, shutdown)
+    }
+    fn tasks(&self) -> Vec<BoxFuture<'static, ()>> {
+        vec![
+            self.clone().forward_messages_task().boxed(),
+            self.clone().poll_steps_task().boxed(),
+            self.clone().set_steps_task().boxed(),
+        ]
+    }
+    fn healthy(&self) -> bool {
+        self.turret_socket.is_connected()
+    }
+}
@@ -0,0 +1,530 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use afv_internal::{
+    board,
+    config::{StepperConfig, TurretConfig},
+    estop::EStopMsg,
+    garmin_lidar_v3::Status,
+    lidar::{LidarMeasurement, LidarMsg, MIN_STREAM_INTERVAL_MS},
+    network::InternalMessage,
+    output::{OutputCommand, OutputMsg, OutputState, LIGHTS_CHANNEL, PUMP_CHANNEL, SIREN_CHANNEL},
+    pump::{PumpMsg, PumpStatus},
+    turret::TurretMsg,
+    FLIR_TURRET_PORT, LIDAR_PORT, LIGHTS_PORT, NOZZLE_TURRET_PORT, PUMP_PORT, SIREN_PORT,
+    SOCKET_MSG_SIZE,
+};
+use log::{debug, info};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::{watch, Mutex as AsyncMutex},
+    time::{sleep, Duration, Instant},
+};
+
+/// The Garmin lidar's rated range
+pub const LIDAR_RANGE_CM: u32 = 4000;
+
+#[derive(Clone, Copy, Debug)]
+/// How the emulated lidar's distance changes over time
+pub struct LidarModel {
+    pub base_cm: u32,
+    /// The distance swings this far either side of the base
+    pub amplitude_cm: u32,
+    pub period: Duration,
+}
+
+impl Default for LidarModel {
+    fn default() -> Self {
+        Self {
+            base_cm: 500,
+            amplitude_cm: 100,
+            period: Duration::from_secs(10),
+        }
+    }
+}
+
+impl LidarModel {
+    pub fn distance_cm(&self, elapsed: Duration) -> u32 {
+        let phase = elapsed.as_secs_f64() / self.period.as_secs_f64().max(f64::EPSILON);
+        let swing = (phase * std::f64::consts::TAU).sin() * self.amplitude_cm as f64;
+        (self.base_cm as f64 + swing).max(0.0) as u32
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmulatedService {
+    FlirTurret,
+    NozzleTurret,
+    Lidar,
+    Pump,
+    Lights,
+    Siren,
+}
+
+impl EmulatedService {
+    pub const ALL: [EmulatedService; 6] = [
+        EmulatedService::FlirTurret,
+        EmulatedService::NozzleTurret,
+        EmulatedService::Lidar,
+        EmulatedService::Pump,
+        EmulatedService::Lights,
+        EmulatedService::Siren,
+    ];
+    /// The port the real firmware serves this service on
+    pub fn port(&self) -> u16 {
+        match self {
+            EmulatedService::FlirTurret => FLIR_TURRET_PORT,
+            EmulatedService::NozzleTurret => NOZZLE_TURRET_PORT,
+            EmulatedService::Lidar => LIDAR_PORT,
+            EmulatedService::Pump => PUMP_PORT,
+            EmulatedService::Lights => LIGHTS_PORT,
+            EmulatedService::Siren => SIREN_PORT,
+        }
+    }
+    /// The output channel the service drives, if it is one
+    pub fn channel(&self) -> Option<u8> {
+        match self {
+            EmulatedService::Pump => Some(PUMP_CHANNEL),
+            EmulatedService::Lights => Some(LIGHTS_CHANNEL),
+            EmulatedService::Siren => Some(SIREN_CHANNEL),
+            _ => None,
+        }
+    }
+}
+
+/// A turret move in progress or finished, the emulated steppers are wherever it has them at the moment
+#[derive(Clone, Copy)]
+struct TurretMotion {
+    from: (i32, i32),
+    to: (i32, i32),
+    started: Instant,
+    /// How long each pan and tilt step takes, zero without [Emulator::motion_timing]
+    step_time: (Duration, Duration),
+}
+
+impl TurretMotion {
+    fn steps(&self) -> (i32, i32) {
+        let elapsed = self.started.elapsed();
+        (
+            stepper_position(self.from.0, self.to.0, self.step_time.0, elapsed),
+            stepper_position(self.from.1, self.to.1, self.step_time.1, elapsed),
+        )
+    }
+}
+
+struct EmulatorState {
+    turrets: HashMap<u16, TurretMotion>,
+    outputs: HashMap<u16, OutputCommand>,
+    lidar: LidarModel,
+    lidar_override: Option<u32>,
+    /// Shared by every service, like the latch on a real board
+    estop: bool,
+}
+
+#[derive(Clone)]
+/// The Emulator stands in for the AFV-INTERNAL boards by serving each MCU service on its real port,
+/// so the drivers and everything above them can run without hardware.
+///
+/// Like the firmware, a turret reports its steps part way through a move and every service takes one connection at a time.
+/// The handle can be cloned to inspect and drive the emulated hardware, which is how integration tests use it.
+pub struct Emulator {
+    bind: Ipv4Addr,
+    motion_timing: bool,
+    started: Instant,
+    state: Arc<Mutex<EmulatorState>>,
+}
+
+impl Emulator {
+    pub fn new(lidar: LidarModel) -> Emulator {
+        Self {
+            bind: Ipv4Addr::UNSPECIFIED,
+            motion_timing: true,
+            started: Instant::now(),
+            state: Arc::new(Mutex::new(EmulatorState {
+                turrets: HashMap::new(),
+                outputs: HashMap::new(),
+                lidar,
+                lidar_override: None,
+                estop: false,
+            })),
+        }
+    }
+    /// The address the services listen on, all interfaces by default so the scanner can find them
+    pub fn bind(mut self, bind: Ipv4Addr) -> Emulator {
+        self.bind = bind;
+        self
+    }
+    /// When disabled turrets reach their target as soon as they are sent it
+    pub fn motion_timing(mut self, motion_timing: bool) -> Emulator {
+        self.motion_timing = motion_timing;
+        self
+    }
+    /// Starts serving every service, returning once all of their ports are bound
+    pub async fn start(&self) -> std::io::Result<()> {
+        self.start_services(&EmulatedService::ALL).await
+    }
+    pub async fn start_services(&self, services: &[EmulatedService]) -> std::io::Result<()> {
+        for &service in services {
+            let listener = TcpListener::bind((self.bind, service.port())).await?;
+            info!("Emulating {:?} on port {}", service, service.port());
+            tokio::spawn(self.clone().serve_task(service, listener));
+        }
+        Ok(())
+    }
+
+    pub fn turret_steps(&self, service: EmulatedService) -> (i32, i32) {
+        let state = self.state.lock().unwrap();
+        state
+            .turrets
+            .get(&service.port())
+            .map(|motion| motion.steps())
+            .unwrap_or_default()
+    }
+    /// Whether the pump, lights or siren output pin is driven high
+    pub fn output_on(&self, service: EmulatedService) -> bool {
+        self.output_state(service).active()
+    }
+    /// PWM and patterns are reported as a steady high, the emulator does not model the pin's timing
+    pub fn output_state(&self, service: EmulatedService) -> OutputState {
+        let state = self.state.lock().unwrap();
+        let command = state
+            .outputs
+            .get(&service.port())
+            .copied()
+            .unwrap_or(OutputCommand::Off);
+        let active = !matches!(command, OutputCommand::Off | OutputCommand::Duty(0));
+        OutputState {
+            command,
+            level: active,
+        }
+    }
+    pub fn estop_latched(&self) -> bool {
+        self.state.lock().unwrap().estop
+    }
+    /// Pins the lidar to a fixed distance, or returns it to its [LidarModel] if None
+    pub fn set_lidar_distance(&self, distance_cm: Option<u32>) {
+        self.state.lock().unwrap().lidar_override = distance_cm;
+    }
+    /// A healthy reading of [Emulator::lidar_distance], or an invalid one past the lidar's range
+    pub fn lidar_measurement(&self) -> LidarMeasurement {
+        let distance_cm = self.lidar_distance();
+        let valid = distance_cm > 1 && distance_cm <= LIDAR_RANGE_CM;
+        LidarMeasurement {
+            distance_cm: distance_cm.min(u16::MAX as u32) as u16,
+            status: match valid {
+                true => Status::HEALTH_FLAG.into(),
+                false => u8::from(Status::HEALTH_FLAG) | u8::from(Status::INVALID_SIGANTURE_FLAG),
+            },
+            signal_strength: if valid { 100 } else { 0 },
+            velocity: 0,
+            i2c_error: false,
+            valid,
+        }
+    }
+    pub fn lidar_distance(&self) -> u32 {
+        let state = self.state.lock().unwrap();
+        state
+            .lidar_override
+            .unwrap_or_else(|| state.lidar.distance_cm(self.started.elapsed()))
+    }
+
+    async fn serve_task(self, service: EmulatedService, listener: TcpListener) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            debug!("Emulated {:?} connected to {}", service, peer);
+            self.connection(service, stream, peer).await;
+            debug!("Emulated {:?} disconnected from {}", service, peer);
+        }
+    }
+    async fn connection(&self, service: EmulatedService, stream: TcpStream, peer: SocketAddr) {
+        let (mut reader, writer) = stream.into_split();
+        let writer = Arc::new(AsyncMutex::new(writer));
+        // Like the firmware, a lidar subscription only lasts as long as the connection
+        let (stream_tx, stream_rx) = watch::channel(None);
+        if service == EmulatedService::Lidar {
+            tokio::spawn(self.clone().lidar_stream_task(writer.clone(), stream_rx));
+        }
+        // Like the firmware, outputs report their state as soon as a host connects
+        if let Some(data) = self.state_report(service).and_then(|msg| msg.to_msg()) {
+            if writer.lock().await.write_all(&data).await.is_err() {
+                return;
+            }
+        }
+        loop {
+            let mut data = [0u8; SOCKET_MSG_SIZE];
+            if reader.read_exact(&mut data).await.is_err() {
+                return;
+            }
+            let msg = match InternalMessage::from_msg(&data) {
+                Some(msg) => msg,
+                None => {
+                    debug!("Emulated {:?} dropped a bad frame from {}", service, peer);
+                    continue;
+                }
+            };
+            match (service, msg) {
+                (
+                    EmulatedService::Lidar,
+                    InternalMessage::Lidar(LidarMsg::Subscribe(interval_ms)),
+                ) => {
+                    let interval_ms = interval_ms.max(MIN_STREAM_INTERVAL_MS);
+                    let _ = stream_tx.send(Some(Duration::from_millis(interval_ms as u64)));
+                    continue;
+                }
+                (EmulatedService::Lidar, InternalMessage::Lidar(LidarMsg::Unsubscribe)) => {
+                    let _ = stream_tx.send(None);
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(response) = self.process(service, msg).await {
+                if let Some(data) = response.to_msg() {
+                    if writer.lock().await.write_all(&data).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+    /// Pushes readings while subscribed, ending with the connection
+    async fn lidar_stream_task(
+        self,
+        writer: Arc<AsyncMutex<OwnedWriteHalf>>,
+        mut stream_rx: watch::Receiver<Option<Duration>>,
+    ) {
+        loop {
+            let interval = *stream_rx.borrow();
+            match interval {
+                Some(interval) => {
+                    tokio::select! {
+                        changed = stream_rx.changed() => {
+                            if changed.is_err() {
+                                return;
+                            }
+                        }
+                        _ = sleep(interval) => {
+                            let msg = InternalMessage::Lidar(LidarMsg::Measurement(
+                                0,
+                                self.lidar_measurement(),
+                            ));
+                            if let Some(data) = msg.to_msg() {
+                                if writer.lock().await.write_all(&data).await.is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+                None => {
+                    if stream_rx.changed().await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+    async fn process(
+        &self,
+        service: EmulatedService,
+        msg: InternalMessage,
+    ) -> Option<InternalMessage> {
+        match (service, msg) {
+            (_, InternalMessage::Ping(val)) => Some(InternalMessage::Ping(val)),
+            (
+                EmulatedService::FlirTurret | EmulatedService::NozzleTurret,
+                InternalMessage::Turret(TurretMsg::PollSteps),
+            ) => Some(InternalMessage::Turret(TurretMsg::Steps(
+                self.turret_steps(service),
+            ))),
+            // Like the firmware, only the turrets and outputs take E-stop messages
+            (EmulatedService::Lidar, InternalMessage::EmergencyStop(_)) => None,
+            (_, InternalMessage::EmergencyStop(msg)) => self.emergency_stop(msg),
+            (
+                EmulatedService::FlirTurret | EmulatedService::NozzleTurret,
+                InternalMessage::Turret(TurretMsg::SetSteps(_)),
+            ) if self.estop_latched() => {
+                info!("Emulated {:?} move refused, E-stop latched", service);
+                None
+            }
+            (
+                EmulatedService::FlirTurret | EmulatedService::NozzleTurret,
+                InternalMessage::Turret(TurretMsg::SetSteps(steps)),
+            ) => {
+                self.move_turret(service, steps);
+                None
+            }
+            // Only the main board's single sensor is emulated
+            (
+                EmulatedService::Lidar,
+                InternalMessage::Lidar(LidarMsg::PollLidar | LidarMsg::PollSensor(0)),
+            ) => Some(InternalMessage::Lidar(LidarMsg::Measurement(
+                0,
+                self.lidar_measurement(),
+            ))),
+            // Settings are accepted but do not change the modelled readings
+            (EmulatedService::Lidar, InternalMessage::Lidar(LidarMsg::Configure(sensor, _))) => {
+                Some(InternalMessage::Lidar(LidarMsg::Configured(
+                    sensor,
+                    sensor == 0,
+                )))
+            }
+            (EmulatedService::Lights, InternalMessage::Lights(msg))
+            | (EmulatedService::Siren, InternalMessage::Siren(msg)) => {
+                self.set_output(service, msg.into())
+            }
+            // Runs are switched but not timed, the emulator keeps no run time or volume accounting
+            (EmulatedService::Pump, InternalMessage::Pump(msg)) => {
+                match msg {
+                    PumpMsg::TurnOn | PumpMsg::RunFor(_) | PumpMsg::RunVolume(_) => {
+                        return self.set_output(service, OutputCommand::On)
+                    }
+                    PumpMsg::TurnOff => return self.set_output(service, OutputCommand::Off),
+                    PumpMsg::PollStatus => {
+                        return Some(InternalMessage::Pump(PumpMsg::Status(PumpStatus {
+                            running: self.output_on(service),
+                            ..Default::default()
+                        })))
+                    }
+                    PumpMsg::Status(_) => {}
+                }
+                None
+            }
+            (_, InternalMessage::Output(OutputMsg::Command(channel, command)))
+                if service.channel() == Some(channel) =>
+            {
+                self.set_output(service, command)
+            }
+            (_, InternalMessage::Output(OutputMsg::PollState(channel)))
+                if service.channel() == Some(channel) =>
+            {
+                self.state_report(service)
+            }
+            _ => None,
+        }
+    }
+    /// Mirrors the firmware's StepperMotor: a target past a limit is refused and leaves that stepper's move alone,
+    /// otherwise the stepper heads for it from wherever it is, one step per step time
+    fn move_turret(&self, service: EmulatedService, target: (i32, i32)) {
+        let config = turret_config(service);
+        let step_time = match self.motion_timing {
+            true => (step_time(&config.pan), step_time(&config.tilt)),
+            false => (Duration::ZERO, Duration::ZERO),
+        };
+        let mut state = self.state.lock().unwrap();
+        let (from, to) = match state.turrets.get(&service.port()) {
+            Some(motion) => (motion.steps(), motion.to),
+            None => ((0, 0), (0, 0)),
+        };
+        let to = (
+            within_limits(&config.pan, target.0).unwrap_or(to.0),
+            within_limits(&config.tilt, target.1).unwrap_or(to.1),
+        );
+        state.turrets.insert(
+            service.port(),
+            TurretMotion {
+                from,
+                to,
+                started: Instant::now(),
+                step_time,
+            },
+        );
+    }
+    fn emergency_stop(&self, msg: EStopMsg) -> Option<InternalMessage> {
+        let mut state = self.state.lock().unwrap();
+        match msg {
+            EStopMsg::Stop => {
+                if !state.estop {
+                    info!("Emulated E-stop latched");
+                }
+                state.estop = true;
+                state.outputs.clear();
+                // Like the firmware, moves halt part way
+                for motion in state.turrets.values_mut() {
+                    let steps = motion.steps();
+                    motion.from = steps;
+                    motion.to = steps;
+                }
+            }
+            EStopMsg::Reset => {
+                if state.estop {
+                    info!("Emulated E-stop reset");
+                }
+                state.estop = false;
+            }
+            EStopMsg::PollState => {}
+            EStopMsg::State(_) => return None,
+        }
+        Some(InternalMessage::EmergencyStop(EStopMsg::State(state.estop)))
+    }
+    /// Returns the new state to report if the command changed, as the firmware does.
+    /// Commands other than off are refused while the E-stop is latched
+    fn set_output(
+        &self,
+        service: EmulatedService,
+        command: OutputCommand,
+    ) -> Option<InternalMessage> {
+        if self.estop_latched() && command != OutputCommand::Off {
+            info!("Emulated {:?} command refused, E-stop latched", service);
+            return None;
+        }
+        let previous = self
+            .state
+            .lock()
+            .unwrap()
+            .outputs
+            .insert(service.port(), command)
+            .unwrap_or(OutputCommand::Off);
+        if previous == command {
+            return None;
+        }
+        info!("Emulated {:?} now running {:?}", service, command);
+        self.state_report(service)
+    }
+    fn state_report(&self, service: EmulatedService) -> Option<InternalMessage> {
+        let channel = service.channel()?;
+        Some(InternalMessage::Output(OutputMsg::State(
+            channel,
+            self.output_state(service),
+        )))
+    }
+}
+
+fn turret_config(service: EmulatedService) -> TurretConfig {
+    let turret = match service {
+        EmulatedService::NozzleTurret => board::FLIR_BOARD.nozzle_turret,
+        _ => board::FLIR_BOARD.flir_turret,
+    };
+    turret.expect("The main board preset runs both turrets")
+}
+
+/// The target, or None if it is past one of the stepper's limits
+fn within_limits(config: &StepperConfig, target: i32) -> Option<i32> {
+    (config.min_clockwise..=config.max_clockwise)
+        .contains(&target)
+        .then_some(target)
+}
+
+/// How long the firmware takes over each step, including its microsteps
+fn step_time(config: &StepperConfig) -> Duration {
+    let microsteps = config.microsteps.unwrap_or(1) as u64;
+    Duration::from_micros(
+        config.step_time_us as u64 + microsteps * 2 * config.microstep_time as u64,
+    )
+}
+
+/// Where a stepper moving from `from` to `to` is after `elapsed`
+fn stepper_position(from: i32, to: i32, step_time: Duration, elapsed: Duration) -> i32 {
+    let total = (to - from).unsigned_abs();
+    let taken = match step_time.is_zero() {
+        true => total,
+        false => (elapsed.as_micros() / step_time.as_micros()).min(total as u128) as u32,
+    };
+    from + (to - from).signum() * taken as i32
+}
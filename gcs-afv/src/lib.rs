@@ -34,12 +34,19 @@
 //!
 //! To run a simulated version of the Control Station and AFV run `cargo run --bin=gcs -- -s` from within the gcs-afv folder
 //!
+//! To emulate the AFV's Arduinos without hardware run `cargo run --bin=mcu-emulator` from within the gcs-afv folder
+//!
 //! To run the Control station run `cargo run --bin=gcs` from within the gcs-afv folder
 //!
 //! To run the AFV system in client mode run `cargo run --bin=afv` from within the gcs-afv folder
 //!
 //! To run the AFV system in server mode run `cargo run --bin=afv -- -s` from within the gcs-afv folder
 //!
+//! Stopping `afv` with Ctrl-C or SIGTERM turns off the pump, lights and siren before it exits, see [shutdown::Shutdown]
+//!
+//! Both `afv` and `gcs` read `afv.toml` from their working directory, or the file given with `--config`,
+//! see [config::Config] for what it holds. The `afv.toml` in the gcs-afv folder lists every setting at its default
+//!
 //! To enable logging output you will need to set the environment vaiable RUST_LOG = 'debug'
 //!
 //! In powershell run `$Env:RUST_LOG = 'debug'`
@@ -59,3 +66,11 @@ pub mod drivers;
 pub mod operators;
 /// This module contains the systems that enable the UI on the Control Station to interact with the operators on board the AFV.
 pub mod communicators;
+/// This module emulates the AFV-INTERNAL boards on the host so the AFV can run without any Arduinos connected.
+pub mod emulator;
+/// This module contains the TOML configuration the `afv` and `gcs` binaries load at startup.
+pub mod config;
+/// This module contains the shutdown signal that stops the AFV's drivers and operators together.
+pub mod shutdown;
+/// This module records the bus to a file and replays recordings into a fresh bus, to reproduce what the AFV saw in the field.
+pub mod recorder;
@@ -2,12 +2,15 @@ use std::net::SocketAddr;
 
 use default_net::Interface;
 use log::{info, error, debug};
-use tokio::{sync::{broadcast, watch}, net::TcpListener};
+use tokio::{sync::{broadcast, watch}, net::{TcpListener, TcpStream}, time::{sleep, Duration}};
 
-use crate::{network::{AFV_COMM_PORT, scanner::{ScanBuilder, ScanCount}, socket::Socket}, operators::naming::NamingOperatorMessage};
+use crate::{network::{AFV_COMM_PORT, scanner::{ScanBuilder, ScanCount}, socket::Socket}, operators::naming::NamingOperatorMessage, shutdown::Shutdown};
 
 use super::NetMessage;
 
+/// How long [AfvBridge::direct_connect] waits between attempts to reach the GCS
+pub const DIRECT_CONNECT_RETRY: Duration = Duration::from_secs(2);
+
 /// The AfvBridge is a bus member that is designed to transparently broadcast messages from 
 /// one bus to another. Essentially making it so that two computers share a logical bus
 /// This functionality is the foundation for the publish-filter architecture of the code base.
@@ -37,18 +40,18 @@ impl AfvBridge{
     /// Note, since we are using async architecture, what happens is for every successful connection 
     /// we spawn a listen task with a copy of the bus channel transmitter that will transparently receive and
     /// and send data from/to the bus. That is why this method does not need to return anything
-    pub async fn client(tx: broadcast::Sender<NetMessage>, scan_count: ScanCount){
+    pub async fn client(tx: broadcast::Sender<NetMessage>, scan_count: ScanCount, shutdown: Shutdown){
         info!("Starting Afv server search using port {}", AFV_COMM_PORT);
         let scan = ScanBuilder::default().scan_count(scan_count).add_port(AFV_COMM_PORT).dispatch();
         while let Ok(stream) = scan.recv_async().await{
             info!("Afv server found at addr {}", stream.peer_addr().unwrap());
             let socket = Socket::new(stream, false);
-            Self::start_communication(tx.clone(), socket);
+            Self::start_communication(tx.clone(), socket, &shutdown);
         }
         info!("Afv server search with port {} completed", AFV_COMM_PORT);
     }
     /// This will spawn a SINGLE AfvBridge that will wait for a connection to be made. Then it will spawn the listen task 
-    pub async fn server(tx: broadcast::Sender<NetMessage>, tgt_interface: Option<Interface>){
+    pub async fn server(tx: broadcast::Sender<NetMessage>, tgt_interface: Option<Interface>, shutdown: Shutdown){
         info!("Opening afv bridge server on port {}", AFV_COMM_PORT);
         
         let interface = match tgt_interface {
@@ -101,20 +104,33 @@ impl AfvBridge{
             if let Ok((stream, peer)) = listener.accept().await{
                 info!("Afv bridge as been linked to {}", peer);
                 let socket = Socket::new(stream, true);
-                Self::start_communication(tx, socket);
+                Self::start_communication(tx, socket, &shutdown);
             }
         }
     }
-    #[allow(unused)]
-    pub async fn direct_connect(bridge_tx: broadcast::Sender<NetMessage>, bus_tx: broadcast::Sender<NetMessage>, tgt: SocketAddr){
-        todo!()
+    /// Connects straight to the bridge server at `tgt` instead of scanning for it, retrying every [DIRECT_CONNECT_RETRY]
+    /// until it answers. Once linked the socket reconnects on its own
+    pub async fn direct_connect(tx: broadcast::Sender<NetMessage>, tgt: SocketAddr, shutdown: Shutdown){
+        info!("Connecting the afv bridge to {}", tgt);
+        let stream = loop{
+            match TcpStream::connect(tgt).await{
+                Ok(stream) => break stream,
+                Err(e) => {
+                    debug!("Could not reach the afv bridge server at {}: {}", tgt, e);
+                    sleep(DIRECT_CONNECT_RETRY).await;
+                },
+            }
+        };
+        info!("Afv bridge as been linked to {}", tgt);
+        let socket = Socket::new(stream, false);
+        Self::start_communication(tx, socket, &shutdown);
     }
-    /// A helper function to start the neccesary tasks to make a functional bridge system
-    pub fn start_communication(tx: broadcast::Sender<NetMessage>, socket: Socket){
+    /// A helper function to start the neccesary tasks to make a functional bridge system, they run until the shutdown is triggered
+    pub fn start_communication(tx: broadcast::Sender<NetMessage>, socket: Socket, shutdown: &Shutdown){
         let (d_tx, d_rx) = watch::channel(NetMessage::NamingOperator(NamingOperatorMessage{id:  0}));
 
-        tokio::spawn(Self::forward(tx.subscribe(), d_rx, socket.clone()));
-        tokio::spawn(Self::listen(tx, d_tx, socket.clone()));
+        shutdown.spawn(Self::forward(tx.subscribe(), d_rx, socket.clone()));
+        shutdown.spawn(Self::listen(tx, d_tx, socket.clone()));
     }
     /// The main task that will put network tasks on the local bus
     async fn listen(tx: broadcast::Sender<NetMessage>, duplicates: watch::Sender<NetMessage>, socket: Socket){
@@ -158,7 +174,9 @@ impl AfvBridge{
 
             debug!("Afv bridge traffic {}->{}: {:?}", socket.local_addr(), socket.peer_addr(), msg);
 
-            socket.write_data(&data).await;
+            if let Err(e) = socket.write_data(&data).await{
+                debug!("Afv bridge dropped a message to {}: {}", socket.peer_addr(), e);
+            }
         } 
     }
 }
\ No newline at end of file
@@ -0,0 +1,185 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use log::{debug, info, warn};
+use tokio::{
+    net::TcpStream,
+    sync::oneshot,
+    time::{timeout, Duration},
+};
+
+use super::scanner::{ScanBuilder, ScanCount};
+use crate::shutdown::Shutdown;
+
+/// How long a connection to a configured endpoint may take
+pub const ENDPOINT_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+/// Discovery finds the MCU services the drivers talk to, by the port each service listens on.
+///
+/// Services with a configured endpoint are connected to directly. Every other port is found by one shared
+/// scan, which runs until each of its ports has been found once. Ports that every board serves stay in the
+/// scan for as long as it runs, and each board found on them is handed out through [Discovery::boards].
+pub struct Discovery {
+    endpoints: Arc<HashMap<u16, SocketAddr>>,
+    requests: flume::Sender<(u16, oneshot::Sender<Option<TcpStream>>)>,
+    boards: Arc<HashMap<u16, flume::Receiver<TcpStream>>>,
+}
+
+impl Discovery {
+    /// * `endpoints` - The address to connect to for each service port that is not scanned for
+    /// * `ports` - The service ports the shared scan looks for
+    /// * `board_ports` - The ports every board serves, which the shared scan keeps looking for on new boards
+    /// * `shutdown` - Ends the shared scan
+    pub fn new(
+        endpoints: HashMap<u16, SocketAddr>,
+        ports: Vec<u16>,
+        board_ports: Vec<u16>,
+        shutdown: &Shutdown,
+    ) -> Self {
+        let (requests, requests_rx) = flume::unbounded();
+        let mut boards = HashMap::new();
+        let mut boards_tx = HashMap::new();
+        for port in board_ports {
+            let (tx, rx) = flume::unbounded();
+            boards.insert(port, rx);
+            boards_tx.insert(port, tx);
+        }
+        let discovery = Self {
+            endpoints: Arc::new(endpoints),
+            requests,
+            boards: Arc::new(boards),
+        };
+
+        shutdown.spawn(Self::scan_task(
+            ports
+                .into_iter()
+                .filter(|port| {
+                    !discovery.endpoints.contains_key(port) && !boards_tx.contains_key(port)
+                })
+                .collect(),
+            boards_tx,
+            requests_rx,
+        ));
+
+        discovery
+    }
+    /// Connects to the service on the port, waiting on the shared scan unless it has an endpoint.
+    /// A port the shared scan is not looking for, or has already handed out, gets a scan of its own
+    pub async fn find(&self, port: u16) -> Option<TcpStream> {
+        if let Some(&endpoint) = self.endpoints.get(&port) {
+            return match timeout(ENDPOINT_CONNECT_TIMEOUT, TcpStream::connect(endpoint)).await {
+                Ok(Ok(stream)) => {
+                    info!("Connected to port {} at {}", port, endpoint);
+                    Some(stream)
+                }
+                Ok(Err(e)) => {
+                    warn!("Could not connect to port {} at {}: {}", port, endpoint, e);
+                    None
+                }
+                Err(_) => {
+                    warn!("Timed out connecting to port {} at {}", port, endpoint);
+                    None
+                }
+            };
+        }
+
+        let (tx, rx) = oneshot::channel();
+        if self.requests.send_async((port, tx)).await.is_ok() {
+            if let Ok(Some(stream)) = rx.await {
+                return Some(stream);
+            }
+        }
+        debug!("Scanning for port {} on its own", port);
+        ScanBuilder::default()
+            .scan_count(ScanCount::Infinite)
+            .add_port(port)
+            .dispatch()
+            .recv_async()
+            .await
+            .ok()
+    }
+    /// Every connection the shared scan makes to a port every board serves, so a board is found again each
+    /// scan round it is still reachable. None if the port was not given as a board port
+    pub fn boards(&self, port: u16) -> Option<flume::Receiver<TcpStream>> {
+        self.boards.get(&port).cloned()
+    }
+    /// Starts a scan for the ports, None if there are none to scan for
+    fn scan(ports: Vec<u16>) -> Option<flume::Receiver<TcpStream>> {
+        if ports.is_empty() {
+            return None;
+        }
+        let mut scan = ScanBuilder::default().scan_count(ScanCount::Infinite);
+        for &port in ports.iter() {
+            scan = scan.add_port(port);
+        }
+        info!("Scanning for ports {:?}", ports);
+        Some(scan.dispatch())
+    }
+    async fn scan_task(
+        ports: HashSet<u16>,
+        boards: HashMap<u16, flume::Sender<TcpStream>>,
+        requests: flume::Receiver<(u16, oneshot::Sender<Option<TcpStream>>)>,
+    ) {
+        let mut remaining = ports;
+        let mut waiting: HashMap<u16, Vec<oneshot::Sender<Option<TcpStream>>>> = HashMap::new();
+        let mut unclaimed: HashMap<u16, TcpStream> = HashMap::new();
+        // Narrowed to the board ports once every other port is found, and dropped if there are none
+        let mut found = Self::scan(remaining.iter().chain(boards.keys()).copied().collect());
+
+        loop {
+            tokio::select! {
+                request = requests.recv_async() => {
+                    let (port, tx) = match request {
+                        Ok(request) => request,
+                        Err(_) => return,
+                    };
+                    if let Some(stream) = unclaimed.remove(&port) {
+                        let _ = tx.send(Some(stream));
+                    } else if remaining.contains(&port) {
+                        waiting.entry(port).or_default().push(tx);
+                    } else {
+                        let _ = tx.send(None);
+                    }
+                }
+                stream = async { found.as_ref().unwrap().recv_async().await }, if found.is_some() => {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => {
+                            found = None;
+                            continue;
+                        }
+                    };
+                    let port = match stream.peer_addr() {
+                        Ok(addr) => addr.port(),
+                        Err(_) => continue,
+                    };
+                    if let Some(tx) = boards.get(&port) {
+                        let _ = tx.send(stream);
+                        continue;
+                    }
+                    // A service that is already found keeps its first board
+                    if !remaining.remove(&port) {
+                        continue;
+                    }
+                    let mut stream = Some(stream);
+                    for tx in waiting.remove(&port).unwrap_or_default() {
+                        if let Err(Some(returned)) = tx.send(stream.take()) {
+                            stream = Some(returned);
+                        }
+                    }
+                    if let Some(stream) = stream {
+                        unclaimed.insert(port, stream);
+                    }
+                    if remaining.is_empty() {
+                        info!("Found every scanned port");
+                        found = Self::scan(boards.keys().copied().collect());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -2,12 +2,13 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     drivers::{
-        flir::FlirDriverMessage, lidar::LidarDriverMessage, lights::LightsDriverMessage,
-        pump::PumpDriverMessage, siren::SirenDriverMessage, turret::TurretDriverMessage,
+        board_log::BoardLogMessage, estop::EmergencyStopMessage, flir::FlirDriverMessage,
+        lidar::LidarDriverMessage, lights::LightsDriverMessage, pump::PumpDriverMessage,
+        siren::SirenDriverMessage, supervisor::SupervisorMessage, turret::TurretDriverMessage,
     },
     operators::{
         flir::FlirOperatorMessage, naming::NamingOperatorMessage, nozzle::NozzleOperatorMessage,
-        peripheral::PeripheralMessage, pump::PumpOperatorMessage,
+        peripheral::PeripheralMessage, pump::PumpOperatorMessage, range_map::RangeMapMessage,
     },
 };
 
@@ -28,11 +29,20 @@ pub enum NetMessage {
     PumpOperator(PumpOperatorMessage),
     PeripheralOperator(PeripheralMessage),
     NamingOperator(NamingOperatorMessage),
+    BoardLog(BoardLogMessage),
+    RangeMap(RangeMapMessage),
+    /// Handled by every driver, see [crate::drivers::estop]
+    EmergencyStop(EmergencyStopMessage),
+    /// The connection state of each driver, see [crate::drivers::supervisor]
+    Supervisor(SupervisorMessage),
 }
 
 /// This module contains the Scanner struct that is resposible for finding all network entites without manual supervision
 pub mod scanner;
 
+/// This module contains the Discovery service that finds every driver's MCU service with one shared scan or a configured endpoint
+pub mod discovery;
+
 /// This module contains a Socket wrapper that automatically reconnects if connections are lost
 pub mod socket;
 
@@ -1,15 +1,57 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{io, net::SocketAddr, sync::Arc};
 
-use log::info;
+use log::{debug, info};
+use rand::{thread_rng, Rng};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpListener, TcpStream,
     },
-    sync::{Mutex, MutexGuard},
+    sync::{watch, Mutex, MutexGuard},
+    time::{sleep, timeout, Duration},
 };
 
+/// How a [Socket] paces its reconnect attempts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    /// The wait before the first attempt, doubled after each one that fails
+    pub min: Duration,
+    pub max: Duration,
+    /// Each wait is randomly lengthened or shortened by up to this fraction of itself,
+    /// so sockets that dropped together do not retry together
+    pub jitter: f32,
+    /// How long a client waits on a single connect attempt
+    pub attempt_timeout: Duration,
+}
+
+impl Backoff {
+    /// The wait before the given attempt, counting from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.min.saturating_mul(1 << attempt.min(16)).min(self.max);
+        let jitter = self.jitter.clamp(0.0, 1.0) as f64;
+        base.mul_f64(1.0 + thread_rng().gen_range(-jitter..=jitter))
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            min: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+            jitter: 0.2,
+            attempt_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketState {
+    Connected,
+    /// Waiting on the given reconnect attempt, counting from 0. Writes fail until the socket is connected again
+    Reconnecting(u32),
+}
+
 /// Will handle maintaing a tcp socket as well as provide procisions
 /// for sending and receiving data
 #[derive(Clone)]
@@ -19,6 +61,10 @@ pub struct Socket {
     wr: Arc<Mutex<OwnedWriteHalf>>,
     peer: SocketAddr,
     local: SocketAddr,
+    backoff: Backoff,
+    state: Arc<watch::Sender<SocketState>>,
+    /// Counts the reconnects, held while reconnecting so readers that fail together reconnect once
+    generation: Arc<Mutex<u64>>,
 }
 
 impl Socket {
@@ -33,8 +79,16 @@ impl Socket {
             peer,
             server,
             local: ip,
+            backoff: Backoff::default(),
+            state: Arc::new(watch::channel(SocketState::Connected).0),
+            generation: Arc::new(Mutex::new(0)),
         }
     }
+    /// Sets how reconnect attempts are paced, [Backoff::default] otherwise
+    pub fn backoff(mut self, backoff: Backoff) -> Socket {
+        self.backoff = backoff;
+        self
+    }
 
     pub async fn get_reader(&self) -> MutexGuard<OwnedReadHalf> {
         self.rd.lock().await
@@ -42,23 +96,25 @@ impl Socket {
     pub async fn get_writer(&self) -> MutexGuard<OwnedWriteHalf> {
         self.wr.lock().await
     }
-    /// Reads exactly one byte from the data stream
+    /// Reads exactly one byte from the data stream, reconnecting for as long as it takes
     pub async fn read_byte(&self) -> u8 {
         loop {
-            let mut rd = self.get_reader().await;
+            let generation = *self.generation.lock().await;
+            let read = self.get_reader().await.read_u8().await;
 
-            match rd.read_u8().await {
+            match read {
                 Ok(byte) => return byte,
-                Err(_) => {
-                    drop(rd);
-                    self.clone().reconnect().await;
-                }
+                Err(_) => self.reconnect(generation).await,
             }
         }
     }
-    /// Writes a whole slice of data to the stream
-    pub async fn write_data(&self, data: &[u8]) {
-        let _ = self.get_writer().await.write(data).await;
+    /// Writes a whole slice of data to the stream.
+    /// Nothing is queued, writes made while the socket is reconnecting fail with [io::ErrorKind::NotConnected]
+    pub async fn write_data(&self, data: &[u8]) -> io::Result<()> {
+        if !self.is_connected() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        self.get_writer().await.write_all(data).await
     }
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer
@@ -66,32 +122,58 @@ impl Socket {
     pub fn local_addr(&self) -> SocketAddr {
         self.local
     }
-    /// This function automatically attempts a reconnection to the peer
-    /// Is is private and is automatically called from within the scanner instance
-    async fn reconnect(&self) {
+    /// Follows the socket through its disconnects and reconnects
+    pub fn state(&self) -> watch::Receiver<SocketState> {
+        self.state.subscribe()
+    }
+    pub fn is_connected(&self) -> bool {
+        *self.state.borrow() == SocketState::Connected
+    }
+    /// This function automatically attempts a reconnection to the peer, backing off between attempts
+    /// Is is private and is automatically called by a read that fails
+    async fn reconnect(&self, seen: u64) {
+        let mut generation = self.generation.lock().await;
+        // Another reader already reconnected since this one failed
+        if *generation != seen {
+            return;
+        }
         info!("Socket has disconnected from {}", self.peer);
-        let mut rd = self.get_reader().await;
-        let mut wr = self.get_writer().await;
 
-        loop {
-            if self.server {
-                if let Ok(l) = TcpListener::bind((self.local.ip(), self.local.port())).await {
-                    if let Ok((s, _)) = l.accept().await {
-                        info!("Socket server has reconnected to {}", self.peer);
-                        (*rd, *wr) = s.into_split();
-                        return;
-                    }
+        let mut attempt = 0;
+        let stream = loop {
+            self.state.send_replace(SocketState::Reconnecting(attempt));
+            sleep(self.backoff.delay(attempt)).await;
+            match self.connect().await {
+                Ok(stream) => break stream,
+                Err(e) => {
+                    debug!(
+                        "Socket reconnect attempt {} to {} failed: {}",
+                        attempt, self.peer, e
+                    );
+                    attempt = attempt.saturating_add(1);
                 }
-                continue;
-            }
-            match TcpStream::connect(self.peer).await {
-                Ok(s) => {
-                    info!("Socket client has reconnected to {}", self.peer);
-                    (*rd, *wr) = s.into_split();
-                    return;
-                }
-                Err(_) => {}
             }
+        };
+
+        // The halves are only locked to swap them, so writers fail fast rather than wait on the reconnect
+        let (rd, wr) = stream.into_split();
+        *self.get_reader().await = rd;
+        *self.get_writer().await = wr;
+        *generation += 1;
+        self.state.send_replace(SocketState::Connected);
+        match self.server {
+            true => info!("Socket server has reconnected to {}", self.peer),
+            false => info!("Socket client has reconnected to {}", self.peer),
+        }
+    }
+    async fn connect(&self) -> io::Result<TcpStream> {
+        if self.server {
+            let listener = TcpListener::bind((self.local.ip(), self.local.port())).await?;
+            return Ok(listener.accept().await?.0);
+        }
+        match timeout(self.backoff.attempt_timeout, TcpStream::connect(self.peer)).await {
+            Ok(stream) => stream,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
         }
     }
 }
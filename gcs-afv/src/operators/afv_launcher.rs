@@ -1,52 +1,184 @@
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::{Ipv4Addr, SocketAddr}};
 
-use afv_internal::{FLIR_TURRET_PORT, NOZZLE_TURRET_PORT};
-use tokio::{sync::broadcast, time::sleep};
+use afv_internal::{FLIR_TURRET_PORT, NOZZLE_TURRET_PORT, LIDAR_PORT, PUMP_PORT, LIGHTS_PORT, SIREN_PORT, LOG_PORT};
+use log::{error, info};
+use tokio::sync::broadcast;
 
-use crate::{network::{NetMessage, afv_bridge::AfvBridge, scanner::ScanCount}, drivers::{turret::TurretDriver, lidar::LidarDriver, pump::PumpDriver, lights::LightsDriver, siren::SirenDriver}};
+use crate::{config::Config, shutdown::Shutdown, recorder::{Recorder, Replay}, emulator::{EmulatedService, Emulator, LidarModel}, network::{NetMessage, afv_bridge::AfvBridge, scanner::ScanCount, discovery::Discovery}, drivers::{estop::EmergencyStop, supervisor::DriverSupervisor, turret::{TurretDriver, TurretDriverConfig}, lidar::LidarDriver, pump::{PumpDriver, PumpDriverConfig}, lights::LightsDriver, siren::SirenDriver, board_log::BoardLogDriver}};
 
-use super::{naming::NamingOperator, flir::FlirOperator, nozzle::NozzleOperator};
+use super::{naming::NamingOperator, flir::FlirOperator, nozzle::NozzleOperator, range_map::RangeMapOperator};
 
-pub async fn launch(client: bool, direct_connect: Option<SocketAddr>){
-    let (net_tx, _rx) = broadcast::channel::<NetMessage>(10000);
-    if client{
-        match direct_connect{
+/// A running AFV. Its drivers and operators share one bus and one [Shutdown], so they stop together
+pub struct Afv{
+    net_tx: broadcast::Sender<NetMessage>,
+    shutdown: Shutdown,
+}
+
+impl Afv{
+    /// Starts the operators and drivers the configuration enables on a fresh bus, without linking it to a GCS
+    pub async fn start(config: &Config) -> Afv{
+        let (net_tx, _rx) = broadcast::channel::<NetMessage>(10000);
+        let afv = Self{
+            net_tx,
+            shutdown: Shutdown::new(),
+        };
+        start(afv.net_tx.clone(), &afv.shutdown, config).await;
+        afv
+    }
+    pub fn net_tx(&self) -> broadcast::Sender<NetMessage>{
+        self.net_tx.clone()
+    }
+    pub fn shutdown(&self) -> Shutdown{
+        self.shutdown.clone()
+    }
+    /// Stops every driver and operator and waits for them to end. The drivers put the pump, lights and siren in a safe state as they stop
+    pub async fn stop(self){
+        info!("Shutting down the AFV");
+        self.shutdown.stop().await;
+        info!("AFV shut down");
+    }
+}
+
+/// Starts the AFV and links its bus to the GCS
+pub async fn launch(config: Config) -> Afv{
+    let afv = Afv::start(&config).await;
+    let net_tx = afv.net_tx();
+    if !config.network.server{
+        match config.network.gcs_address{
             Some(addr) => {
-                tokio::spawn(AfvBridge::direct_connect(net_tx.clone(), net_tx.clone(), addr));
+                afv.shutdown.spawn(AfvBridge::direct_connect(net_tx, addr, afv.shutdown()));
             },
             None => {
-                tokio::spawn(AfvBridge::client(net_tx.clone(), ScanCount::Limited(3)));
+                afv.shutdown.spawn(AfvBridge::client(net_tx, ScanCount::Limited(config.network.afv_scans), afv.shutdown()));
             },
         }
     }
     else{
-        tokio::spawn(AfvBridge::server(net_tx.clone(), None));
+        afv.shutdown.spawn(AfvBridge::server(net_tx, None, afv.shutdown()));
     }
+    afv
+}
 
-    tokio::spawn(NamingOperator::new(net_tx.clone()));
-    tokio::spawn(FlirOperator::new(net_tx.clone()));
-    tokio::spawn(NozzleOperator::new(net_tx.clone()));
-    tokio::spawn(TurretDriver::new(net_tx.clone(), FLIR_TURRET_PORT));
-    tokio::spawn(TurretDriver::new(net_tx.clone(), NOZZLE_TURRET_PORT));
-    tokio::spawn(LidarDriver::new(net_tx.clone()));
-    tokio::spawn(PumpDriver::new(net_tx.clone()));
-    tokio::spawn(LightsDriver::new(net_tx.clone()));
-    tokio::spawn(SirenDriver::new(net_tx.clone()));
-    loop{
-        sleep(tokio::time::Duration::from_secs(1)).await;
+/// Starts the AFV on the MCU emulator, which serves every board's services from this machine
+pub async fn simulate(mut config: Config) -> Afv{
+    if let Err(e) = Emulator::new(LidarModel::default()).start().await{
+        error!("Could not start the MCU emulator: {}", e);
+    }
+    // Scans skip loopback, so devices without an endpoint are pointed straight at the emulator
+    let devices = &mut config.devices;
+    for (endpoint, service) in [
+        (&mut devices.flir_turret, EmulatedService::FlirTurret),
+        (&mut devices.nozzle_turret, EmulatedService::NozzleTurret),
+        (&mut devices.lidar, EmulatedService::Lidar),
+        (&mut devices.pump, EmulatedService::Pump),
+        (&mut devices.lights, EmulatedService::Lights),
+        (&mut devices.siren, EmulatedService::Siren),
+    ]{
+        endpoint.get_or_insert(SocketAddr::from((Ipv4Addr::LOCALHOST, service.port())));
     }
+    let afv = Afv::start(&config).await;
+    afv.shutdown.spawn(AfvBridge::server(afv.net_tx(), None, afv.shutdown()));
+    afv
 }
 
-pub async fn simulate(){
+/// Plays a recording as a virtual AFV that the GCS finds like a real one, returning once the recording has been played
+pub async fn replay(replay: Replay) -> Afv{
     let (net_tx, _rx) = broadcast::channel::<NetMessage>(10000);
-    tokio::spawn(AfvBridge::server(net_tx.clone(), None));
-    tokio::spawn(NamingOperator::new(net_tx.clone()));
-    tokio::spawn(FlirOperator::new(net_tx.clone()));
-    tokio::spawn(NozzleOperator::new(net_tx.clone()));
-    tokio::spawn(TurretDriver::new(net_tx.clone(), FLIR_TURRET_PORT));
-    tokio::spawn(TurretDriver::new(net_tx.clone(), NOZZLE_TURRET_PORT));
-    tokio::spawn(LidarDriver::new(net_tx.clone()));
-    tokio::spawn(PumpDriver::new(net_tx.clone()));
-    tokio::spawn(LightsDriver::new(net_tx.clone()));
-    tokio::spawn(SirenDriver::new(net_tx.clone()));
+    let afv = Afv{
+        net_tx,
+        shutdown: Shutdown::new(),
+    };
+    // The virtual AFV names itself so the GCS never mistakes it for the AFV it was recorded on
+    afv.shutdown.spawn(NamingOperator::new(afv.net_tx()));
+    afv.shutdown.spawn(AfvBridge::server(afv.net_tx(), None, afv.shutdown()));
+    let replay = replay.filter(|msg| !matches!(msg, NetMessage::NamingOperator(_)));
+    if let Err(e) = replay.play(&afv.net_tx).await{
+        error!("Could not replay the recording: {}", e);
+    }
+    afv
+}
+
+/// Starts the operators and drivers the configuration enables
+async fn start(net_tx: broadcast::Sender<NetMessage>, shutdown: &Shutdown, config: &Config){
+    // Recording starts first so it holds everything the drivers and operators publish
+    let recorder = match &config.recording.path{
+        Some(path) => match Recorder::start(path, &net_tx, shutdown).await{
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                error!("Could not record to {:?}: {}", path, e);
+                None
+            },
+        },
+        None => None,
+    };
+
+    EmergencyStop::new(net_tx.clone(), shutdown.clone()).publish_status();
+
+    let operators = &config.operators;
+    if operators.naming{
+        shutdown.spawn(NamingOperator::new(net_tx.clone()));
+    }
+    if operators.flir{
+        let flir = FlirOperator::new(net_tx.clone(), config.calibration.flir_fov_deg, shutdown).await;
+        if let Some(recorder) = &recorder{
+            recorder.record_ir_stream(flir.ir_stream_rx());
+        }
+    }
+    if operators.nozzle{
+        NozzleOperator::new(net_tx.clone(), config.calibration.clone(), shutdown).await;
+    }
+    if operators.range_map{
+        RangeMapOperator::new(net_tx.clone(), config.recording.range_map_dir.clone(), shutdown).await;
+    }
+
+    let drivers = &config.drivers;
+    let devices = &config.devices;
+    // One scan finds every enabled driver's service that has no endpoint
+    let mut endpoints = HashMap::new();
+    let mut ports = vec![];
+    for (enabled, port, endpoint) in [
+        (drivers.flir_turret, FLIR_TURRET_PORT, devices.flir_turret),
+        (drivers.nozzle_turret, NOZZLE_TURRET_PORT, devices.nozzle_turret),
+        (drivers.lidar, LIDAR_PORT, devices.lidar),
+        (drivers.pump, PUMP_PORT, devices.pump),
+        (drivers.lights, LIGHTS_PORT, devices.lights),
+        (drivers.siren, SIREN_PORT, devices.siren),
+    ]{
+        match (enabled, endpoint){
+            (false, _) => {},
+            (true, Some(endpoint)) => {
+                endpoints.insert(port, endpoint);
+            },
+            (true, None) => ports.push(port),
+        }
+    }
+    // Every board serves its log, so the scan keeps looking for new boards on it
+    let board_ports = match drivers.board_log{
+        true => vec![LOG_PORT],
+        false => vec![],
+    };
+    let discovery = Discovery::new(endpoints, ports, board_ports, shutdown);
+
+    let poll_steps_interval = config.intervals.poll_steps();
+    if drivers.flir_turret{
+        DriverSupervisor::<TurretDriver>::spawn(net_tx.clone(), discovery.clone(), shutdown.clone(), TurretDriverConfig{ port: FLIR_TURRET_PORT, poll_steps_interval });
+    }
+    if drivers.nozzle_turret{
+        DriverSupervisor::<TurretDriver>::spawn(net_tx.clone(), discovery.clone(), shutdown.clone(), TurretDriverConfig{ port: NOZZLE_TURRET_PORT, poll_steps_interval });
+    }
+    if drivers.lidar{
+        DriverSupervisor::<LidarDriver>::spawn(net_tx.clone(), discovery.clone(), shutdown.clone(), config.intervals.lidar_stream_ms);
+    }
+    if drivers.pump{
+        DriverSupervisor::<PumpDriver>::spawn(net_tx.clone(), discovery.clone(), shutdown.clone(), PumpDriverConfig{ status_interval: config.intervals.pump_status(), nozzle_poll_interval: poll_steps_interval });
+    }
+    if drivers.lights{
+        DriverSupervisor::<LightsDriver>::spawn(net_tx.clone(), discovery.clone(), shutdown.clone(), ());
+    }
+    if drivers.siren{
+        DriverSupervisor::<SirenDriver>::spawn(net_tx.clone(), discovery.clone(), shutdown.clone(), ());
+    }
+    if drivers.board_log{
+        BoardLogDriver::new(net_tx.clone(), &discovery, shutdown).await;
+    }
 }
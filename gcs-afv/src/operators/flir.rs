@@ -14,11 +14,13 @@ use tokio::{
 use crate::{
     drivers::{flir::FlirDriver, turret::TurretDriverMessage},
     network::NetMessage,
+    shutdown::Shutdown,
 };
 
 pub const BROADCAST_SETTINGS_INTERVAL: Duration = Duration::from_secs(5);
 pub const AUTO_TARGET_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
 pub const AUTO_TARGET_REQUEST_WAIT: Duration = Duration::from_millis(500);
+/// The FLIR A50's half field of view in degrees, by default
 pub const FLIRFOV: (f32, f32) = (29.0, 22.0);
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -59,28 +61,53 @@ pub struct FlirOperator {
     settings_watch: Arc<watch::Sender<FlirOperatorSettings>>,
     image_watch: Arc<watch::Sender<DynamicImage>>,
     auto_target_watch: Arc<watch::Sender<Instant>>,
-    flir_driver: FlirDriver,
+    /// The IR nal packets images are decoded from
+    ir_stream: broadcast::Sender<Vec<u8>>,
+    /// The camera's half field of view, pan then tilt, in degrees
+    fov: [f32; 2],
 }
 
 impl FlirOperator {
-    /// Creates and adds a new FlirOperator to the main bus.
-    pub async fn new(net_tx: broadcast::Sender<NetMessage>) -> FlirOperator {
+    /// Creates and adds a new FlirOperator to the main bus, along with the [FlirDriver] it takes images from.
+    ///
+    /// * `fov` - The camera's half field of view, pan then tilt, in degrees
+    pub async fn new(
+        net_tx: broadcast::Sender<NetMessage>,
+        fov: [f32; 2],
+        shutdown: &Shutdown,
+    ) -> FlirOperator {
+        let flir_driver = FlirDriver::new(net_tx.clone(), true, shutdown).await;
+        Self::from_ir_stream(net_tx, flir_driver.ir_stream(), fov, shutdown)
+    }
+    /// Creates and adds a new FlirOperator to the main bus that takes images from the given IR nal packet stream
+    /// instead of the camera, such as a [Replay](crate::recorder::Replay) of a recording
+    pub fn from_ir_stream(
+        net_tx: broadcast::Sender<NetMessage>,
+        ir_stream: broadcast::Sender<Vec<u8>>,
+        fov: [f32; 2],
+        shutdown: &Shutdown,
+    ) -> FlirOperator {
         let operator = Self {
-            flir_driver: FlirDriver::new(net_tx.clone(), true).await,
+            ir_stream,
             net_tx,
             settings_watch: Arc::new(watch::channel(Default::default()).0),
             image_watch: Arc::new(watch::channel(Default::default()).0),
             auto_target_watch: Arc::new(watch::channel(Instant::now()).0),
+            fov,
         };
 
-        tokio::spawn(operator.clone().settings_update_task());
-        tokio::spawn(operator.clone().settings_broadcast_task());
-        tokio::spawn(operator.clone().nal_intake_task());
-        tokio::spawn(operator.clone().analyze_image_task());
-        tokio::spawn(operator.clone().auto_target_task());
-        tokio::spawn(operator.clone().auto_target_watch_task());
+        shutdown.spawn(operator.clone().settings_update_task());
+        shutdown.spawn(operator.clone().settings_broadcast_task());
+        shutdown.spawn(operator.clone().nal_intake_task());
+        shutdown.spawn(operator.clone().analyze_image_task());
+        shutdown.spawn(operator.clone().auto_target_task());
+        shutdown.spawn(operator.clone().auto_target_watch_task());
         operator
     }
+    /// The IR nal packets the operator decodes images from, such as for a [Recorder](crate::recorder::Recorder)
+    pub fn ir_stream_rx(&self) -> broadcast::Receiver<Vec<u8>> {
+        self.ir_stream.subscribe()
+    }
     /// Updates the time of last request for the auto target system
     async fn auto_target_watch_task(self) {
         let mut net_rx = self.net_tx.subscribe();
@@ -247,8 +274,8 @@ impl FlirOperator {
             let image_height = image.height();
             let center = Vec2::new((image_width as f32) / 2.0, (image_height as f32) / 2.0);
             let delta_pix = (target_centroid - center) * Vec2::new(1.0, 1.0);
-            let deg_pix_x = (FLIRFOV.0 * 2.0) / image_width as f32;
-            let deg_pix_y = (FLIRFOV.1 * 2.0) / image_height as f32;
+            let deg_pix_x = (self.fov[0] * 2.0) / image_width as f32;
+            let deg_pix_y = (self.fov[1] * 2.0) / image_height as f32;
 
             let delta_x = delta_pix.x * deg_pix_x;
             let delta_y = delta_pix.y * deg_pix_y;
@@ -266,9 +293,9 @@ impl FlirOperator {
                 )));
         }
     }
-    /// When auto targeting is enabled, starts constructing images from the [crate::drivers::flir::FlirDriver] ir nal stream
+    /// When auto targeting is enabled, starts constructing images from the IR nal stream
     async fn nal_intake_task(self) {
-        let mut nal_rx = self.flir_driver.ir_stream_rx();
+        let mut nal_rx = self.ir_stream.subscribe();
         let mut auto_target_watch = self.auto_target_watch.subscribe();
         let mut decoder = match Decoder::new() {
             Ok(d) => d,
@@ -287,7 +314,7 @@ impl FlirOperator {
                 info!("Stopping flir operator image intake");
                 let _ = auto_target_watch.changed().await;
                 info!("Staring flir operator image intake");
-                nal_rx = self.flir_driver.ir_stream_rx();
+                nal_rx = self.ir_stream.subscribe();
                 continue;
             }
 
@@ -19,7 +19,12 @@ pub mod flir;
 /// It should also be responsible for turning the pump on when the nozzle turret its in position.
 pub mod nozzle;
 
-/// Unused
+/// This operator sweeps a turret's lidar over a sector and pairs each reading with the turret's angle,
+/// building a range map of the surroundings that can be queried on the bus or exported as a point cloud
+pub mod range_map;
+
+/// This operator is the pump's safety interlock, only letting the pump run while the AFV is armed from the Control Station,
+/// no E-stop is latched and the nozzle turret is still and inside its sector
 pub mod pump;
 
 /// Unused
@@ -3,12 +3,19 @@ use glam::Vec2;
 use serde::{Serialize, Deserialize};
 use tokio::sync::broadcast;
 
-use crate::{network::NetMessage, drivers::{turret::TurretDriverMessage, lidar::LidarDriverMessage}};
+use crate::{config::CalibrationConfig, network::NetMessage, shutdown::Shutdown, drivers::{turret::TurretDriverMessage, lidar::{LidarDriverMessage, FLIR_LIDAR}}};
 
 use super::flir::FlirOperatorMessage;
 
 pub const AUTO_TARGET_REQUEST_INTERVAL: u64 = 1;
+// The defaults for the nozzle's part of the CalibrationConfig
 pub const LOCK_ON_ANGLE: [f32; 2] = [3.0, 3.0];
+/// The fewest filtered lidar readings a firing solution is computed from
+pub const MIN_RANGE_SAMPLES: usize = 5;
+/// Where the nozzle turret sits relative to the FLIR turret, in m
+pub const NOZZLE_TURRET_OFFSET: [f32; 2] = [0.4572, 0.0];
+/// How fast water leaves the nozzle, in m/s
+pub const WATER_SPEED: f32 = 1.0;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum NozzleOperatorMessage{
@@ -18,15 +25,17 @@ pub enum NozzleOperatorMessage{
 #[derive(Clone)]
 pub struct NozzleOperator{
     net_tx: broadcast::Sender<NetMessage>,
+    calibration: CalibrationConfig,
 }
 
 impl NozzleOperator{
-    pub async fn new(net_tx: broadcast::Sender<NetMessage>) -> NozzleOperator {
+    pub async fn new(net_tx: broadcast::Sender<NetMessage>, calibration: CalibrationConfig, shutdown: &Shutdown) -> NozzleOperator {
         let operator = Self{
             net_tx,
+            calibration,
         };
 
-        tokio::spawn(operator.clone().auto_target());
+        shutdown.spawn(operator.clone().auto_target());
 
         operator
         
@@ -48,7 +57,8 @@ impl NozzleOperator{
             loop{
                 if let Ok(NetMessage::FlirOperator(FlirOperatorMessage::Analysis(Some(analysis)))) = net_rx.recv().await{
                     let [delta_x, delta_y] = analysis.angle_change;
-                    if delta_x.abs() < LOCK_ON_ANGLE[0] && delta_y.abs() < LOCK_ON_ANGLE[0]{
+                    let [lock_x, lock_y] = self.calibration.lock_on_angle_deg;
+                    if delta_x.abs() < lock_x && delta_y.abs() < lock_y{
                         break;
                     }
                 }
@@ -66,14 +76,17 @@ impl NozzleOperator{
             let lidar_distance: u32;
             // Get flir angle
             loop{
-                if let Ok(NetMessage::LidarDriver(LidarDriverMessage::LidarDistanceCm(distance))) = net_rx.recv().await{
-                    lidar_distance = distance;
-                    break;
+                // The filtered range is used so a single spurious reading never sets the firing solution
+                if let Ok(NetMessage::LidarDriver(LidarDriverMessage::FilteredRange(range))) = net_rx.recv().await{
+                    if range.sensor == FLIR_LIDAR && range.samples >= self.calibration.min_range_samples{
+                        lidar_distance = range.median_cm as u32;
+                        break;
+                    }
                 }
             }
 
 
-            let mut new_angles = Self::calculate_firing_solution(flir_pan_angle, lidar_distance as f32 / 100.0);
+            let mut new_angles = self.calculate_firing_solution(flir_pan_angle, lidar_distance as f32 / 100.0);
             new_angles = [flir_pan_angle, new_angles[1]];
 
             let _ = self.net_tx.send(NetMessage::TurretDriver(TurretDriverMessage::SetAbsoluteAngle(NOZZLE_TURRET_PORT, new_angles)));
@@ -83,18 +96,17 @@ impl NozzleOperator{
     }
 
     /// Should return the required pan, tilt angles for the nozzle turret
-    fn calculate_firing_solution(flir_pan_angle: f32, lidar_distance_meter: f32) -> [f32; 2]{
+    fn calculate_firing_solution(&self, flir_pan_angle: f32, lidar_distance_meter: f32) -> [f32; 2]{
         let target_dir = Vec2::new(flir_pan_angle.cos(), flir_pan_angle.sin());
         let mut target_coords = target_dir * lidar_distance_meter;
         
-        let nozzle_turret_offset = Vec2::new(0.4572, 0.0);
+        let nozzle_turret_offset = Vec2::from(self.calibration.nozzle_offset_m);
 
         target_coords -= nozzle_turret_offset;
         
         let pan_angle = target_coords.angle_between(Vec2::X);
 
-        // m/s
-        let speed = 1.0;
+        let speed = self.calibration.water_speed_m_s;
 
         if let Some(tilt_angle) = Self::optimal_angle(lidar_distance_meter, 0.0, speed, 9.81){
             return [pan_angle, tilt_angle];
@@ -1,16 +1,352 @@
-use serde::{Serialize, Deserialize};
+use std::sync::{Arc, Mutex};
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
-pub enum PumpOperatorMessage{
-    
+use afv_internal::{output::OutputCommand, NOZZLE_TURRET_PORT};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, watch},
+    time::{interval, Duration, Instant},
+};
+
+use crate::{
+    drivers::{
+        estop::EmergencyStopMessage, lease::LeaseMessage, pump::PumpDriverMessage,
+        turret::TurretDriverMessage,
+    },
+    network::NetMessage,
+    shutdown::Shutdown,
+};
+
+/// How long an arm from the GCS lasts unless it is sent again
+pub const ARM_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the interlock is re-evaluated and its status published
+pub const INTERLOCK_INTERVAL: Duration = Duration::from_millis(250);
+/// The nozzle turret counts as still when two angle reports in a row are this close
+pub const NOZZLE_STILL_TOLERANCE_DEG: f32 = 0.5;
+/// A nozzle angle older than this many of its polls counts as moving, since the nozzle could have moved unseen
+pub const NOZZLE_ANGLE_STALE_POLLS: u32 = 3;
+/// The sector the nozzle may spray in until another is set with [PumpOperatorMessage::SetSector]
+pub const DEFAULT_NOZZLE_SECTOR: NozzleSector = NozzleSector {
+    min_pan: -90.0,
+    max_pan: 90.0,
+    min_tilt: -10.0,
+    max_tilt: 60.0,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PumpOperatorMessage {
+    /// Arms the pump for [ARM_TIMEOUT], sent again to keep it armed
+    Arm,
+    Disarm,
+    SetSector(NozzleSector),
+    /// Published every [INTERLOCK_INTERVAL]
+    Interlock(InterlockStatus),
+    /// A pump command that was refused, and why
+    Refused(PumpDriverMessage, RefusalReason),
+}
+
+/// Pan and tilt limits, in degrees, the nozzle turret must be inside for the pump to run
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct NozzleSector {
+    pub min_pan: f32,
+    pub max_pan: f32,
+    pub min_tilt: f32,
+    pub max_tilt: f32,
+}
+
+impl NozzleSector {
+    pub fn contains(&self, [pan, tilt]: [f32; 2]) -> bool {
+        (self.min_pan..=self.max_pan).contains(&pan)
+            && (self.min_tilt..=self.max_tilt).contains(&tilt)
+    }
+}
+
+/// Listed in the order they are checked, a command is refused for the first that applies
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefusalReason {
+    EStop,
+    Disarmed,
+    /// The nozzle turret has not reported its angle recently
+    NozzleUnknown,
+    NozzleMoving,
+    OutsideSector,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InterlockStatus {
+    /// None while disarmed
+    pub armed_remaining_ms: Option<u32>,
+    pub estop: bool,
+    pub nozzle_angle: Option<[f32; 2]>,
+    pub sector: NozzleSector,
+    /// None when the pump may run
+    pub refusal: Option<RefusalReason>,
+}
+
+struct Interlock {
+    armed_until: Option<Instant>,
+    estop: bool,
+    sector: NozzleSector,
+    /// How old a nozzle angle may be before the nozzle counts as moving
+    nozzle_stale: Duration,
+    /// The latest nozzle angle, when it was reported and whether it matched the report before it
+    nozzle: Option<([f32; 2], Instant, bool)>,
+}
+
+impl Interlock {
+    fn refusal(&self, now: Instant) -> Option<RefusalReason> {
+        if self.estop {
+            return Some(RefusalReason::EStop);
+        }
+        if !self.armed_until.map_or(false, |until| until > now) {
+            return Some(RefusalReason::Disarmed);
+        }
+        let (angle, reported, still) = match self.nozzle {
+            Some(nozzle) => nozzle,
+            None => return Some(RefusalReason::NozzleUnknown),
+        };
+        if now.duration_since(reported) > self.nozzle_stale || !still {
+            return Some(RefusalReason::NozzleMoving);
+        }
+        if !self.sector.contains(angle) {
+            return Some(RefusalReason::OutsideSector);
+        }
+        None
+    }
+    fn status(&self, now: Instant) -> InterlockStatus {
+        InterlockStatus {
+            armed_remaining_ms: self
+                .armed_until
+                .filter(|until| *until > now)
+                .map(|until| until.duration_since(now).as_millis() as u32),
+            estop: self.estop,
+            nozzle_angle: self.nozzle.map(|(angle, _, _)| angle),
+            sector: self.sector,
+            refusal: self.refusal(now),
+        }
+    }
+}
+
+#[derive(Clone)]
+/// The PumpOperator is the pump's safety interlock. The pump only runs while the AFV is armed from the GCS,
+/// the vehicle's E-stop is clear, and the nozzle turret is still and inside its sector.
+///
+/// The [PumpDriver](crate::drivers::pump::PumpDriver) holds the operator's [PumpOperator::permit] and drops every
+/// command while it is false, stopping the pump when it turns false. The operator publishes the reason each
+/// refused command was dropped for.
+pub struct PumpOperator {
+    net_tx: broadcast::Sender<NetMessage>,
+    interlock: Arc<Mutex<Interlock>>,
+    permit_tx: Arc<watch::Sender<bool>>,
 }
 
-pub struct PumpOperator{
-    
+impl PumpOperator {
+    /// * `nozzle_poll_interval` - How often the nozzle turret's driver polls it for its angle
+    pub fn new(
+        net_tx: broadcast::Sender<NetMessage>,
+        nozzle_poll_interval: Duration,
+        shutdown: &Shutdown,
+    ) -> Self {
+        let operator = Self {
+            net_tx,
+            interlock: Arc::new(Mutex::new(Interlock {
+                armed_until: None,
+                estop: false,
+                sector: DEFAULT_NOZZLE_SECTOR,
+                nozzle_stale: nozzle_poll_interval * NOZZLE_ANGLE_STALE_POLLS,
+                nozzle: None,
+            })),
+            permit_tx: Arc::new(watch::channel(false).0),
+        };
+
+        shutdown.spawn(operator.clone().intake_task());
+        shutdown.spawn(operator.clone().evaluate_task());
+
+        operator
+    }
+    /// True while the pump may run
+    pub fn permit(&self) -> watch::Receiver<bool> {
+        self.permit_tx.subscribe()
+    }
+    /// Whether a pump command would start or keep the pump running
+    fn runs_pump(msg: &PumpDriverMessage) -> bool {
+        match msg {
+            PumpDriverMessage::TurnOn
+            | PumpDriverMessage::RunFor(_)
+            | PumpDriverMessage::RunVolume(_) => true,
+            PumpDriverMessage::Hold(command)
+            | PumpDriverMessage::Lease(LeaseMessage::Acquire { command, .. }) => {
+                !matches!(command, OutputCommand::Off | OutputCommand::Duty(0))
+            }
+            _ => false,
+        }
+    }
+    async fn intake_task(self) {
+        let mut net_rx = self.net_tx.subscribe();
+
+        loop {
+            let msg = match net_rx.recv().await {
+                Ok(msg) => msg,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let now = Instant::now();
+            let mut interlock = self.interlock.lock().unwrap();
+            match msg {
+                NetMessage::PumpOperator(PumpOperatorMessage::Arm) if interlock.estop => {
+                    warn!("Pump not armed, the E-stop is latched");
+                }
+                NetMessage::PumpOperator(PumpOperatorMessage::Arm) => {
+                    if interlock.armed_until.map_or(true, |until| until <= now) {
+                        info!("Pump armed");
+                    }
+                    interlock.armed_until = Some(now + ARM_TIMEOUT);
+                }
+                NetMessage::PumpOperator(PumpOperatorMessage::Disarm) => {
+                    info!("Pump disarmed");
+                    interlock.armed_until = None;
+                }
+                // Resetting the E-stop leaves the pump disarmed
+                NetMessage::EmergencyStop(
+                    EmergencyStopMessage::Stop(_) | EmergencyStopMessage::Status(true),
+                ) => {
+                    interlock.estop = true;
+                    interlock.armed_until = None;
+                }
+                NetMessage::EmergencyStop(EmergencyStopMessage::Reset) => {
+                    interlock.estop = false;
+                }
+                NetMessage::PumpOperator(PumpOperatorMessage::SetSector(sector)) => {
+                    interlock.sector = sector;
+                }
+                NetMessage::TurretDriver(TurretDriverMessage::Angle(NOZZLE_TURRET_PORT, angle)) => {
+                    let still = interlock.nozzle.map_or(false, |(last, _, _)| {
+                        (last[0] - angle[0]).abs() <= NOZZLE_STILL_TOLERANCE_DEG
+                            && (last[1] - angle[1]).abs() <= NOZZLE_STILL_TOLERANCE_DEG
+                    });
+                    interlock.nozzle = Some((angle, now, still));
+                }
+                // Any request to move the nozzle means it is about to be moving
+                NetMessage::TurretDriver(
+                    TurretDriverMessage::SetAbsoluteAngle(NOZZLE_TURRET_PORT, _)
+                    | TurretDriverMessage::SetAngleChange(NOZZLE_TURRET_PORT, _),
+                ) => {
+                    if let Some(nozzle) = interlock.nozzle.as_mut() {
+                        nozzle.2 = false;
+                    }
+                }
+                NetMessage::PumpDriver(command) if Self::runs_pump(&command) => {
+                    if let Some(reason) = interlock.refusal(now) {
+                        warn!("Refused pump command {:?}: {:?}", command, reason);
+                        drop(interlock);
+                        let _ = self.net_tx.send(NetMessage::PumpOperator(
+                            PumpOperatorMessage::Refused(command, reason),
+                        ));
+                        continue;
+                    }
+                }
+                _ => continue,
+            }
+            let permitted = interlock.refusal(now).is_none();
+            drop(interlock);
+            self.permit_tx
+                .send_if_modified(|permit| std::mem::replace(permit, permitted) != permitted);
+        }
+    }
+    /// Catches the arm timing out and the nozzle angle going stale, which no message announces
+    async fn evaluate_task(self) {
+        let mut interval = interval(INTERLOCK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            let status = self.interlock.lock().unwrap().status(Instant::now());
+            let permitted = status.refusal.is_none();
+            if self
+                .permit_tx
+                .send_if_modified(|permit| std::mem::replace(permit, permitted) != permitted)
+                && !permitted
+            {
+                warn!("Pump stopped by its interlock: {:?}", status.refusal);
+            }
+            let _ = self
+                .net_tx
+                .send(NetMessage::PumpOperator(PumpOperatorMessage::Interlock(
+                    status,
+                )));
+        }
+    }
 }
 
-impl PumpOperator{
-    pub fn new(){
-        
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STALE: Duration = Duration::from_secs(3);
+
+    /// An interlock that lets the pump run, with the nozzle reported still at `now`
+    fn clear(now: Instant) -> Interlock {
+        Interlock {
+            armed_until: Some(now + ARM_TIMEOUT),
+            estop: false,
+            sector: DEFAULT_NOZZLE_SECTOR,
+            nozzle_stale: STALE,
+            nozzle: Some(([0.0, 0.0], now, true)),
+        }
+    }
+
+    #[test]
+    fn refusals_are_given_in_priority_order() {
+        let now = Instant::now();
+        let mut interlock = Interlock {
+            armed_until: None,
+            estop: true,
+            sector: DEFAULT_NOZZLE_SECTOR,
+            nozzle_stale: STALE,
+            nozzle: None,
+        };
+        assert_eq!(interlock.refusal(now), Some(RefusalReason::EStop));
+        interlock.estop = false;
+        assert_eq!(interlock.refusal(now), Some(RefusalReason::Disarmed));
+        interlock.armed_until = Some(now + ARM_TIMEOUT);
+        assert_eq!(interlock.refusal(now), Some(RefusalReason::NozzleUnknown));
+        interlock.nozzle = Some(([120.0, 0.0], now, false));
+        assert_eq!(interlock.refusal(now), Some(RefusalReason::NozzleMoving));
+        interlock.nozzle = Some(([120.0, 0.0], now, true));
+        assert_eq!(interlock.refusal(now), Some(RefusalReason::OutsideSector));
+        interlock.nozzle = Some(([0.0, 0.0], now, true));
+        assert_eq!(interlock.refusal(now), None);
+    }
+
+    #[test]
+    fn an_expired_arm_is_disarmed() {
+        let now = Instant::now();
+        let interlock = clear(now);
+        assert_eq!(
+            interlock.refusal(now + ARM_TIMEOUT),
+            Some(RefusalReason::Disarmed)
+        );
+        assert_eq!(interlock.status(now + ARM_TIMEOUT).armed_remaining_ms, None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn a_stale_nozzle_angle_counts_as_moving() {
+        let now = Instant::now();
+        let interlock = clear(now);
+        assert_eq!(interlock.refusal(now + STALE), None);
+        assert_eq!(
+            interlock.refusal(now + STALE + Duration::from_millis(1)),
+            Some(RefusalReason::NozzleMoving)
+        );
+    }
+
+    #[test]
+    fn the_sector_includes_its_limits() {
+        let now = Instant::now();
+        let mut interlock = clear(now);
+        let sector = DEFAULT_NOZZLE_SECTOR;
+        interlock.nozzle = Some(([sector.max_pan, sector.min_tilt], now, true));
+        assert_eq!(interlock.refusal(now), None);
+        interlock.nozzle = Some(([sector.max_pan, sector.min_tilt - 0.1], now, true));
+        assert_eq!(interlock.refusal(now), Some(RefusalReason::OutsideSector));
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::broadcast,
+    time::{timeout_at, Duration, Instant},
+};
+
+use crate::{
+    drivers::{lidar::LidarDriverMessage, turret::TurretDriverMessage},
+    network::NetMessage,
+    shutdown::Shutdown,
+};
+
+/// Valid readings taken at each point of a sweep
+pub const SAMPLES_PER_POINT: usize = 3;
+/// How long a sweep waits at a point for the turret to arrive and the lidar to answer before moving on
+pub const POINT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How close a reported turret angle must be to the commanded one before readings are taken
+pub const SETTLED_ANGLE: f32 = 1.0;
+/// How far from a requested direction [RangeMapMessage::Lookup] searches for a point
+pub const LOOKUP_RADIUS: f32 = 3.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+/// A raster over a sector, angles are in degrees as reported by the turret driver
+pub struct SweepRequest {
+    pub turret_port: u16,
+    /// The lidar sensor mounted on the turret
+    pub sensor: u8,
+    pub pan: [f32; 2],
+    pub tilt: [f32; 2],
+    /// The spacing between points on both axes
+    pub step: f32,
+}
+
+impl SweepRequest {
+    /// The sweep's points row by row, alternating pan direction so the turret never swings back across the sector
+    pub fn targets(&self) -> Vec<[f32; 2]> {
+        let step = self.step.abs().max(0.1);
+        let axis = |[start, end]: [f32; 2]| {
+            let count = ((end - start).abs() / step).floor() as usize + 1;
+            let direction = if end < start { -1.0 } else { 1.0 };
+            (0..count)
+                .map(|i| start + direction * step * i as f32)
+                .collect::<Vec<f32>>()
+        };
+        let pans = axis(self.pan);
+        let mut targets = vec![];
+        for (row, tilt) in axis(self.tilt).into_iter().enumerate() {
+            let mut line: Vec<[f32; 2]> = pans.iter().map(|pan| [*pan, tilt]).collect();
+            if row % 2 == 1 {
+                line.reverse();
+            }
+            targets.extend(line);
+        }
+        targets
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+/// A lidar reading paired with the turret angle it was taken at
+pub struct RangePoint {
+    pub pan: f32,
+    pub tilt: f32,
+    pub range_cm: f32,
+}
+
+impl RangePoint {
+    /// The point in meters relative to the turret, x forward at zero pan and tilt, y left and z up
+    pub fn position(&self) -> [f32; 3] {
+        let (pan, tilt) = (self.pan.to_radians(), self.tilt.to_radians());
+        let range = self.range_cm / 100.0;
+        [
+            range * tilt.cos() * pan.cos(),
+            range * tilt.cos() * pan.sin(),
+            range * tilt.sin(),
+        ]
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapFormat {
+    Ply,
+    Csv,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+/// The polar range map built by the latest sweep
+pub struct RangeMap {
+    pub sweep: Option<SweepRequest>,
+    pub points: Vec<RangePoint>,
+}
+
+impl RangeMap {
+    /// The point closest to a direction, if any lies within [LOOKUP_RADIUS]
+    pub fn nearest(&self, [pan, tilt]: [f32; 2]) -> Option<RangePoint> {
+        self.points
+            .iter()
+            .map(|p| (p, (p.pan - pan).hypot(p.tilt - tilt)))
+            .filter(|(_, distance)| *distance <= LOOKUP_RADIUS)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(p, _)| *p)
+    }
+    pub fn write_ply(&self, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "ply")?;
+        writeln!(writer, "format ascii 1.0")?;
+        writeln!(writer, "element vertex {}", self.points.len())?;
+        writeln!(writer, "property float x")?;
+        writeln!(writer, "property float y")?;
+        writeln!(writer, "property float z")?;
+        writeln!(writer, "end_header")?;
+        for point in self.points.iter() {
+            let [x, y, z] = point.position();
+            writeln!(writer, "{} {} {}", x, y, z)?;
+        }
+        Ok(())
+    }
+    pub fn write_csv(&self, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "pan,tilt,range_cm,x,y,z")?;
+        for point in self.points.iter() {
+            let [x, y, z] = point.position();
+            writeln!(
+                writer,
+                "{},{},{},{},{},{}",
+                point.pan, point.tilt, point.range_cm, x, y, z
+            )?;
+        }
+        Ok(())
+    }
+    pub fn export(&self, path: impl AsRef<Path>, format: MapFormat) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        match format {
+            MapFormat::Ply => self.write_ply(&mut writer)?,
+            MapFormat::Csv => self.write_csv(&mut writer)?,
+        }
+        writer.flush()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RangeMapMessage {
+    /// Starts a sweep, ignored while another is running
+    Sweep(SweepRequest),
+    /// Stops the running sweep, keeping the points taken so far
+    Cancel,
+    /// Published when a sweep finishes or is cancelled
+    Map(RangeMap),
+    /// Asks for the range in a direction of the last map
+    Lookup([f32; 2]),
+    Range([f32; 2], Option<RangePoint>),
+    /// Writes the last map to a file of this name in the AFV's configured range map directory.
+    /// Names that are not a plain file name are refused
+    Export(String, MapFormat),
+}
+
+#[derive(Clone)]
+pub struct RangeMapOperator {
+    net_tx: broadcast::Sender<NetMessage>,
+    export_dir: Option<PathBuf>,
+    map: Arc<Mutex<RangeMap>>,
+    sweeping: Arc<AtomicBool>,
+    shutdown: Shutdown,
+}
+
+impl RangeMapOperator {
+    /// * `export_dir` - The directory [RangeMapMessage::Export] writes to, exports are refused without one
+    pub async fn new(
+        net_tx: broadcast::Sender<NetMessage>,
+        export_dir: Option<PathBuf>,
+        shutdown: &Shutdown,
+    ) -> RangeMapOperator {
+        let operator = Self {
+            net_tx,
+            export_dir,
+            map: Default::default(),
+            sweeping: Default::default(),
+            shutdown: shutdown.clone(),
+        };
+
+        shutdown.spawn(operator.clone().command_task());
+
+        operator
+    }
+
+    async fn command_task(self) {
+        let mut net_rx = self.net_tx.subscribe();
+        loop {
+            let msg = match net_rx.recv().await {
+                Ok(NetMessage::RangeMap(msg)) => msg,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            match msg {
+                RangeMapMessage::Sweep(request) => {
+                    if self.sweeping.swap(true, Ordering::SeqCst) {
+                        warn!("A range map sweep is already running");
+                        continue;
+                    }
+                    self.shutdown.spawn(self.clone().sweep_task(request));
+                }
+                RangeMapMessage::Lookup(direction) => {
+                    let point = self.map.lock().unwrap().nearest(direction);
+                    let _ = self
+                        .net_tx
+                        .send(NetMessage::RangeMap(RangeMapMessage::Range(
+                            direction, point,
+                        )));
+                }
+                RangeMapMessage::Export(name, format) => {
+                    let path = match self.export_path(&name) {
+                        Ok(path) => path,
+                        Err(e) => {
+                            warn!("Range map export refused: {}", e);
+                            continue;
+                        }
+                    };
+                    let map = self.map.lock().unwrap().clone();
+                    match map.export(&path, format) {
+                        Ok(_) => info!(
+                            "Exported {} range map points to {:?}",
+                            map.points.len(),
+                            path
+                        ),
+                        Err(e) => error!("Could not export the range map to {:?}: {}", path, e),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Where an export named `name` is written, only ever a file directly inside the export directory
+    fn export_path(&self, name: &str) -> Result<PathBuf, String> {
+        let dir = match &self.export_dir {
+            Some(dir) => dir,
+            None => return Err("no range map directory is configured".into()),
+        };
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) if !name.contains(&['/', '\\'][..]) => {
+                Ok(dir.join(name))
+            }
+            _ => Err(format!("{:?} is not a file name", name)),
+        }
+    }
+
+    async fn sweep_task(self, request: SweepRequest) {
+        let mut net_rx = self.net_tx.subscribe();
+        let targets = request.targets();
+        info!(
+            "Sweeping turret {} over {} points",
+            request.turret_port,
+            targets.len()
+        );
+        let mut map = RangeMap {
+            sweep: Some(request),
+            points: vec![],
+        };
+
+        'sweep: for target in targets {
+            let _ = self.net_tx.send(NetMessage::TurretDriver(
+                TurretDriverMessage::SetAbsoluteAngle(request.turret_port, target),
+            ));
+            let deadline = Instant::now() + POINT_TIMEOUT;
+            // Readings are only paired once the turret reports it has reached the point
+            let mut angle: Option<[f32; 2]> = None;
+            let mut samples = 0;
+            while samples < SAMPLES_PER_POINT {
+                let msg = match timeout_at(deadline, net_rx.recv()).await {
+                    Err(_) => {
+                        warn!("Range map sweep skipped {:?}", target);
+                        break;
+                    }
+                    Ok(Err(broadcast::error::RecvError::Closed)) => return,
+                    Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                    Ok(Ok(msg)) => msg,
+                };
+                match msg {
+                    NetMessage::RangeMap(RangeMapMessage::Cancel) => break 'sweep,
+                    NetMessage::TurretDriver(TurretDriverMessage::Angle(port, reported))
+                        if port == request.turret_port =>
+                    {
+                        let settled = (reported[0] - target[0]).abs() <= SETTLED_ANGLE
+                            && (reported[1] - target[1]).abs() <= SETTLED_ANGLE;
+                        angle = Some(reported).filter(|_| settled);
+                    }
+                    NetMessage::LidarDriver(LidarDriverMessage::Measurement(
+                        sensor,
+                        measurement,
+                    )) if sensor == request.sensor && measurement.valid => {
+                        if let Some([pan, tilt]) = angle {
+                            map.points.push(RangePoint {
+                                pan,
+                                tilt,
+                                range_cm: measurement.distance_cm as f32,
+                            });
+                            samples += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        info!("Range map sweep finished with {} points", map.points.len());
+        *self.map.lock().unwrap() = map.clone();
+        self.sweeping.store(false, Ordering::SeqCst);
+        let _ = self
+            .net_tx
+            .send(NetMessage::RangeMap(RangeMapMessage::Map(map)));
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    sync::broadcast,
+    time::{sleep_until, Duration, Instant},
+};
+
+use crate::{network::NetMessage, shutdown::Shutdown};
+
+/// Every recording starts with this, followed by its records
+pub const RECORDING_MAGIC: &[u8; 8] = b"AFVREC01";
+/// A record longer than this, in bytes, means the recording is corrupt
+pub const MAX_RECORD_LEN: u32 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RecordedEvent {
+    /// A message published on the bus
+    Bus(NetMessage),
+    /// A packet of the FLIR's IR video, which only reaches the bus while the GCS is watching it
+    IrNal(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Record {
+    /// When the event happened, in µs since the recording started
+    pub at_us: u64,
+    pub event: RecordedEvent,
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[derive(Clone)]
+/// The Recorder writes every message published on the bus, along with when it was published, to a recording
+/// that a [Replay] can play back later.
+///
+/// A recording is [RECORDING_MAGIC] followed by its records, each a little endian u32 length and a bincode encoded [Record].
+/// The recording is flushed and closed once the [Shutdown] is triggered.
+pub struct Recorder {
+    started: Instant,
+    record_tx: flume::Sender<Record>,
+    shutdown: Shutdown,
+}
+
+impl Recorder {
+    /// Creates the recording, replacing any file at the path, and starts recording the bus into it
+    pub async fn start(
+        path: &Path,
+        net_tx: &broadcast::Sender<NetMessage>,
+        shutdown: &Shutdown,
+    ) -> io::Result<Recorder> {
+        let mut file = BufWriter::new(File::create(path).await?);
+        file.write_all(RECORDING_MAGIC).await?;
+
+        let (record_tx, record_rx) = flume::unbounded();
+        let recorder = Self {
+            started: Instant::now(),
+            record_tx,
+            shutdown: shutdown.clone(),
+        };
+        info!("Recording the bus to {:?}", path);
+
+        shutdown.track(tokio::spawn(Self::write_task(
+            file,
+            record_rx,
+            shutdown.clone(),
+        )));
+        shutdown.spawn(recorder.clone().bus_task(net_tx.subscribe()));
+
+        Ok(recorder)
+    }
+    /// Also records the FLIR's IR video, see [FlirOperator::ir_stream_rx](crate::operators::flir::FlirOperator::ir_stream_rx)
+    pub fn record_ir_stream(&self, ir_rx: broadcast::Receiver<Vec<u8>>) {
+        self.shutdown.spawn(self.clone().ir_task(ir_rx));
+    }
+    /// False once the recording has stopped
+    fn record(&self, event: RecordedEvent) -> bool {
+        let record = Record {
+            at_us: self.started.elapsed().as_micros() as u64,
+            event,
+        };
+        self.record_tx.send(record).is_ok()
+    }
+    async fn bus_task(self, mut net_rx: broadcast::Receiver<NetMessage>) {
+        loop {
+            match net_rx.recv().await {
+                Ok(msg) => {
+                    if !self.record(RecordedEvent::Bus(msg)) {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("The recording missed {} bus messages", missed)
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+    async fn ir_task(self, mut ir_rx: broadcast::Receiver<Vec<u8>>) {
+        loop {
+            match ir_rx.recv().await {
+                Ok(nal) => {
+                    if !self.record(RecordedEvent::IrNal(nal)) {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("The recording missed {} IR nal packets", missed)
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+    async fn write_task(
+        mut file: BufWriter<File>,
+        record_rx: flume::Receiver<Record>,
+        shutdown: Shutdown,
+    ) {
+        loop {
+            let record = tokio::select! {
+                record = record_rx.recv_async() => match record {
+                    Ok(record) => record,
+                    Err(_) => break,
+                },
+                _ = shutdown.triggered() => break,
+            };
+            if let Err(e) = Self::write_record(&mut file, &record).await {
+                error!("Recording stopped, it could not be written to: {}", e);
+                return;
+            }
+        }
+
+        // Whatever was recorded before the shutdown is kept
+        for record in record_rx.drain() {
+            if let Err(e) = Self::write_record(&mut file, &record).await {
+                error!("Recording stopped, it could not be written to: {}", e);
+                return;
+            }
+        }
+        match file.flush().await {
+            Ok(_) => info!("Recording closed"),
+            Err(e) => error!("Recording could not be flushed: {}", e),
+        }
+    }
+    async fn write_record(file: &mut BufWriter<File>, record: &Record) -> io::Result<()> {
+        let data = bincode::serialize(record).map_err(invalid_data)?;
+        file.write_u32_le(data.len() as u32).await?;
+        file.write_all(&data).await
+    }
+}
+
+/// Reads the records of a recording in the order they were recorded
+pub struct RecordingReader {
+    file: BufReader<File>,
+}
+
+impl RecordingReader {
+    pub async fn open(path: &Path) -> io::Result<RecordingReader> {
+        let mut file = BufReader::new(File::open(path).await?);
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic).await?;
+        if &magic != RECORDING_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} is not a recording", path),
+            ));
+        }
+        Ok(Self { file })
+    }
+    /// The next record, None once the recording has ended.
+    /// A record cut short, such as by the AFV losing power, ends the recording
+    pub async fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let len = match self.file.read_u32_le().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if len > MAX_RECORD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record of {} bytes is too long", len),
+            ));
+        }
+
+        let mut data = vec![0u8; len as usize];
+        match self.file.read_exact(&mut data).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                warn!("The recording ends with a partial record");
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+        bincode::deserialize(&data).map(Some).map_err(invalid_data)
+    }
+}
+
+#[derive(Clone)]
+/// Replay publishes a recording into a bus with the timing it was recorded with, scaled by [Replay::speed].
+///
+/// Played into a fresh bus it stands in for the AFV it was recorded on, and with [Replay::filter] and
+/// [Replay::ir_stream] it can feed a [FlirOperator](crate::operators::flir::FlirOperator) or
+/// [NozzleOperator](crate::operators::nozzle::NozzleOperator) what they saw in the field.
+pub struct Replay {
+    path: PathBuf,
+    speed: f64,
+    filter: fn(&NetMessage) -> bool,
+    ir_stream: Option<broadcast::Sender<Vec<u8>>>,
+}
+
+impl Replay {
+    pub fn new(path: impl Into<PathBuf>) -> Replay {
+        Self {
+            path: path.into(),
+            speed: 1.0,
+            filter: |_| true,
+            ir_stream: None,
+        }
+    }
+    /// How many times faster than it was recorded the recording is played, 1 by default.
+    /// [f64::INFINITY] plays it as fast as it can be read
+    pub fn speed(mut self, speed: f64) -> Replay {
+        self.speed = speed;
+        self
+    }
+    /// Only publishes the bus messages the filter keeps, every message by default
+    pub fn filter(mut self, filter: fn(&NetMessage) -> bool) -> Replay {
+        self.filter = filter;
+        self
+    }
+    /// Publishes the recorded IR video to the stream, such as one a FlirOperator was created on with
+    /// [FlirOperator::from_ir_stream](crate::operators::flir::FlirOperator::from_ir_stream). It is dropped otherwise
+    pub fn ir_stream(mut self, ir_stream: broadcast::Sender<Vec<u8>>) -> Replay {
+        self.ir_stream = Some(ir_stream);
+        self
+    }
+    /// Publishes the recording into the bus, returning once all of it has been published
+    pub async fn play(&self, net_tx: &broadcast::Sender<NetMessage>) -> io::Result<()> {
+        if self.speed.is_nan() || self.speed <= 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the replay speed must be above 0",
+            ));
+        }
+        let mut recording = RecordingReader::open(&self.path).await?;
+        info!("Replaying {:?} at {}x", self.path, self.speed);
+
+        let started = Instant::now();
+        while let Some(record) = recording.read_record().await? {
+            sleep_until(started + Duration::from_micros(record.at_us).div_f64(self.speed)).await;
+            match record.event {
+                RecordedEvent::Bus(msg) => {
+                    if (self.filter)(&msg) {
+                        let _ = net_tx.send(msg);
+                    }
+                }
+                RecordedEvent::IrNal(nal) => {
+                    if let Some(ir_stream) = &self.ir_stream {
+                        let _ = ir_stream.send(nal);
+                    }
+                }
+            }
+        }
+        info!("Replay of {:?} finished", self.path);
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use log::warn;
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{timeout, Duration},
+};
+
+/// How long [Shutdown::stop] waits on the tasks before aborting the ones left
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+/// The shutdown signal shared by every driver and operator of an AFV.
+///
+/// Long running tasks are spawned through [Shutdown::spawn], which ends them at their next await once the
+/// shutdown is triggered. Tasks that have to clean up, such as a [DriverSupervisor](crate::drivers::supervisor::DriverSupervisor)
+/// putting its driver in a safe state, wait on [Shutdown::triggered] themselves and are handed over with [Shutdown::track].
+/// Either way their handles are kept so [Shutdown::stop] can wait for them to end.
+pub struct Shutdown {
+    triggered: Arc<watch::Sender<bool>>,
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            triggered: Arc::new(watch::channel(false).0),
+            tasks: Default::default(),
+        }
+    }
+    /// Spawns a task that is dropped once the shutdown is triggered
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let shutdown = self.clone();
+        self.track(tokio::spawn(async move {
+            tokio::select! {
+                _ = task => {}
+                _ = shutdown.triggered() => {}
+            }
+        }));
+    }
+    /// Keeps the handle of a task that ends itself once the shutdown is triggered
+    pub fn track(&self, task: JoinHandle<()>) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+    /// Completes once the shutdown is triggered
+    pub async fn triggered(&self) {
+        let mut triggered = self.triggered.subscribe();
+        while !*triggered.borrow_and_update() {
+            // The sender lives as long as self, so this never errors
+            if triggered.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+    /// Triggers the shutdown and waits up to [STOP_TIMEOUT] for every task to end, aborting any that are left
+    pub async fn stop(&self) {
+        self.trigger();
+
+        let mut pending: Vec<JoinHandle<()>> = vec![];
+        let wait = async {
+            loop {
+                // Tasks may still be handed over while others are ending
+                pending.extend(self.tasks.lock().unwrap().drain(..));
+                match pending.last_mut() {
+                    Some(task) => {
+                        let _ = task.await;
+                        pending.pop();
+                    }
+                    None => return,
+                }
+            }
+        };
+        if timeout(STOP_TIMEOUT, wait).await.is_err() {
+            pending.extend(self.tasks.lock().unwrap().drain(..));
+            warn!("Aborting {} tasks that did not stop in time", pending.len());
+            for task in pending {
+                task.abort();
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use clap::Parser;
 
@@ -9,7 +9,8 @@ use eframe::{
 use tokio::{runtime::Runtime, sync::Mutex};
 
 use crate::{
-    communicators::afv::AfvCommuncation, network::scanner::ScanCount, operators::afv_launcher,
+    communicators::afv::AfvCommuncation, config::Config, network::scanner::ScanCount,
+    operators::afv_launcher, recorder::Replay,
 };
 
 /// Generic representation of a renderable object
@@ -22,6 +23,15 @@ pub trait Renderable {
 struct GcsArgs {
     #[arg(short, long)]
     simulate: bool,
+    /// The TOML configuration file, afv.toml in the working directory is used if it exists
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Plays a recording made with `afv --record` as a virtual AFV
+    #[arg(long, conflicts_with = "simulate")]
+    replay: Option<PathBuf>,
+    /// How many times faster than it was recorded the replay is played
+    #[arg(long, default_value_t = 1.0, requires = "replay")]
+    replay_speed: f64,
 }
 
 /// This is the main starting struct for the ground station
@@ -33,30 +43,53 @@ pub struct GcsUi {
 }
 
 impl GcsUi {
-    /// Starts the eframe event loop
+    /// Loads the configuration and starts the eframe event loop, exiting if the configuration is invalid
     pub fn launch() {
+        let args = GcsArgs::parse();
+        let config = match Config::load(args.config.as_deref()) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        if args.replay_speed.is_nan() || args.replay_speed <= 0.0 {
+            eprintln!("--replay-speed must be above 0");
+            std::process::exit(1);
+        }
+        let replay = args
+            .replay
+            .map(|path| Replay::new(path).speed(args.replay_speed));
         eframe::run_native(
             "Afv Ground Control Station",
             Default::default(),
-            Box::new(|cc| Self::run(cc)),
+            Box::new(move |cc| Self::run(cc, args.simulate, replay, config)),
         );
     }
-    pub fn run(_cc: &CreationContext) -> Box<GcsUi> {
-        let args = GcsArgs::parse();
+    pub fn run(
+        _cc: &CreationContext,
+        simulate: bool,
+        replay: Option<Replay>,
+        config: Config,
+    ) -> Box<GcsUi> {
         let runtime = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .expect("Could not start tokio runtime");
 
-        if args.simulate {
-            runtime.spawn(afv_launcher::simulate());
+        let scans = config.network.gcs_scans;
+        if simulate {
+            runtime.spawn(afv_launcher::simulate(config));
+        }
+        if let Some(replay) = replay {
+            runtime.spawn(afv_launcher::replay(replay));
         }
 
         let connected_afvs = Arc::new(Mutex::new(vec![]));
 
         runtime.spawn(AfvCommuncation::find_afvs(
             connected_afvs.clone(),
-            ScanCount::Limited(2),
+            ScanCount::Limited(scans),
         ));
 
         Box::new(Self {
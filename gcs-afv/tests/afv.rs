@@ -0,0 +1,153 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use gcs_afv::{
+    config::{Config, DevicesConfig, DriversConfig, OperatorsConfig},
+    drivers::{
+        lights::LightsDriverMessage,
+        supervisor::{ConnectionState, SupervisorMessage},
+    },
+    emulator::{EmulatedService, Emulator, LidarModel},
+    network::NetMessage,
+    operators::afv_launcher::{self, Afv},
+};
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpListener,
+    time::{interval, sleep, timeout, Duration},
+};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Every driver connects straight to the emulator, so nothing scans the network
+fn local_config() -> Config {
+    let local =
+        |service: EmulatedService| Some(SocketAddr::from((Ipv4Addr::LOCALHOST, service.port())));
+    Config {
+        drivers: DriversConfig {
+            board_log: false,
+            ..Default::default()
+        },
+        devices: DevicesConfig {
+            flir_turret: local(EmulatedService::FlirTurret),
+            nozzle_turret: local(EmulatedService::NozzleTurret),
+            lidar: local(EmulatedService::Lidar),
+            pump: local(EmulatedService::Pump),
+            lights: local(EmulatedService::Lights),
+            siren: local(EmulatedService::Siren),
+        },
+        operators: OperatorsConfig {
+            naming: false,
+            flir: false,
+            nozzle: false,
+            range_map: false,
+        },
+        ..Default::default()
+    }
+}
+
+async fn wait_until(done: impl Fn() -> bool) {
+    let wait = async {
+        while !done() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+    timeout(TEST_TIMEOUT, wait)
+        .await
+        .expect("the emulator never reached the expected state");
+}
+
+#[tokio::test]
+async fn stopping_turns_outputs_off_and_ends_every_task() {
+    let emulator = Emulator::new(LidarModel::default())
+        .bind(Ipv4Addr::LOCALHOST)
+        .motion_timing(false);
+    emulator.start().await.unwrap();
+
+    let afv = Afv::start(&local_config()).await;
+    let net_tx = afv.net_tx();
+    let mut net_rx = net_tx.subscribe();
+    let connected = async {
+        loop {
+            if let Ok(NetMessage::Supervisor(SupervisorMessage::ConnectionState(
+                name,
+                ConnectionState::Connected,
+            ))) = net_rx.recv().await
+            {
+                if name == "Lights" {
+                    return;
+                }
+            }
+        }
+    };
+    timeout(TEST_TIMEOUT, connected)
+        .await
+        .expect("the lights never connected");
+    drop(net_rx);
+
+    net_tx
+        .send(NetMessage::LightDriver(LightsDriverMessage::TurnOn))
+        .unwrap();
+    wait_until(|| emulator.output_on(EmulatedService::Lights)).await;
+
+    timeout(TEST_TIMEOUT, afv.stop())
+        .await
+        .expect("the AFV did not stop");
+    wait_until(|| !emulator.output_on(EmulatedService::Lights)).await;
+    assert!(!emulator.output_on(EmulatedService::Pump));
+    assert!(!emulator.output_on(EmulatedService::Siren));
+    // A driver or operator task left running would still hold its bus subscription
+    assert_eq!(net_tx.receiver_count(), 0);
+}
+
+#[tokio::test]
+async fn a_gcs_address_links_the_bridge_straight_to_it() {
+    let gcs = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let mut config = local_config();
+    config.drivers = DriversConfig {
+        flir_turret: false,
+        nozzle_turret: false,
+        lidar: false,
+        pump: false,
+        lights: false,
+        siren: false,
+        board_log: false,
+    };
+    config.devices = DevicesConfig::default();
+    config.network.gcs_address = Some(gcs.local_addr().unwrap());
+
+    let afv = afv_launcher::launch(config).await;
+    let net_tx = afv.net_tx();
+    let (mut stream, _) = timeout(TEST_TIMEOUT, gcs.accept())
+        .await
+        .expect("the bridge never connected to the GCS")
+        .unwrap();
+
+    // Sent until the bridge's forwarding task has subscribed to the bus
+    let mut resend = interval(Duration::from_millis(200));
+    let forwarded = async {
+        let mut data = vec![];
+        loop {
+            tokio::select! {
+                _ = resend.tick() => {
+                    let _ = net_tx.send(NetMessage::LightDriver(LightsDriverMessage::TurnOn));
+                }
+                byte = stream.read_u8() => {
+                    data.push(byte.unwrap());
+                    if let Ok(msg) = bincode::deserialize::<NetMessage>(&data) {
+                        if msg == NetMessage::LightDriver(LightsDriverMessage::TurnOn) {
+                            return;
+                        }
+                        data.clear();
+                    }
+                }
+            }
+        }
+    };
+    timeout(TEST_TIMEOUT, forwarded)
+        .await
+        .expect("the bridge never forwarded the bus to the GCS");
+
+    timeout(TEST_TIMEOUT, afv.stop())
+        .await
+        .expect("the AFV did not stop");
+}
@@ -0,0 +1,214 @@
+use std::path::PathBuf;
+
+use afv_internal::{FLIR_TURRET_PORT, NOZZLE_TURRET_PORT};
+use gcs_afv::{
+    config::CalibrationConfig,
+    drivers::{
+        lidar::{FilteredRange, LidarDriverMessage, FLIR_LIDAR},
+        lights::LightsDriverMessage,
+        turret::TurretDriverMessage,
+    },
+    network::NetMessage,
+    operators::{
+        flir::{FlirAnalysis, FlirOperator, FlirOperatorMessage},
+        nozzle::{NozzleOperator, NozzleOperatorMessage},
+    },
+    recorder::{RecordedEvent, Recorder, RecordingReader, Replay},
+    shutdown::Shutdown,
+};
+use tokio::{
+    sync::broadcast,
+    time::{sleep, timeout, Duration, Instant},
+};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+const GAP: Duration = Duration::from_millis(200);
+
+/// A recording path no other test uses
+fn recording_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("afv-{}-{}.rec", name, std::process::id()))
+}
+
+/// Records the messages onto a fresh bus, [GAP] apart
+async fn record(name: &str, messages: Vec<NetMessage>) -> PathBuf {
+    let path = recording_path(name);
+    let shutdown = Shutdown::new();
+    let (net_tx, _rx) = broadcast::channel(100);
+    Recorder::start(&path, &net_tx, &shutdown).await.unwrap();
+    for msg in messages {
+        net_tx.send(msg).unwrap();
+        sleep(GAP).await;
+    }
+    shutdown.stop().await;
+    path
+}
+
+#[tokio::test]
+async fn replay_keeps_the_order_content_and_scaled_timing_of_a_recording() {
+    let path = recording_path("round-trip");
+    let nal = vec![0, 0, 0, 1, 0x67, 0x42];
+    let shutdown = Shutdown::new();
+    let (net_tx, _rx) = broadcast::channel(100);
+    let (ir_tx, _ir_rx) = broadcast::channel(100);
+    let recorder = Recorder::start(&path, &net_tx, &shutdown).await.unwrap();
+    recorder.record_ir_stream(ir_tx.subscribe());
+
+    net_tx
+        .send(NetMessage::LightDriver(LightsDriverMessage::TurnOn))
+        .unwrap();
+    sleep(GAP).await;
+    ir_tx.send(nal.clone()).unwrap();
+    sleep(GAP).await;
+    net_tx
+        .send(NetMessage::NozzleOperator(
+            NozzleOperatorMessage::AutoTarget,
+        ))
+        .unwrap();
+    shutdown.stop().await;
+
+    let mut recording = RecordingReader::open(&path).await.unwrap();
+    let mut records = vec![];
+    while let Some(record) = recording.read_record().await.unwrap() {
+        records.push(record);
+    }
+    let events: Vec<_> = records.iter().map(|r| r.event.clone()).collect();
+    assert_eq!(
+        events,
+        vec![
+            RecordedEvent::Bus(NetMessage::LightDriver(LightsDriverMessage::TurnOn)),
+            RecordedEvent::IrNal(nal.clone()),
+            RecordedEvent::Bus(NetMessage::NozzleOperator(
+                NozzleOperatorMessage::AutoTarget
+            )),
+        ]
+    );
+    for pair in records.windows(2) {
+        assert!(pair[1].at_us - pair[0].at_us >= GAP.as_micros() as u64);
+    }
+
+    let (replay_tx, mut replay_rx) = broadcast::channel(100);
+    let (replay_ir_tx, mut replay_ir_rx) = broadcast::channel(100);
+    let replay = Replay::new(&path).speed(10.0).ir_stream(replay_ir_tx);
+    let started = Instant::now();
+    timeout(TEST_TIMEOUT, replay.play(&replay_tx))
+        .await
+        .expect("the replay never finished")
+        .unwrap();
+    let elapsed = started.elapsed();
+
+    assert_eq!(
+        replay_rx.recv().await.unwrap(),
+        NetMessage::LightDriver(LightsDriverMessage::TurnOn)
+    );
+    assert_eq!(
+        replay_rx.recv().await.unwrap(),
+        NetMessage::NozzleOperator(NozzleOperatorMessage::AutoTarget)
+    );
+    assert_eq!(replay_ir_rx.recv().await.unwrap(), nal);
+    // The recording spans two gaps, played ten times faster
+    assert!(elapsed >= GAP * 2 / 10);
+    assert!(elapsed < GAP * 2);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn replay_feeds_the_nozzle_operator_a_recorded_fire() {
+    let stale_solution = NetMessage::TurretDriver(TurretDriverMessage::SetAbsoluteAngle(
+        NOZZLE_TURRET_PORT,
+        [-1.0, -1.0],
+    ));
+    let path = record(
+        "nozzle",
+        vec![
+            NetMessage::NozzleOperator(NozzleOperatorMessage::AutoTarget),
+            NetMessage::FlirOperator(FlirOperatorMessage::Analysis(Some(FlirAnalysis {
+                angle_change: [0.5, 0.5],
+                ..Default::default()
+            }))),
+            NetMessage::TurretDriver(TurretDriverMessage::Angle(FLIR_TURRET_PORT, [10.0, 0.0])),
+            NetMessage::LidarDriver(LidarDriverMessage::FilteredRange(FilteredRange {
+                sensor: FLIR_LIDAR,
+                median_cm: 500.0,
+                mean_cm: 500.0,
+                variance: 0.0,
+                min_cm: 500,
+                max_cm: 500,
+                samples: 10,
+                rejected: 0,
+            })),
+            // What the nozzle operator on the AFV answered, which the replay leaves out
+            stale_solution,
+        ],
+    )
+    .await;
+
+    let shutdown = Shutdown::new();
+    let (net_tx, _rx) = broadcast::channel(100);
+    NozzleOperator::new(net_tx.clone(), CalibrationConfig::default(), &shutdown).await;
+    let mut net_rx = net_tx.subscribe();
+    // The operator subscribes to the bus once its task first runs
+    sleep(GAP).await;
+    let replay = Replay::new(&path).speed(10.0).filter(|msg| {
+        !matches!(
+            msg,
+            NetMessage::TurretDriver(TurretDriverMessage::SetAbsoluteAngle(..))
+        )
+    });
+    tokio::spawn(async move { replay.play(&net_tx).await });
+
+    let solution = async {
+        loop {
+            if let Ok(NetMessage::TurretDriver(TurretDriverMessage::SetAbsoluteAngle(
+                port,
+                angles,
+            ))) = net_rx.recv().await
+            {
+                return (port, angles);
+            }
+        }
+    };
+    let (port, [pan, _]) = timeout(TEST_TIMEOUT, solution)
+        .await
+        .expect("the nozzle operator never aimed the nozzle");
+    assert_eq!(port, NOZZLE_TURRET_PORT);
+    assert_eq!(pan, 10.0);
+
+    shutdown.stop().await;
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn replay_feeds_the_flir_operator_recorded_ir_video() {
+    let path = recording_path("flir");
+    let nal = vec![0, 0, 0, 1, 0x65, 0x88];
+    let shutdown = Shutdown::new();
+    let (net_tx, _rx) = broadcast::channel(100);
+    let (ir_tx, _ir_rx) = broadcast::channel(100);
+    let recorder = Recorder::start(&path, &net_tx, &shutdown).await.unwrap();
+    recorder.record_ir_stream(ir_tx.subscribe());
+    ir_tx.send(nal.clone()).unwrap();
+    sleep(GAP).await;
+    shutdown.stop().await;
+
+    let shutdown = Shutdown::new();
+    let (net_tx, _rx) = broadcast::channel(100);
+    let (replay_ir_tx, _replay_ir_rx) = broadcast::channel(100);
+    let operator = FlirOperator::from_ir_stream(
+        net_tx.clone(),
+        replay_ir_tx.clone(),
+        [29.0, 22.0],
+        &shutdown,
+    );
+    let mut operator_ir_rx = operator.ir_stream_rx();
+    Replay::new(&path)
+        .speed(f64::INFINITY)
+        .ir_stream(replay_ir_tx)
+        .play(&net_tx)
+        .await
+        .unwrap();
+    assert_eq!(operator_ir_rx.recv().await.unwrap(), nal);
+
+    shutdown.stop().await;
+    let _ = std::fs::remove_file(path);
+}
@@ -0,0 +1,70 @@
+use afv_internal::FLIR_TURRET_PORT;
+use gcs_afv::{
+    config::{Config, DriversConfig, OperatorsConfig},
+    drivers::{lights::LightsDriverMessage, turret::TurretDriverMessage},
+    network::NetMessage,
+    operators::afv_launcher,
+};
+use tokio::time::{interval, timeout, Duration};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[tokio::test]
+async fn simulate_runs_the_drivers_on_the_emulator() {
+    let config = Config {
+        drivers: DriversConfig {
+            board_log: false,
+            ..Default::default()
+        },
+        operators: OperatorsConfig {
+            naming: false,
+            flir: false,
+            nozzle: false,
+            range_map: false,
+        },
+        ..Default::default()
+    };
+    let afv = afv_launcher::simulate(config).await;
+    let net_tx = afv.net_tx();
+    let mut net_rx = net_tx.subscribe();
+
+    // The turret driver polls the emulated turret once it has connected
+    let polled = async {
+        loop {
+            if let Ok(NetMessage::TurretDriver(TurretDriverMessage::Angle(FLIR_TURRET_PORT, _))) =
+                net_rx.recv().await
+            {
+                return;
+            }
+        }
+    };
+    timeout(TEST_TIMEOUT, polled)
+        .await
+        .expect("the emulated FLIR turret never reported its angle");
+
+    // Repeated as a keep alive, which also covers the lights driver connecting late
+    let mut keepalive = interval(Duration::from_millis(200));
+    let lit = async {
+        loop {
+            tokio::select! {
+                _ = keepalive.tick() => {
+                    let _ = net_tx.send(NetMessage::LightDriver(LightsDriverMessage::TurnOn));
+                }
+                msg = net_rx.recv() => {
+                    if let Ok(NetMessage::LightDriver(LightsDriverMessage::State(state))) = msg {
+                        if state.active() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    };
+    timeout(TEST_TIMEOUT, lit)
+        .await
+        .expect("the emulated lights never turned on");
+
+    timeout(TEST_TIMEOUT, afv.stop())
+        .await
+        .expect("the AFV did not stop");
+}
@@ -0,0 +1,136 @@
+use std::io;
+
+use gcs_afv::network::socket::{Backoff, Socket, SocketState};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    time::{sleep, timeout, Duration},
+};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn fast_backoff() -> Backoff {
+    Backoff {
+        min: Duration::from_millis(10),
+        max: Duration::from_millis(50),
+        jitter: 0.2,
+        attempt_timeout: Duration::from_millis(200),
+    }
+}
+
+/// A client socket connected to a loopback peer, along with the peer's listener
+async fn loopback(backoff: Backoff) -> (TcpListener, Socket, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (peer, _) = listener.accept().await.unwrap();
+    (listener, Socket::new(client, false).backoff(backoff), peer)
+}
+
+async fn wait_for(state: &mut watch::Receiver<SocketState>, done: fn(SocketState) -> bool) {
+    let wait = async {
+        while !done(*state.borrow_and_update()) {
+            state.changed().await.unwrap();
+        }
+    };
+    timeout(TEST_TIMEOUT, wait)
+        .await
+        .expect("socket never reached the expected state");
+}
+
+#[tokio::test]
+async fn reconnects_after_peer_restarts() {
+    let (listener, socket, mut peer) = loopback(fast_backoff()).await;
+    let addr = listener.local_addr().unwrap();
+    let mut state = socket.state();
+
+    peer.write_all(&[1]).await.unwrap();
+    assert_eq!(timeout(TEST_TIMEOUT, socket.read_byte()).await, Ok(1));
+
+    // Reads drive the reconnect, so one is left waiting while the peer is gone
+    let reader = tokio::spawn({
+        let socket = socket.clone();
+        async move { socket.read_byte().await }
+    });
+    drop(peer);
+    drop(listener);
+    wait_for(&mut state, |s| matches!(s, SocketState::Reconnecting(_))).await;
+    assert!(!socket.is_connected());
+
+    let err = socket.write_data(&[0]).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let (mut peer, _) = timeout(TEST_TIMEOUT, listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    peer.write_all(&[2]).await.unwrap();
+    assert_eq!(timeout(TEST_TIMEOUT, reader).await.unwrap().unwrap(), 2);
+    wait_for(&mut state, |s| s == SocketState::Connected).await;
+
+    socket.write_data(&[3]).await.unwrap();
+    assert_eq!(peer.read_u8().await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn reconnect_attempts_back_off() {
+    let backoff = Backoff {
+        min: Duration::from_millis(100),
+        max: Duration::from_millis(100),
+        jitter: 0.0,
+        attempt_timeout: Duration::from_millis(100),
+    };
+    let (listener, socket, peer) = loopback(backoff).await;
+    let state = socket.state();
+
+    tokio::spawn({
+        let socket = socket.clone();
+        async move { socket.read_byte().await }
+    });
+    drop(peer);
+    drop(listener);
+
+    sleep(Duration::from_millis(450)).await;
+    match *state.borrow() {
+        SocketState::Reconnecting(attempt) => assert!(
+            (1..=4).contains(&attempt),
+            "made {} attempts in 450 ms",
+            attempt
+        ),
+        SocketState::Connected => panic!("socket reconnected to a closed peer"),
+    }
+}
+
+#[test]
+fn backoff_doubles_up_to_its_limit() {
+    let backoff = Backoff {
+        min: Duration::from_millis(100),
+        max: Duration::from_secs(1),
+        jitter: 0.0,
+        attempt_timeout: Duration::from_secs(1),
+    };
+    for (attempt, ms) in [(0, 100), (1, 200), (3, 800), (4, 1000), (u32::MAX, 1000)] {
+        let delay = backoff.delay(attempt).as_secs_f64() * 1000.0;
+        assert!(
+            (delay - ms as f64).abs() < 1.0,
+            "attempt {} waited {} ms",
+            attempt,
+            delay
+        );
+    }
+}
+
+#[test]
+fn backoff_jitter_stays_within_its_fraction() {
+    let backoff = Backoff {
+        jitter: 0.25,
+        ..fast_backoff()
+    };
+    for _ in 0..100 {
+        let delay = backoff.delay(10);
+        assert!(delay >= backoff.max.mul_f64(0.749) && delay <= backoff.max.mul_f64(1.251));
+    }
+}